@@ -1,10 +1,17 @@
 use std::env;
 
 fn main(){
+    println!("cargo:rustc-check-cfg=cfg(libusb, ohos, mock)");
     let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap();
+    let mock = env::var("CARGO_FEATURE_MOCK").is_ok();
+    if mock {
+        println!("cargo:rustc-cfg=mock");
+    }
     if target_env.as_str() == "ohos" {
         println!("cargo:rustc-cfg=ohos");
-    }else {
+    }else if env::var("CARGO_FEATURE_LIBUSB").is_ok() {
         println!("cargo:rustc-cfg=libusb");
+    }else if !mock {
+        panic!("eusb needs a backend, enable the `libusb` or the `mock` feature");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,254 @@
+use std::fmt::{Debug, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Reusable transfer buffers of one size.
+///
+/// Cloning shares the pool. A buffer handed out by [`get`](Self::get) goes
+/// back to the pool when dropped, unless `capacity` buffers are already idle.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    buffer_size: usize,
+    capacity: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                buffer_size,
+                capacity,
+                idle: Mutex::new(Vec::with_capacity(capacity)),
+            }),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.inner.buffer_size
+    }
+
+    /// Buffers waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    /// A buffer of `buffer_size` bytes, holding whatever its last user left in it.
+    pub fn get(&self) -> PooledBuffer {
+        PooledBuffer::new(self.take(), self.inner.buffer_size, Some(self.clone()))
+    }
+
+    /// A buffer whose length equals its capacity, so a backend can hand its
+    /// pointer to the OS and rebuild it with `Vec::from_raw_parts`.
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.inner.idle.lock().unwrap().pop()
+            .unwrap_or_else(|| vec![0; self.inner.buffer_size].into_boxed_slice().into_vec())
+    }
+
+    pub(crate) fn put(&self, data: Vec<u8>) {
+        if data.len() != self.inner.buffer_size || data.capacity() != data.len() {
+            return;
+        }
+        let mut idle = self.inner.idle.lock().unwrap();
+        if idle.len() < self.inner.capacity {
+            idle.push(data);
+        }
+    }
+}
+
+/// Transfer buffer which returns to its [`BufferPool`] when dropped.
+///
+/// Derefs to the received or to-be-sent bytes. A buffer made from a `Vec`
+/// has no pool and is simply freed.
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    len: usize,
+    pool: Option<BufferPool>,
+}
+
+impl PooledBuffer {
+    pub(crate) fn new(data: Vec<u8>, len: usize, pool: Option<BufferPool>) -> Self {
+        Self { len: len.min(data.len()), data, pool }
+    }
+
+    /// Bytes the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Limit the valid bytes, e.g. to send only part of the buffer.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Make the whole capacity valid again, e.g. before reading into the buffer.
+    pub fn reset(&mut self) {
+        self.len = self.data.len();
+    }
+
+    /// Take the valid bytes out of the pool.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        let mut data = std::mem::take(&mut self.data);
+        data.truncate(self.len);
+        data
+    }
+
+    /// The whole buffer and its pool, for a transfer to fill or send.
+    pub(crate) fn into_parts(mut self) -> (Vec<u8>, Option<BufferPool>) {
+        (std::mem::take(&mut self.data), self.pool.take())
+    }
+}
+
+impl From<Vec<u8>> for PooledBuffer {
+    fn from(value: Vec<u8>) -> Self {
+        Self::new(value, usize::MAX, None)
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data[..self.len]
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data[..self.len]
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Debug for PooledBuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+impl PartialEq for PooledBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<[u8]> for PooledBuffer {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl PartialEq<Vec<u8>> for PooledBuffer {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == **other
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_reuse() {
+        let pool = BufferPool::new(4, 1);
+        let mut a = pool.get();
+        a.copy_from_slice(&[1, 2, 3, 4]);
+        a.truncate(2);
+        assert_eq!(a, vec![1, 2]);
+        let b = pool.get();
+        drop(a);
+        drop(b);
+        assert_eq!(pool.idle(), 1);
+
+        let mut a = pool.get();
+        assert_eq!(pool.idle(), 0);
+        assert_eq!(a.capacity(), 4);
+        assert_eq!(a[..2], [1, 2]);
+        a.truncate(1);
+        assert_eq!(a.into_vec(), vec![1]);
+        assert_eq!(pool.idle(), 0);
+
+        assert_eq!(PooledBuffer::from(vec![5; 3]).capacity(), 3);
+    }
+
+    #[cfg(mock)]
+    #[tokio::test]
+    async fn test_buffer_pool() {
+        use std::time::Duration;
+        use crate::error::Error;
+        use crate::mock::MockResponse;
+        use crate::mock::test::*;
+        use crate::prelude::*;
+
+        let (mock, device) = open(mock_device());
+        let timeout = Duration::from_secs(1);
+        let pool = BufferPool::new(8, 4);
+
+        assert!(matches!(device.bulk_transfer_pip_in(1, PipConfig {
+            package_size: 16,
+            buffer_pool: Some(pool.clone()),
+            ..Default::default()
+        }), Err(Error::InvalidParam)));
+
+        let mut pip = device.bulk_transfer_pip_in(1, PipConfig {
+            package_size: 8,
+            request_num: 1,
+            buffer_pool: Some(pool.clone()),
+            ..Default::default()
+        }).unwrap();
+        mock.push(0x81, MockResponse::Data(vec![1; 4]));
+        let data = pip.next().await.unwrap().unwrap();
+        assert_eq!(data, vec![1; 4]);
+        assert_eq!(data.capacity(), 8);
+        assert_eq!(pool.idle(), 0);
+        drop(data);
+        assert_eq!(pool.idle(), 1);
+        drop(pip);
+
+        mock.push(0x81, MockResponse::Data(vec![7; 3]));
+        let mut buf = device.bulk_transfer_in_buf(1, pool.get(), timeout).await.unwrap();
+        assert_eq!(buf, vec![7; 3]);
+        assert_eq!(buf.capacity(), 8);
+
+        buf.truncate(2);
+        mock.push(0x02, MockResponse::Ack);
+        let (n, buf) = device.bulk_transfer_out_buf(2, buf, timeout).await.unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(buf, vec![7, 7]);
+        assert_eq!(buf.capacity(), 8);
+        assert_eq!(mock.take_written(0x02), vec![vec![7, 7]]);
+        drop(buf);
+        assert_eq!(pool.idle(), 1);
+
+        mock.push(0x81, MockResponse::Timeout);
+        assert!(device.bulk_transfer_in_buf(1, pool.get(), timeout).await.is_err());
+        assert_eq!(pool.idle(), 1);
+        mock.push(0x02, MockResponse::Stall);
+        assert!(device.bulk_transfer_out_buf(2, pool.get(), timeout).await.is_err());
+        assert_eq!(pool.idle(), 1);
+        device.clear_halt(0x02).unwrap();
+
+        device.set_stall_policy(StallPolicy::ClearHaltAndRetry(1));
+        mock.push(0x81, MockResponse::Stall);
+        mock.push(0x81, MockResponse::Data(vec![5]));
+        assert_eq!(device.bulk_transfer_in_buf(1, pool.get(), timeout).await.unwrap(), vec![5]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::device::UsbDevice;
+use crate::filter::DeviceFilter;
+use crate::hotplug::{HotplugFilter, HotplugStream};
+pub(crate) use crate::platform::*;
+use crate::error::*;
+
+
+static GLOBAL: Mutex<Option<UsbContext>> = Mutex::new(None);
+
+/// A libusb context along with the thread handling its events, or a context
+/// of virtual devices built [`with_mock`](UsbContextBuilder::with_mock).
+///
+/// Contexts are isolated from each other, each one enumerates and opens
+/// devices on its own. Cloning shares the context. It is shut down once
+/// dropped along with every device, pipe and hotplug stream obtained
+/// through it.
+///
+/// The associated functions of [`UsbDevice`] use [`UsbContext::global`].
+#[derive(Clone)]
+pub struct UsbContext {
+    pub(crate) platform: Arc<dyn ManagerCtx>,
+}
+
+/// Scheduling priority of the event thread, see
+/// [`UsbContextBuilder::with_priority`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventThreadPriority {
+    Min,
+    /// From 0, the lowest, to 99, the highest. Larger values count as 99.
+    Value(u8),
+    Max,
+}
+
+impl EventThreadPriority {
+    #[allow(unused)]
+    pub(crate) fn to_os(self) -> thread_priority::ThreadPriority {
+        use thread_priority::{ThreadPriority, ThreadPriorityValue};
+
+        match self {
+            EventThreadPriority::Min => ThreadPriority::Min,
+            EventThreadPriority::Value(value) => {
+                let value = value.min(ThreadPriorityValue::MAX);
+                ThreadPriority::Crossplatform(value.try_into().unwrap())
+            }
+            EventThreadPriority::Max => ThreadPriority::Max,
+        }
+    }
+}
+
+/// How a [`UsbContext`] handles events, see [`UsbContext::builder`].
+#[derive(Debug, Clone)]
+pub struct UsbContextBuilder {
+    pub(crate) event_thread: bool,
+    pub(crate) thread_name: String,
+    pub(crate) priority: Option<EventThreadPriority>,
+    pub(crate) affinity: Vec<usize>,
+    pub(crate) event_timeout: Duration,
+    pub(crate) backend: Backend,
+}
+
+impl Default for UsbContextBuilder {
+    fn default() -> Self {
+        Self {
+            event_thread: true,
+            thread_name: "USB main event".into(),
+            priority: Some(EventThreadPriority::Max),
+            affinity: vec![],
+            event_timeout: Duration::from_secs(60),
+            backend: Backend::default(),
+        }
+    }
+}
+
+impl UsbContextBuilder {
+    pub fn with_thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Priority of the event thread, `Max` by default. `None` keeps the
+    /// priority it is spawned with. Failing to set it is only logged.
+    pub fn with_priority(mut self, priority: Option<EventThreadPriority>) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Pin the event thread to these CPUs. Only Linux and Android support it,
+    /// failing to set it is only logged.
+    pub fn with_affinity(mut self, cpus: &[usize]) -> Self {
+        self.affinity = cpus.to_vec();
+        self
+    }
+
+    /// Longest time the event thread waits in libusb for an event at once, 60
+    /// seconds by default.
+    pub fn with_event_timeout(mut self, timeout: Duration) -> Self {
+        self.event_timeout = timeout;
+        self
+    }
+
+    /// Spawn no event thread. Transfers only complete while the caller runs
+    /// [`UsbContext::handle_events`].
+    pub fn without_event_thread(mut self) -> Self {
+        self.event_thread = false;
+        self
+    }
+
+    /// Serve the virtual devices of [`crate::mock`] instead of real ones. The
+    /// default when eusb is built without the `libusb` feature.
+    #[cfg(mock)]
+    pub fn with_mock(mut self) -> Self {
+        self.backend = Backend::Mock;
+        self
+    }
+
+    pub fn build(self) -> Result<UsbContext> {
+        Ok(UsbContext { platform: new_manager(&self)? })
+    }
+}
+
+impl UsbContext {
+    /// A context with the default [`UsbContextBuilder`].
+    pub fn new() -> Result<Self> {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> UsbContextBuilder {
+        UsbContextBuilder::default()
+    }
+
+    /// The default context, created on first use and never shut down.
+    pub fn global() -> Result<Self> {
+        let mut g = GLOBAL.lock().unwrap();
+        if let Some(ctx) = g.as_ref() {
+            return Ok(ctx.clone());
+        }
+        let ctx = Self::new()?;
+        *g = Some(ctx.clone());
+        Ok(ctx)
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub fn list(&self) -> Result<Vec<UsbDevice>> {
+        self.platform.clone().device_list()
+    }
+
+    #[cfg(not(target_os = "android"))]
+    pub fn open_with_vid_pid(&self, vid: u16, pid: u16) -> Result<UsbDevice> {
+        self.platform.clone().open_device_with_vid_pid(vid, pid)
+    }
+
+    /// All devices matching `filter`.
+    #[cfg(not(target_os = "android"))]
+    pub fn find(&self, filter: &DeviceFilter) -> Result<Vec<UsbDevice>> {
+        let devices = self.list()?;
+        Ok(devices.into_iter().filter(|o| filter.matches(o)).collect())
+    }
+
+    /// The first device matching `filter`.
+    #[cfg(not(target_os = "android"))]
+    pub fn find_one(&self, filter: &DeviceFilter) -> Result<UsbDevice> {
+        let devices = self.list()?;
+        devices.into_iter().find(|o| filter.matches(o)).ok_or(Error::NotFound)
+    }
+
+    #[cfg(unix)]
+    pub fn open_with_fd(&self, fd: RawFd) -> Result<UsbDevice> {
+        self.platform.clone().open_device_with_fd(fd)
+    }
+
+    /// Watch devices being attached and detached.
+    pub fn hotplug(&self, filter: HotplugFilter) -> Result<HotplugStream> {
+        self.platform.clone().hotplug(filter)
+    }
+
+    /// Handle pending events, waiting up to `timeout` for one. For contexts
+    /// built [`without_event_thread`](UsbContextBuilder::without_event_thread),
+    /// which must call it in a loop.
+    pub fn handle_events(&self, timeout: Duration) -> Result {
+        self.platform.handle_events(timeout)
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::mock::MockDevice;
+    use crate::mock::test::context;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_isolated_context() {
+        let ctx = context();
+        let other = context();
+        let mock = MockDevice::new(0xA00A, 1).plug_into(&ctx);
+        assert_eq!(ctx.list().unwrap().len(), 1);
+        ctx.open_with_vid_pid(0xA00A, 1).unwrap();
+        assert!(matches!(other.open_with_vid_pid(0xA00A, 1), Err(Error::NotFound)));
+
+        mock.unplug();
+        assert!(ctx.list().unwrap().is_empty());
+    }
+
+    #[cfg(not(libusb))]
+    #[tokio::test]
+    async fn test_global_mock() {
+        let mock = MockDevice::new(0xA00A, 2).plug();
+        assert!(UsbDevice::open_with_vid_pid(0xA00A, 2).is_ok());
+        assert!(matches!(context().open_with_vid_pid(0xA00A, 2), Err(Error::NotFound)));
+        mock.unplug();
+    }
+
+    #[tokio::test]
+    async fn test_without_event_thread() {
+        let ctx = UsbContext::builder()
+            .with_mock()
+            .with_thread_name("test event")
+            .with_priority(None)
+            .without_event_thread()
+            .build().unwrap();
+        MockDevice::new(0xA00A, 3).plug_into(&ctx);
+        ctx.open_with_vid_pid(0xA00A, 3).unwrap();
+        ctx.handle_events(Duration::from_millis(1)).unwrap();
+    }
+
+    #[test]
+    fn test_event_thread_priority() {
+        use thread_priority::{ThreadPriority, ThreadPriorityValue};
+
+        assert_eq!(EventThreadPriority::Max.to_os(), ThreadPriority::Max);
+        assert_eq!(EventThreadPriority::Value(200).to_os(),
+                   ThreadPriority::Crossplatform(ThreadPriorityValue::MAX.try_into().unwrap()));
+    }
+}
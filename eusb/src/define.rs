@@ -1,15 +1,27 @@
 #![allow(unused)]
 
 use std::time::Duration;
+use crate::buffer::BufferPool;
 
+/// Recipient of a control transfer. The interface number or endpoint address
+/// goes to the low byte of wIndex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum UsbControlRecipient {
     Device,
-    SpecifiedInterface,
-    Endpoint,
+    /// Interface with this number. It is claimed before a class or vendor request.
+    Interface(u8),
+    /// Endpoint with this address, including the direction bit.
+    Endpoint(u8),
     Other,
-    DefaultInterface
+    /// The interface in the low byte of `index`, sent as is and not claimed.
+    #[deprecated(since = "2.0.0", note = "use `Interface` with the interface number")]
+    SpecifiedInterface,
+    /// Same as `SpecifiedInterface`, `index` defaults to interface 0.
+    #[deprecated(since = "2.0.0", note = "use `Interface(0)`")]
+    DefaultInterface,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum UsbControlTransferType {
     Standard,
     Class,
@@ -22,6 +34,7 @@ pub struct ControlTransferRequest{
     pub transfer_type: UsbControlTransferType,
     pub request: u8,
     pub value: u16,
+    /// wIndex, except that an interface or endpoint recipient replaces its low byte.
     pub index: u16,
     pub timeout: Duration,
 }
@@ -39,30 +52,91 @@ impl Default for ControlTransferRequest {
     }
 }
 
+impl ControlTransferRequest {
+    #[allow(deprecated)]
+    pub(crate) fn setup(&self, direction: Direction, length: u16) -> ControlSetup {
+        let direction: u8 = match direction {
+            Direction::In => 0x80,
+            Direction::Out => 0x00,
+        };
+        let transfer_type: u8 = match self.transfer_type {
+            UsbControlTransferType::Standard => 0x00,
+            UsbControlTransferType::Class => 0x20,
+            UsbControlTransferType::Vendor => 0x40,
+            UsbControlTransferType::Reserved => 0x60,
+        };
+        let (recipient, index): (u8, u16) = match self.recipient {
+            UsbControlRecipient::Device => (0x00, self.index),
+            UsbControlRecipient::Interface(num) => (0x01, self.index & 0xFF00 | num as u16),
+            UsbControlRecipient::Endpoint(address) => (0x02, self.index & 0xFF00 | address as u16),
+            UsbControlRecipient::Other => (0x03, self.index),
+            UsbControlRecipient::SpecifiedInterface
+            | UsbControlRecipient::DefaultInterface => (0x01, self.index),
+        };
+        ControlSetup {
+            request_type: direction | transfer_type | recipient,
+            request: self.request,
+            value: self.value,
+            index,
+            length,
+        }
+    }
+
+    /// Interface the host must claim before sending the request, as class and
+    /// vendor requests to an interface belong to its driver.
+    pub(crate) fn interface_to_claim(&self) -> Option<u8> {
+        match (self.recipient, self.transfer_type) {
+            (UsbControlRecipient::Interface(num), UsbControlTransferType::Class | UsbControlTransferType::Vendor) => Some(num),
+            _ => None,
+        }
+    }
+}
 
-#[derive(Debug)]
+/// Setup packet of a control transfer as it is sent on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub struct ControlSetup {
+    /// bmRequestType
+    pub request_type: u8,
+    /// bRequest
+    pub request: u8,
+    /// wValue
+    pub value: u16,
+    /// wIndex
+    pub index: u16,
+    /// wLength
+    pub length: u16,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum Direction{
     In, Out
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum EndpointTransferType{
     Control, Isochronous, Bulk, Interrupt
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum IsoSyncType{
     None, Async, Adaptive, Sync
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum IsoUsageType{
     Data, Feedback, Implicit, Unknown(u8)
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Speed{
     Unknown, Low, Full, High, Super, SuperPlus
 }
 
 #[allow(non_snake_case)]
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceDescriptor{
     pub bLength: u8,
     pub bDescriptorType: u8,
@@ -79,6 +153,8 @@ pub struct DeviceDescriptor{
     pub iSerialNumber: u8,
     pub bNumConfigurations: u8,
 }
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub struct EndpointDescriptor {
     pub num: u8,
     pub direction: Direction,
@@ -110,7 +186,8 @@ impl EndpointDescriptor {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum  DeviceClass{
     PerInterface,
     Audio,
@@ -134,6 +211,8 @@ pub enum  DeviceClass{
 }
 
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub struct InterfaceDescriptor {
     pub num: u8,
     pub alt_setting: u8,
@@ -144,10 +223,14 @@ pub struct InterfaceDescriptor {
     pub interface: String,
     pub extra: Vec<u8>
 }
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub struct InterfaceAltSettingDescriptor {
     pub alt_settings: Vec<InterfaceDescriptor>
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConfigDescriptor {
     pub value: u8,
     pub interfaces: Vec<InterfaceAltSettingDescriptor>,
@@ -157,12 +240,89 @@ pub struct ConfigDescriptor {
     pub configuration: String,
 }
 
+/// Location of a device on the bus, stable while it stays connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceId {
+    pub bus_number: u8,
+    pub device_address: u8,
+}
+
+/// What an IN pipe does with a received buffer while `cache_size` buffers are waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discard the received buffer.
+    #[default]
+    DropNewest,
+    /// Discard the oldest waiting buffer.
+    DropOldest,
+    /// Keep the buffer and stop resubmitting transfers until the consumer catches up.
+    Block,
+    /// End the pipe with `Error::Overflow`.
+    Error,
+}
+
+/// One packet of an isochronous transfer received by an
+/// [`EndpointIsoPipIn`](crate::endpoint::EndpointIsoPipIn).
+#[derive(Debug, Clone)]
+pub struct IsoPacket {
+    /// Received bytes, `actual_length` of them.
+    pub data: Vec<u8>,
+    /// Status of this packet alone, a failed packet does not end the pipe.
+    pub status: crate::error::Result,
+    /// Index of the packet in the stream since the pipe was opened. Packets
+    /// of buffers discarded by the [`OverflowPolicy`] leave a gap.
+    pub position: u64,
+}
+
+impl IsoPacket {
+    pub fn actual_length(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Outcome of one packet of an isochronous OUT transfer.
+#[derive(Debug, Clone)]
+pub struct IsoPacketResult {
+    /// Bytes the device accepted.
+    pub actual_length: usize,
+    pub status: crate::error::Result,
+}
+
+/// What a bulk or interrupt transfer or an IN pipe does when its endpoint
+/// answers with a STALL. An OUT transfer which got part of its payload
+/// through fails instead of sending it twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StallPolicy {
+    /// Fail with `Error::Stall`. The endpoint stays halted until
+    /// [`UsbDevice::clear_halt`](crate::device::UsbDevice::clear_halt).
+    #[default]
+    Fail,
+    /// Clear the halt, then submit again unless the endpoint stalled this
+    /// many times in a row, in which case fail with `Error::Stall`.
+    ClearHaltAndRetry(u32),
+}
+
 #[derive(Clone)]
 pub struct PipConfig{
     pub cache_size: usize,
     pub package_size: usize,
     pub request_num: usize,
     pub timeout: Duration,
+    pub overflow: OverflowPolicy,
+    /// Where the buffers of a bulk or interrupt IN pipe come from, its
+    /// `buffer_size` must be `package_size`. By default each pipe has its own.
+    pub buffer_pool: Option<BufferPool>,
+    /// Let a bulk or interrupt IN pipe receive into memory mapped by the
+    /// kernel driver, so the kernel does not copy the data to user space. The
+    /// pipe still copies each received buffer out of the mapping into a
+    /// pooled buffer, the data is copied once either way. Only Linux provides
+    /// it, the pipe silently uses ordinary buffers elsewhere or when the
+    /// mapping fails.
+    pub device_memory: bool,
+    /// Applies to IN pipes, which by default clear the halt and go on, failing
+    /// after [`PipConfig::STALL_RETRIES`] stalls in a row.
+    pub stall: StallPolicy,
 }
 
 impl Default for PipConfig {
@@ -172,7 +332,16 @@ impl Default for PipConfig {
             request_num: 4,
             package_size:0,
             timeout: Default::default(),
+            overflow: Default::default(),
+            buffer_pool: None,
+            device_memory: false,
+            stall: StallPolicy::ClearHaltAndRetry(Self::STALL_RETRIES),
         }
     }
 }
 
+impl PipConfig {
+    /// Stalls in a row an IN pipe clears by default before it fails.
+    pub const STALL_RETRIES: u32 = 3;
+}
+
@@ -1,15 +1,33 @@
+use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
 use std::time::Duration;
+use crate::buffer::PooledBuffer;
 use crate::define::*;
-use crate::endpoint::{EndpointPipIn};
+use crate::endpoint::{EndpointIsoPipIn, EndpointIsoPipOut, EndpointPipIn, EndpointPipOut};
 use crate::error::*;
-use crate::manager::Manager;
+use crate::filter::DeviceFilter;
+use crate::hotplug::{HotplugFilter, HotplugStream};
+use crate::interface::ClaimedInterface;
+use crate::streams::BulkStreams;
+use crate::context::UsbContext;
 use crate::platform::*;
 use crate::utils::bcd_to_version;
+use log::warn;
 
 
+/// An open USB device.
+///
+/// Bulk, interrupt and isochronous transfers and pipes use the interface of
+/// the active configuration owning their endpoint, and fail with `NotFound`
+/// if there is none. The interface is claimed if needed, and released again
+/// once the last transfer or pipe using it is done unless a
+/// [`ClaimedInterface`] holds it.
 pub struct UsbDevice {
-    ctx: DeviceCtxImpl,
+    ctx: Box<dyn DeviceCtx>,
+    /// Interfaces held by a [`ClaimedInterface`].
+    claimed: Mutex<HashSet<u8>>,
+    stall_policy: Mutex<StallPolicy>,
 }
 
 
@@ -22,34 +40,95 @@ impl Display for UsbDevice {
     }
 }
 
+#[cfg(libusb)]
 impl From<DeviceCtxImpl> for UsbDevice {
     fn from(value: DeviceCtxImpl) -> Self {
-        Self {
-            ctx: value
-        }
+        Self::new(Box::new(value))
+    }
+}
+
+#[cfg(mock)]
+impl From<crate::platform::mock::device::DeviceCtxImpl> for UsbDevice {
+    fn from(value: crate::platform::mock::device::DeviceCtxImpl) -> Self {
+        Self::new(Box::new(value))
     }
 }
 
 #[allow(unused)]
 impl UsbDevice {
+    fn new(ctx: Box<dyn DeviceCtx>) -> Self {
+        Self {
+            ctx,
+            claimed: Mutex::new(HashSet::new()),
+            stall_policy: Mutex::new(StallPolicy::default()),
+        }
+    }
 
+    pub(crate) fn ctx(&self) -> &dyn DeviceCtx {
+        self.ctx.as_ref()
+    }
 
+    /// Devices of the [global](UsbContext::global) context.
     #[cfg(not(target_os = "android"))]
     pub fn list() -> Result<Vec<UsbDevice>> {
-        let manager = Manager::get();
-        manager.device_list()
+        UsbContext::global()?.list()
     }
 
     #[cfg(not(target_os = "android"))]
     pub fn open_with_vid_pid(vid: u16, pid: u16) -> Result<UsbDevice> {
-        let manager = Manager::get();
-        manager.open_device_with_vid_pid(vid, pid)
+        UsbContext::global()?.open_with_vid_pid(vid, pid)
+    }
+
+    /// All devices matching `filter`.
+    #[cfg(not(target_os = "android"))]
+    pub fn find(filter: &DeviceFilter) -> Result<Vec<UsbDevice>> {
+        UsbContext::global()?.find(filter)
+    }
+
+    /// The first device matching `filter`.
+    #[cfg(not(target_os = "android"))]
+    pub fn find_one(filter: &DeviceFilter) -> Result<UsbDevice> {
+        UsbContext::global()?.find_one(filter)
     }
 
     #[cfg(unix)]
     pub fn open_with_fd(fd: RawFd)->Result<UsbDevice>{
-        let manager = Manager::get();
-        manager.open_device_with_fd(fd)
+        UsbContext::global()?.open_with_fd(fd)
+    }
+
+    /// Watch devices being attached and detached.
+    pub fn hotplug(filter: HotplugFilter) -> Result<HotplugStream> {
+        UsbContext::global()?.hotplug(filter)
+    }
+
+    /// Ports from the root hub to the device.
+    pub fn port_numbers(&self) -> Result<Vec<u8>> {
+        self.ctx.port_numbers()
+    }
+
+    pub fn id(&self) -> DeviceId {
+        DeviceId {
+            bus_number: self.ctx.bus_number(),
+            device_address: self.ctx.device_address(),
+        }
+    }
+
+    /// Record every transfer issued through the returned device into `path`.
+    #[cfg(feature = "record")]
+    pub fn record(self, path: impl AsRef<std::path::Path>) -> Result<UsbDevice> {
+        let claimed = self.claimed.into_inner().unwrap();
+        let ctx = crate::record::Recorder::new(self.ctx, path.as_ref())?;
+        let device = UsbDevice::new(Box::new(ctx));
+        *device.claimed.lock().unwrap() = claimed;
+        Ok(device)
+    }
+
+    /// Serve a session captured by [`UsbDevice::record`] without hardware.
+    #[cfg(feature = "record")]
+    pub fn replay(path: impl AsRef<std::path::Path>) -> Result<UsbDevice> {
+        let session = crate::record::Session::load(path)?;
+        let ctx = crate::record::Replay::from(session);
+        Ok(UsbDevice::new(Box::new(ctx)))
     }
 
     pub fn serial_number(&self) -> Result<String> {
@@ -95,11 +174,164 @@ impl UsbDevice {
         self.ctx.get_active_configuration()
     }
 
+    /// Claim an interface until the returned guard is dropped.
+    ///
+    /// Fails with `Busy` if the interface is already held by another guard.
+    pub fn claim_interface(&self, interface_number: u8) -> Result<ClaimedInterface<'_>> {
+        self.claim(interface_number, false)
+    }
+
+    /// Detach the kernel driver bound to the interface, if any, and claim it.
+    /// The driver is attached again when the guard is dropped.
+    pub fn detach_and_claim_interface(&self, interface_number: u8) -> Result<ClaimedInterface<'_>> {
+        let reattach = match self.ctx.kernel_driver_active(interface_number) {
+            Ok(true) => {
+                self.ctx.detach_kernel_driver(interface_number)?;
+                true
+            }
+            Ok(false) | Err(Error::NotSupported) => false,
+            Err(e) => return Err(e),
+        };
+        self.claim(interface_number, reattach).inspect_err(|_| {
+            if reattach {
+                let _ = self.ctx.attach_kernel_driver(interface_number);
+            }
+        })
+    }
+
+    fn claim(&self, interface_number: u8, reattach: bool) -> Result<ClaimedInterface<'_>> {
+        let mut g = self.claimed.lock().unwrap();
+        if g.contains(&interface_number) {
+            return Err(Error::Busy);
+        }
+        self.ctx.claim_interface(interface_number)?;
+        g.insert(interface_number);
+        Ok(ClaimedInterface::new(self, interface_number, reattach))
+    }
+
+    pub(crate) fn release_claimed(&self, interface_number: u8) -> Result {
+        let mut g = self.claimed.lock().unwrap();
+        g.remove(&interface_number);
+        self.ctx.release_interface(interface_number)
+    }
+
+    /// Allocate `num_streams` USB 3 bulk streams on each of `endpoints`, given
+    /// as addresses including the direction bit. Their interfaces are claimed
+    /// if needed and stay claimed while the device is open, as releasing them
+    /// would free the streams. The streams are freed when the returned
+    /// [`BulkStreams`] drops.
+    pub fn alloc_streams(&self, num_streams: u32, endpoints: &[u8]) -> Result<BulkStreams<'_>> {
+        let num_streams = self.ctx.alloc_streams(num_streams, endpoints)?;
+        Ok(BulkStreams::new(self, endpoints.to_vec(), num_streams))
+    }
+
+    pub fn kernel_driver_active(&self, interface_number: u8) -> Result<bool> {
+        self.ctx.kernel_driver_active(interface_number)
+    }
+
+    pub fn detach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.ctx.detach_kernel_driver(interface_number)
+    }
+
+    pub fn attach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.ctx.attach_kernel_driver(interface_number)
+    }
+
+    /// Clear the halt of an endpoint, given by address including the direction
+    /// bit, and reset its data toggle.
+    pub fn clear_halt(&self, endpoint: u8) -> Result {
+        self.ctx.clear_halt(endpoint)
+    }
+
+    /// What bulk and interrupt transfers, like [`bulk_transfer_in`](Self::bulk_transfer_in)
+    /// or [`interrupt_transfer_out`](Self::interrupt_transfer_out), do when the
+    /// endpoint stalls. Pipes take theirs from [`PipConfig::stall`].
+    pub fn set_stall_policy(&self, policy: StallPolicy) {
+        *self.stall_policy.lock().unwrap() = policy;
+    }
+
+    /// Run `transfer` again as the stall policy says while `endpoint` stalls.
+    async fn retry_stall<T>(&self, endpoint: u8, transfer: impl Fn() -> AsyncResult<T>) -> Result<T> {
+        let mut stalls = 0;
+        loop {
+            match transfer().await {
+                Err(e) if self.retry_after(endpoint, &e, &mut stalls) => {}
+                r => return r,
+            }
+        }
+    }
+
+    /// [`retry_stall`](Self::retry_stall) for transfers on a buffer, which is
+    /// handed back whatever the result.
+    async fn retry_stall_buf(
+        &self, endpoint: u8, mut buf: Vec<u8>, transfer: impl Fn(Vec<u8>) -> AsyncBufResult,
+    ) -> (Vec<u8>, Result<usize>) {
+        let mut stalls = 0;
+        loop {
+            let (back, r) = transfer(buf).await;
+            match r {
+                Err(e) if self.retry_after(endpoint, &e, &mut stalls) => buf = back,
+                r => return (back, r),
+            }
+        }
+    }
+
+    /// Whether to submit again after a transfer on `endpoint` failed with `e`,
+    /// clearing the halt if it stalled under [`StallPolicy::ClearHaltAndRetry`].
+    /// An OUT transfer which got part of its payload through is not repeated,
+    /// nor is any transfer if the halt could not be cleared.
+    fn retry_after(&self, endpoint: u8, e: &Error, stalls: &mut u32) -> bool {
+        let policy = *self.stall_policy.lock().unwrap();
+        match policy {
+            StallPolicy::ClearHaltAndRetry(retries) if matches!(e.kind(), Error::Stall) => {
+                warn!("{}", e);
+                if let Err(e) = self.ctx.clear_halt(endpoint) {
+                    warn!("ep[{}] clear halt: {}", endpoint, e);
+                    return false;
+                }
+                *stalls += 1;
+                let sent = endpoint & 0x80 == 0 && e.actual_length() > 0;
+                !sent && *stalls <= retries
+            }
+            _ => false,
+        }
+    }
+
     pub fn bulk_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<EndpointPipIn> {
+        check_pool(&pip_config)?;
         let inner = self.ctx.bulk_transfer_pip_in(endpoint, pip_config)?;
         Ok(inner.into())
     }
 
+    /// Keep `request_num` interrupt IN transfers armed, see [`EndpointPipIn`].
+    pub fn interrupt_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<EndpointPipIn> {
+        check_pool(&pip_config)?;
+        let inner = self.ctx.interrupt_transfer_pip_in(endpoint, pip_config)?;
+        Ok(inner.into())
+    }
+
+    /// Keep `request_num` isochronous IN transfers of `num_iso_packages` packets
+    /// in flight, each packet holding up to `package_size` bytes.
+    pub fn iso_transfer_pip_in(&self, endpoint: u8, num_iso_packages: usize, pip_config: PipConfig) -> Result<EndpointIsoPipIn> {
+        let inner = self.ctx.iso_transfer_pip_in(endpoint, num_iso_packages, pip_config)?;
+        Ok(inner.into())
+    }
+
+    /// Stream buffers to a bulk OUT endpoint with `request_num` transfers in flight.
+    pub fn bulk_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig) -> Result<EndpointPipOut> {
+        let request_num = pip_config.request_num;
+        let inner = self.ctx.bulk_transfer_pip_out(endpoint, pip_config)?;
+        Ok(EndpointPipOut::new(inner, request_num))
+    }
+
+    /// Stream isochronous transfers to an OUT endpoint with `request_num`
+    /// transfers in flight, each item holding the packets of one transfer.
+    pub fn iso_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig) -> Result<EndpointIsoPipOut> {
+        let request_num = pip_config.request_num;
+        let inner = self.ctx.iso_transfer_pip_out(endpoint, pip_config)?;
+        Ok(EndpointPipOut::new(inner, request_num))
+    }
+
     pub async fn control_transfer_in(
         &self,
         control_transfer_request: ControlTransferRequest,
@@ -120,27 +352,173 @@ impl UsbDevice {
     pub async fn bulk_transfer_in(
         &self, endpoint: u8, capacity: usize, timeout: Duration,
     ) -> Result<Vec<u8>> {
-        self.ctx.bulk_transfer_in(endpoint, capacity, timeout).await
+        self.retry_stall(endpoint | 0x80, || self.ctx.bulk_transfer_in(endpoint, capacity, timeout)).await
     }
 
     pub async fn bulk_transfer_out(
         &self, endpoint: u8, data: &[u8], timeout: Duration,
     ) -> Result<usize> {
-        self.ctx.bulk_transfer_out(endpoint, data, timeout).await
+        self.retry_stall(endpoint & 0x7F, || self.ctx.bulk_transfer_out(endpoint, data, timeout)).await
+    }
+    /// Read into `buf` without copying, up to its [`capacity`](PooledBuffer::capacity).
+    /// The buffer comes back holding the received bytes, or returns to its
+    /// pool on error.
+    pub async fn bulk_transfer_in_buf(&self, endpoint: u8, buf: PooledBuffer, timeout: Duration) -> Result<PooledBuffer> {
+        let (data, pool) = buf.into_parts();
+        let (data, r) = self.retry_stall_buf(endpoint | 0x80, data, |data| {
+            self.ctx.bulk_transfer_in_buf(endpoint, data, timeout)
+        }).await;
+        let len = r.as_ref().copied().unwrap_or_default();
+        let buf = PooledBuffer::new(data, len, pool);
+        r.map(|_| buf)
     }
+
+    /// Send the bytes of `buf` without copying, returns the sent length and the
+    /// buffer. It returns to its pool on error.
+    pub async fn bulk_transfer_out_buf(&self, endpoint: u8, buf: PooledBuffer, timeout: Duration) -> Result<(usize, PooledBuffer)> {
+        let len = buf.len();
+        let (data, pool) = buf.into_parts();
+        let (data, r) = self.retry_stall_buf(endpoint & 0x7F, data, |data| {
+            self.ctx.bulk_transfer_out_buf(endpoint, data, len, timeout)
+        }).await;
+        let buf = PooledBuffer::new(data, len, pool);
+        r.map(|sent| (sent, buf))
+    }
+
     pub async fn interrupt_transfer_in(
         &self, endpoint: u8, capacity: usize, timeout: Duration,
     ) -> Result<Vec<u8>> {
-        self.ctx.interrupt_transfer_in(endpoint, capacity, timeout).await
+        self.retry_stall(endpoint | 0x80, || self.ctx.interrupt_transfer_in(endpoint, capacity, timeout)).await
     }
 
     pub async fn interrupt_transfer_out(
         &self, endpoint: u8, data: &[u8], timeout: Duration,
     ) -> Result<usize> {
-        self.ctx.interrupt_transfer_out(endpoint, data, timeout).await
+        self.retry_stall(endpoint & 0x7F, || self.ctx.interrupt_transfer_out(endpoint, data, timeout)).await
     }
 
     pub async fn iso_transfer_in(&self, endpoint: u8, num_iso_packages: usize, package_capacity: usize, timeout: Duration) -> Result<Vec<Vec<u8>>>{
         self.ctx.iso_transfer_in(endpoint, num_iso_packages, package_capacity, timeout).await
     }
-}
\ No newline at end of file
+
+    /// Send one packet per entry of `packs`, in order. A packet the device did
+    /// not accept is reported in its [`IsoPacketResult`], not as an error.
+    pub async fn iso_transfer_out(&self, endpoint: u8, packs: Vec<Vec<u8>>, timeout: Duration) -> Result<Vec<IsoPacketResult>> {
+        self.ctx.iso_transfer_out(endpoint, packs, timeout).await
+    }
+}
+fn check_pool(pip_config: &PipConfig) -> Result {
+    match &pip_config.buffer_pool {
+        Some(pool) if pool.buffer_size() != pip_config.package_size => Err(Error::InvalidParam),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::mock::MockResponse;
+    use crate::mock::test::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_iso_transfer_out() {
+        use futures::SinkExt;
+
+        let (mock, device) = open(mock_device());
+        let timeout = Duration::from_secs(1);
+
+        mock.push(0x02, MockResponse::Iso(vec![
+            MockResponse::Ack,
+            MockResponse::Stall,
+            MockResponse::Ack,
+        ]));
+        let packets = device.iso_transfer_out(2, vec![vec![1; 2], vec![2; 3], vec![3]], timeout).await.unwrap();
+        assert_eq!(packets.iter().map(|o| o.actual_length).collect::<Vec<_>>(), vec![2, 0, 1]);
+        assert!(matches!(packets[1].status, Err(Error::Stall)));
+        assert_eq!(mock.take_written(0x02), vec![vec![1, 1, 2, 2, 2, 3]]);
+        assert!(matches!(device.iso_transfer_out(2, vec![], timeout).await, Err(Error::InvalidParam)));
+
+        let mut pip = device.iso_transfer_pip_out(2, PipConfig {
+            request_num: 2,
+            ..Default::default()
+        }).unwrap();
+        mock.push(0x02, MockResponse::Ack);
+        mock.push(0x02, MockResponse::Iso(vec![MockResponse::Stall]));
+        pip.feed(vec![vec![4; 2], vec![5; 2]]).await.unwrap();
+        pip.feed(vec![vec![6]]).await.unwrap();
+        pip.flush().await.unwrap();
+        assert_eq!(mock.take_written(0x02), vec![vec![4, 4, 5, 5], vec![6]]);
+    }
+
+    #[tokio::test]
+    async fn test_stall_policy() {
+        let (mock, device) = open(mock_device());
+        let timeout = Duration::from_secs(1);
+
+        mock.push(0x81, MockResponse::Stall);
+        assert!(matches!(device.bulk_transfer_in(1, 8, timeout).await.unwrap_err().kind(), Error::Stall));
+        assert!(mock.is_halted(0x81));
+        device.clear_halt(0x81).unwrap();
+        assert!(!mock.is_halted(0x81));
+
+        device.set_stall_policy(StallPolicy::ClearHaltAndRetry(1));
+        mock.push(0x81, MockResponse::Stall);
+        mock.push(0x81, MockResponse::Data(vec![1]));
+        assert_eq!(device.bulk_transfer_in(1, 8, timeout).await.unwrap(), vec![1]);
+        assert!(!mock.is_halted(0x81));
+
+        mock.push(0x02, MockResponse::Stall);
+        mock.push(0x02, MockResponse::Stall);
+        assert!(matches!(device.bulk_transfer_out(2, &[1], timeout).await.unwrap_err().kind(), Error::Stall));
+        assert!(!mock.is_halted(0x02));
+
+        mock.push(0x02, MockResponse::PartialStall(1));
+        let e = device.bulk_transfer_out(2, &[1, 2], timeout).await.unwrap_err();
+        assert!(matches!(e.kind(), Error::Stall));
+        assert_eq!(e.actual_length(), 1);
+        assert_eq!(mock.take_written(0x02), vec![vec![1]]);
+        assert!(!mock.is_halted(0x02));
+
+        mock.push(0x83, MockResponse::Stall);
+        mock.push(0x83, MockResponse::Data(vec![3]));
+        assert_eq!(device.interrupt_transfer_in(3, 8, timeout).await.unwrap(), vec![3]);
+        assert!(!mock.is_halted(0x83));
+
+        let mut pip = device.bulk_transfer_pip_in(1, PipConfig {
+            package_size: 8,
+            request_num: 1,
+            stall: StallPolicy::ClearHaltAndRetry(1),
+            ..Default::default()
+        }).unwrap();
+        mock.push(0x81, MockResponse::Stall);
+        mock.push(0x81, MockResponse::Data(vec![2]));
+        assert_eq!(pip.next().await.unwrap().unwrap(), vec![2]);
+        mock.push(0x81, MockResponse::Stall);
+        mock.push(0x81, MockResponse::Stall);
+        assert!(matches!(pip.next().await.unwrap().unwrap_err().kind(), Error::Stall));
+        assert!(pip.next().await.is_none());
+        assert!(!mock.is_halted(0x81));
+        drop(pip);
+
+        let mut pip = device.bulk_transfer_pip_in(1, PipConfig {
+            package_size: 8,
+            request_num: 1,
+            ..Default::default()
+        }).unwrap();
+        for _ in 0..=PipConfig::STALL_RETRIES {
+            mock.push(0x81, MockResponse::Stall);
+        }
+        assert!(matches!(pip.next().await.unwrap().unwrap_err().kind(), Error::Stall));
+        drop(pip);
+
+        let mut pip = device.bulk_transfer_pip_in(1, PipConfig {
+            package_size: 8,
+            request_num: 1,
+            stall: StallPolicy::Fail,
+            ..Default::default()
+        }).unwrap();
+        mock.push(0x81, MockResponse::Stall);
+        assert!(matches!(pip.next().await.unwrap().unwrap_err().kind(), Error::Stall));
+        assert!(mock.is_halted(0x81));
+    }
+}
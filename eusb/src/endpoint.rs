@@ -1,11 +1,26 @@
-use crate::platform::{EndpointPipInInner, EndpointPipInImpl};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use futures::{Sink, Stream};
+use futures::future::poll_fn;
+use crate::buffer::PooledBuffer;
+use crate::define::IsoPacket;
+use crate::error::*;
+use crate::platform::{EndpointPipInInner, EndpointPipOutInner};
 
+/// Bulk or interrupt IN pipe keeping `request_num` transfers in flight.
+///
+/// Received buffers come from the pipe's [`BufferPool`](crate::buffer::BufferPool)
+/// and go back to it when dropped. A transfer error ends the pipe: the buffers
+/// received before it are yielded first, then the error once, then `None`.
+/// Dropping the pipe cancels its transfers without waiting for them.
 pub struct EndpointPipIn {
-    inner: EndpointPipInImpl
+    inner: Box<dyn EndpointPipInInner>
 }
 
-impl From<EndpointPipInImpl> for EndpointPipIn {
-    fn from(value: EndpointPipInImpl) -> Self {
+impl From<Box<dyn EndpointPipInInner>> for EndpointPipIn {
+    fn from(value: Box<dyn EndpointPipInInner>) -> Self {
         Self{
             inner: value
         }
@@ -13,8 +28,396 @@ impl From<EndpointPipInImpl> for EndpointPipIn {
 }
 
 impl EndpointPipIn {
-    pub async fn next(&mut self) ->Option<Vec<u8>>{
-       self.inner.next().await
+    pub async fn next(&mut self) -> Option<Result<PooledBuffer>> {
+        poll_fn(|cx| self.inner.poll_next(cx)).await
+    }
+
+    /// Buffers discarded because the consumer fell behind, see [`OverflowPolicy`](crate::prelude::OverflowPolicy).
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped()
+    }
+
+    /// Error which ended the pipe, e.g. `NoDevice` after a disconnect.
+    pub fn error(&self) -> Option<Error> {
+        self.inner.error()
+    }
+}
+
+impl Stream for EndpointPipIn {
+    type Item = Result<PooledBuffer>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next(cx)
+    }
+}
+
+/// Isochronous IN pipe keeping `request_num` transfers in flight.
+///
+/// Each item holds the packets of one transfer. A packet error is reported in
+/// [`IsoPacket::status`] and the stream goes on, only a transfer error such
+/// as `NoDevice` ends the pipe like it does an [`EndpointPipIn`].
+pub struct EndpointIsoPipIn {
+    inner: Box<dyn EndpointPipInInner<Vec<IsoPacket>>>
+}
+
+impl From<Box<dyn EndpointPipInInner<Vec<IsoPacket>>>> for EndpointIsoPipIn {
+    fn from(value: Box<dyn EndpointPipInInner<Vec<IsoPacket>>>) -> Self {
+        Self{
+            inner: value
+        }
+    }
+}
+
+impl EndpointIsoPipIn {
+    pub async fn next(&mut self) -> Option<Result<Vec<IsoPacket>>> {
+        poll_fn(|cx| self.inner.poll_next(cx)).await
+    }
+
+    /// Transfers discarded because the consumer fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped()
+    }
+
+    /// Error which ended the pipe.
+    pub fn error(&self) -> Option<Error> {
+        self.inner.error()
     }
 }
 
+impl Stream for EndpointIsoPipIn {
+    type Item = Result<Vec<IsoPacket>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next(cx)
+    }
+}
+
+/// Bulk or isochronous OUT pipe keeping up to `request_num` buffers in flight.
+///
+/// Sending waits only for a free slot, flushing waits for every queued
+/// buffer. Each failed buffer is reported by one of the next `poll_ready` or
+/// `poll_flush` calls, in order, or by [`take_errors`](Self::take_errors)
+/// along with its position. Buffers still queued when the pipe is dropped are
+/// sent in the background.
+pub struct EndpointPipOut<I = Vec<u8>> {
+    inner: Box<dyn EndpointPipOutInner<I>>,
+    request_num: usize,
+    state: Arc<Mutex<PipOutState>>,
+}
+
+#[derive(Default)]
+struct PipOutState {
+    in_flight: usize,
+    /// Position of the next buffer sent.
+    next_index: u64,
+    /// Failed buffers not reported yet.
+    errors: VecDeque<(u64, Error)>,
+    waker: Option<Waker>,
+}
+
+/// Isochronous OUT pipe, each item holds the packets of one transfer.
+///
+/// A packet the device did not accept is only logged, so a glitch does not
+/// interrupt playback. Transfer errors are reported like for bulk.
+pub type EndpointIsoPipOut = EndpointPipOut<Vec<Vec<u8>>>;
+
+impl<I> EndpointPipOut<I> {
+    pub(crate) fn new(inner: Box<dyn EndpointPipOutInner<I>>, request_num: usize) -> Self {
+        Self {
+            inner,
+            request_num: request_num.max(1),
+            state: Default::default(),
+        }
+    }
+
+    /// Buffers submitted and not completed yet.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// Errors of the failed buffers not reported yet, each along with the
+    /// position of its buffer, counting the buffers sent through the pipe
+    /// from 0.
+    pub fn take_errors(&self) -> Vec<(u64, Error)> {
+        self.state.lock().unwrap().errors.drain(..).collect()
+    }
+
+    fn poll_until(&self, cx: &mut Context<'_>, max_in_flight: usize) -> Poll<Result> {
+        let mut g = self.state.lock().unwrap();
+        if let Some((_, e)) = g.errors.pop_front() {
+            return Poll::Ready(Err(e));
+        }
+        if g.in_flight <= max_in_flight {
+            return Poll::Ready(Ok(()));
+        }
+        g.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<I> Sink<I> for EndpointPipOut<I> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        self.poll_until(cx, self.request_num - 1)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: I) -> Result {
+        let index = {
+            let mut g = self.state.lock().unwrap();
+            g.in_flight += 1;
+            g.next_index += 1;
+            g.next_index - 1
+        };
+        let state = self.state.clone();
+        let r = self.inner.submit(item, Box::new(move |r| {
+            let waker = {
+                let mut g = state.lock().unwrap();
+                g.in_flight -= 1;
+                if let Err(e) = r {
+                    g.errors.push_back((index, e));
+                }
+                g.waker.take()
+            };
+            if let Some(w) = waker {
+                w.wake();
+            }
+        }));
+        if r.is_err() {
+            self.state.lock().unwrap().in_flight -= 1;
+        }
+        r
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        self.poll_until(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use std::time::Duration;
+    use crate::mock::MockResponse;
+    use crate::mock::test::*;
+    use crate::prelude::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pip_in() {
+        let (mock, device) = open(mock_device());
+
+        for i in 0..10u8 {
+            mock.push(0x81, MockResponse::Data(vec![i; 16]));
+        }
+        {
+            let mut pip = device.bulk_transfer_pip_in(1, PipConfig {
+                package_size: 16,
+                request_num: 2,
+                ..Default::default()
+            }).unwrap();
+            for i in 0..10u8 {
+                assert_eq!(pip.next().await.unwrap().unwrap(), vec![i; 16]);
+            }
+            assert_eq!(mock.pending(0x81), 2);
+        }
+        assert_eq!(mock.pending(0x81), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pip_out() {
+        use futures::SinkExt;
+
+        let (mock, device) = open(mock_device());
+        let mut pip = device.bulk_transfer_pip_out(1, PipConfig {
+            request_num: 2,
+            ..Default::default()
+        }).unwrap();
+
+        pip.feed(vec![1]).await.unwrap();
+        pip.feed(vec![2]).await.unwrap();
+        assert_eq!(mock.pending(0x01), 2);
+        {
+            let mut feed = pip.feed(vec![3]);
+            assert!(futures::poll!(&mut feed).is_pending());
+            mock.push(0x01, MockResponse::Ack);
+            feed.await.unwrap();
+        }
+        assert_eq!(pip.in_flight(), 2);
+
+        let pusher = mock.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            pusher.push(0x01, MockResponse::Ack);
+            pusher.push(0x01, MockResponse::Ack);
+        });
+        pip.flush().await.unwrap();
+        assert_eq!(pip.in_flight(), 0);
+        assert_eq!(mock.take_written(0x01), vec![vec![1], vec![2], vec![3]]);
+
+        pip.feed(vec![4]).await.unwrap();
+        mock.push(0x01, MockResponse::Stall);
+        assert!(matches!(pip.flush().await.unwrap_err().kind(), Error::Stall));
+        pip.flush().await.unwrap();
+        device.clear_halt(0x01).unwrap();
+
+        pip.feed(vec![5]).await.unwrap();
+        pip.feed(vec![6]).await.unwrap();
+        mock.push(0x01, MockResponse::Timeout);
+        mock.push(0x01, MockResponse::PartialAck(1));
+        assert!(matches!(pip.flush().await.unwrap_err().kind(), Error::Timeout));
+        assert_eq!(pip.flush().await.unwrap_err().actual_length(), 1);
+        pip.flush().await.unwrap();
+
+        pip.feed(vec![7]).await.unwrap();
+        pip.feed(vec![8]).await.unwrap();
+        mock.push(0x01, MockResponse::Ack);
+        mock.push(0x01, MockResponse::Timeout);
+        let errors = pip.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 7);
+        assert!(matches!(errors[0].1.kind(), Error::Timeout));
+        pip.flush().await.unwrap();
+        assert_eq!(mock.take_written(0x01), vec![vec![6], vec![7]]);
+
+        mock.push(0x01, MockResponse::Ack);
+        pip.send(vec![10]).await.unwrap();
+        assert_eq!(mock.take_written(0x01), vec![vec![10]]);
+    }
+
+    #[tokio::test]
+    async fn test_pip_overflow() {
+        let (mock, device) = open(mock_device());
+        let pip = |overflow| {
+            for i in 0..4u8 {
+                mock.push(0x81, MockResponse::Data(vec![i]));
+            }
+            device.bulk_transfer_pip_in(1, PipConfig {
+                cache_size: 2,
+                request_num: 1,
+                package_size: 8,
+                overflow,
+                ..Default::default()
+            }).unwrap()
+        };
+
+        let mut p = pip(OverflowPolicy::DropNewest);
+        assert_eq!(p.next().await.unwrap().unwrap(), vec![0]);
+        assert_eq!(p.next().await.unwrap().unwrap(), vec![1]);
+        assert_eq!(p.dropped(), 2);
+        drop(p);
+
+        let mut p = pip(OverflowPolicy::DropOldest);
+        assert_eq!(p.next().await.unwrap().unwrap(), vec![2]);
+        assert_eq!(p.next().await.unwrap().unwrap(), vec![3]);
+        assert_eq!(p.dropped(), 2);
+        drop(p);
+
+        let mut p = pip(OverflowPolicy::Block);
+        assert_eq!(mock.pending(0x81), 0);
+        for i in 0..4u8 {
+            assert_eq!(p.next().await.unwrap().unwrap(), vec![i]);
+        }
+        assert_eq!(p.dropped(), 0);
+        assert_eq!(mock.pending(0x81), 1);
+        drop(p);
+
+        let mut p = pip(OverflowPolicy::Error);
+        assert_eq!(p.next().await.unwrap().unwrap(), vec![0]);
+        assert_eq!(p.next().await.unwrap().unwrap(), vec![1]);
+        assert!(matches!(p.next().await.unwrap().unwrap_err().kind(), Error::Overflow));
+        assert!(p.next().await.is_none());
+        assert!(matches!(p.error(), Some(Error::Overflow)));
+        assert_eq!(p.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pip_in_errors() {
+        use futures::StreamExt;
+
+        let (mock, device) = open(mock_device());
+        let config = PipConfig {
+            package_size: 8,
+            request_num: 1,
+            ..Default::default()
+        };
+
+        mock.push(0x81, MockResponse::Data(vec![1]));
+        mock.push(0x81, MockResponse::Timeout);
+        mock.push(0x81, MockResponse::Data(vec![2]));
+        let pip = device.bulk_transfer_pip_in(1, config.clone()).unwrap();
+        let items: Vec<_> = pip.collect().await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), &vec![1]);
+        assert!(matches!(items[1].as_ref().unwrap_err().kind(), Error::Timeout));
+
+        let mut pip = device.bulk_transfer_pip_in(1, config).unwrap();
+        assert_eq!(pip.next().await.unwrap().unwrap(), vec![2]);
+        mock.push(0x81, MockResponse::Data(vec![3]));
+        mock.unplug();
+        assert_eq!(pip.next().await.unwrap().unwrap(), vec![3]);
+        assert!(matches!(pip.next().await.unwrap().unwrap_err().kind(), Error::NoDevice));
+        assert!(pip.next().await.is_none());
+        assert!(matches!(pip.error().unwrap().kind(), Error::NoDevice));
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_pip_in() {
+        let (mock, device) = open(mock_device());
+
+        let mut pip = device.interrupt_transfer_pip_in(2, PipConfig {
+            package_size: 8,
+            request_num: 1,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(mock.pending(0x82), 1);
+        for i in 0..3u8 {
+            mock.push(0x82, MockResponse::Data(vec![i; 4]));
+            assert_eq!(pip.next().await.unwrap().unwrap(), vec![i; 4]);
+        }
+        mock.push(0x82, MockResponse::Stall);
+        assert_eq!(mock.pending(0x82), 1);
+        mock.push(0x82, MockResponse::Data(vec![9; 16]));
+        assert!(matches!(pip.next().await.unwrap().unwrap_err().kind(), Error::Overflow));
+        assert!(pip.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_iso_pip_in() {
+        let (mock, device) = open(mock_device());
+
+        let mut pip = device.iso_transfer_pip_in(1, 3, PipConfig {
+            package_size: 4,
+            request_num: 2,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(mock.pending(0x81), 2);
+
+        mock.push(0x81, MockResponse::Iso(vec![
+            MockResponse::Data(vec![1; 4]),
+            MockResponse::Stall,
+            MockResponse::Data(vec![2; 2]),
+        ]));
+        let packets = pip.next().await.unwrap().unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].data, vec![1; 4]);
+        assert!(matches!(packets[1].status, Err(Error::Stall)));
+        assert_eq!(packets[1].actual_length(), 0);
+        assert_eq!(packets[2].data, vec![2; 2]);
+        assert_eq!(packets.iter().map(|o| o.position).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        mock.push(0x81, MockResponse::Iso(vec![MockResponse::Data(vec![3; 8])]));
+        let packets = pip.next().await.unwrap().unwrap();
+        assert!(matches!(packets[0].status, Err(Error::Overflow)));
+        assert!(packets[1].status.is_ok());
+        assert_eq!(packets[2].position, 5);
+        assert_eq!(mock.pending(0x81), 2);
+
+        mock.push(0x81, MockResponse::Disconnect);
+        assert!(matches!(pip.next().await.unwrap().unwrap_err().kind(), Error::NoDevice));
+        assert!(pip.next().await.is_none());
+    }
+}
@@ -1,8 +1,11 @@
 use std::{result};
+use std::fmt::{Display, Formatter};
+use crate::define::{DeviceId, EndpointTransferType};
 
 pub type Result<T=()> = result::Result<T, Error>;
 
-#[derive(thiserror::Error, Debug)]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub enum Error {
     #[error("Input/output error: {0}")]
     Io(String),
@@ -28,8 +31,11 @@ pub enum Error {
     #[error("Overflow")]
     Overflow,
 
-    #[error("Pipe error")]
-    Pipe,
+    /// The endpoint answered with a STALL handshake, or the device
+    /// rejected a control request.
+    #[error("Endpoint stalled")]
+    #[cfg_attr(feature = "record", serde(alias = "Pipe"))]
+    Stall,
 
     #[error("System call interrupted (perhaps due to signal)")]
     Interrupted,
@@ -45,4 +51,191 @@ pub enum Error {
 
     #[error("[USB] Something wrong: {0}")]
     Other(String),
+
+    /// A transfer failed with `error`, see [`Error::kind`].
+    #[error("{context}: {error}")]
+    Transfer {
+        error: Box<Error>,
+        context: Box<TransferContext>,
+    },
+}
+
+impl Error {
+    /// Former name of [`Error::Stall`], matches it in patterns too.
+    #[deprecated(note = "renamed to `Error::Stall`")]
+    #[allow(non_upper_case_globals)]
+    pub const Pipe: Error = Error::Stall;
+
+    /// The error without the context of the transfer it ended, to match on.
+    pub fn kind(&self) -> &Error {
+        match self {
+            Error::Transfer { error, .. } => error.kind(),
+            e => e,
+        }
+    }
+
+    pub fn context(&self) -> Option<&TransferContext> {
+        match self {
+            Error::Transfer { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Bytes a failed transfer moved, e.g. sent before it timed out.
+    pub fn actual_length(&self) -> usize {
+        self.context().map_or(0, |o| o.actual_length)
+    }
+
+    /// Bytes a failed IN transfer received, e.g. before it timed out or overflowed.
+    pub fn partial_data(&self) -> &[u8] {
+        self.context().map_or(&[], |o| &o.data)
+    }
+
+    /// Attach the context of the failed transfer, unless there is one already.
+    pub(crate) fn in_transfer(self, context: TransferContext) -> Self {
+        match self {
+            Error::Transfer { .. } => self,
+            error => Error::Transfer {
+                error: Box::new(error),
+                context: Box::new(context),
+            },
+        }
+    }
+}
+
+/// Where a transfer failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransferContext {
+    pub device: DeviceId,
+    pub transfer_type: EndpointTransferType,
+    /// Endpoint address including the direction bit, `0x80` for a control
+    /// transfer reading from the device.
+    pub endpoint: u8,
+    /// Bytes moved before the transfer failed.
+    pub actual_length: usize,
+    /// Bytes an IN transfer received before it failed, empty for OUT and
+    /// isochronous transfers.
+    #[cfg_attr(feature = "record", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub data: Vec<u8>,
+}
+
+impl Display for TransferContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} transfer on ep[0x{:02X}] of device {:03}:{:03} failed after {} bytes",
+               self.transfer_type, self.endpoint,
+               self.device.bus_number, self.device.device_address, self.actual_length)
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        use std::io::ErrorKind;
+
+        let kind = match value.kind() {
+            Error::InvalidParam => ErrorKind::InvalidInput,
+            Error::Access => ErrorKind::PermissionDenied,
+            Error::NoDevice | Error::NotFound => ErrorKind::NotFound,
+            Error::Busy => ErrorKind::ResourceBusy,
+            Error::Timeout => ErrorKind::TimedOut,
+            Error::Overflow => ErrorKind::InvalidData,
+            // usbfs reports a stall as EPIPE.
+            Error::Stall => ErrorKind::BrokenPipe,
+            Error::Interrupted => ErrorKind::Interrupted,
+            Error::NoMem => ErrorKind::OutOfMemory,
+            Error::NotSupported => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, value)
+    }
+}
+
+/// Gives back the original error of an `io::Error` made from an [`Error`],
+/// otherwise maps the kind.
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        if let Some(e) = value.get_ref().and_then(|o| o.downcast_ref::<Error>()) {
+            return e.clone();
+        }
+        match value.kind() {
+            ErrorKind::InvalidInput => Error::InvalidParam,
+            ErrorKind::PermissionDenied => Error::Access,
+            ErrorKind::NotFound => Error::NotFound,
+            ErrorKind::ResourceBusy => Error::Busy,
+            ErrorKind::TimedOut => Error::Timeout,
+            ErrorKind::BrokenPipe => Error::Stall,
+            ErrorKind::Interrupted => Error::Interrupted,
+            ErrorKind::OutOfMemory => Error::NoMem,
+            ErrorKind::Unsupported => Error::NotSupported,
+            _ => Error::Io(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use super::*;
+
+    #[test]
+    fn test_io_error() {
+        let e = Error::Timeout.in_transfer(TransferContext {
+            device: DeviceId { bus_number: 1, device_address: 2 },
+            transfer_type: EndpointTransferType::Bulk,
+            endpoint: 0x81,
+            actual_length: 1,
+            data: vec![7],
+        });
+        let io = std::io::Error::from(e);
+        assert_eq!(io.kind(), ErrorKind::TimedOut);
+        let e = Error::from(io);
+        assert!(matches!(e.kind(), Error::Timeout));
+        assert_eq!(e.partial_data(), &[7]);
+
+        assert_eq!(std::io::Error::from(Error::Stall).kind(), ErrorKind::BrokenPipe);
+        assert_eq!(std::io::Error::from(Error::Other("x".to_string())).kind(), ErrorKind::Other);
+        assert!(matches!(Error::from(std::io::Error::from(ErrorKind::PermissionDenied)), Error::Access));
+        assert!(matches!(Error::from(std::io::Error::other("x")), Error::Io(_)));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_pipe_alias() {
+        assert!(matches!(Error::Stall, Error::Pipe));
+        assert_eq!(Error::Pipe, Error::Stall);
+    }
+
+    #[cfg(mock)]
+    #[tokio::test]
+    async fn test_partial_transfer() {
+        use std::time::Duration;
+        use crate::mock::MockResponse;
+        use crate::mock::test::*;
+        use crate::prelude::*;
+
+        let (mock, device) = open(mock_device());
+        let timeout = Duration::from_secs(1);
+
+        mock.push(0x81, MockResponse::PartialData(vec![1, 2, 3]));
+        let e = device.bulk_transfer_in(1, 8, timeout).await.unwrap_err();
+        assert!(matches!(e.kind(), Error::Timeout));
+        assert_eq!(e.actual_length(), 3);
+        assert_eq!(e.partial_data(), &[1, 2, 3]);
+
+        mock.push(0x02, MockResponse::PartialAck(2));
+        let e = device.bulk_transfer_out(2, &[4, 5, 6, 7], timeout).await.unwrap_err();
+        assert!(matches!(e.kind(), Error::Timeout));
+        assert_eq!(e.actual_length(), 2);
+        assert!(e.partial_data().is_empty());
+        assert_eq!(mock.take_written(0x02), vec![vec![4, 5]]);
+
+        mock.push(0x80, MockResponse::PartialData(vec![9]));
+        let e = device.get_descriptor(DescriptorType::Device, 0, 0, 18, timeout).await.unwrap_err();
+        assert_eq!(e.partial_data(), &[9]);
+        assert_eq!(e.context().unwrap().transfer_type, EndpointTransferType::Control);
+
+        assert_eq!(Error::Timeout.actual_length(), 0);
+    }
 }
@@ -0,0 +1,158 @@
+use crate::define::*;
+use crate::device::UsbDevice;
+use crate::utils::glob_match;
+
+/// Criteria to pick devices out of [`ctx.find`].
+///
+/// Unset fields match any device. String criteria need to read the string
+/// descriptors, devices which can not be opened do not match them.
+/// Manufacturer and product accept `*` and `?` wildcards.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial_number: Option<String>,
+    bus_number: Option<u8>,
+    device_address: Option<u8>,
+    port_numbers: Option<Vec<u8>>,
+    device_class: Option<DeviceClass>,
+    interface_class: Option<DeviceClass>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+}
+
+impl DeviceFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn vid(mut self, vid: u16) -> Self {
+        self.vid = Some(vid);
+        self
+    }
+
+    pub fn pid(mut self, pid: u16) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    pub fn serial_number(mut self, serial_number: &str) -> Self {
+        self.serial_number = Some(serial_number.to_string());
+        self
+    }
+
+    pub fn bus_number(mut self, bus_number: u8) -> Self {
+        self.bus_number = Some(bus_number);
+        self
+    }
+
+    pub fn device_address(mut self, device_address: u8) -> Self {
+        self.device_address = Some(device_address);
+        self
+    }
+
+    /// Ports from the root hub to the device, see [`UsbDevice::port_numbers`].
+    pub fn port_numbers(mut self, port_numbers: &[u8]) -> Self {
+        self.port_numbers = Some(port_numbers.to_vec());
+        self
+    }
+
+    pub fn device_class(mut self, class: DeviceClass) -> Self {
+        self.device_class = Some(class);
+        self
+    }
+
+    /// Any interface of the active configuration has this class.
+    pub fn interface_class(mut self, class: DeviceClass) -> Self {
+        self.interface_class = Some(class);
+        self
+    }
+
+    pub fn manufacturer(mut self, pattern: &str) -> Self {
+        self.manufacturer = Some(pattern.to_string());
+        self
+    }
+
+    pub fn product(mut self, pattern: &str) -> Self {
+        self.product = Some(pattern.to_string());
+        self
+    }
+
+    pub fn matches(&self, device: &UsbDevice) -> bool {
+        let des = match device.device_descriptor() {
+            Ok(des) => des,
+            Err(_) => return false,
+        };
+        let id = device.id();
+        let fits = self.vid.is_none_or(|o| o == des.idVendor)
+            && self.pid.is_none_or(|o| o == des.idProduct)
+            && self.bus_number.is_none_or(|o| o == id.bus_number)
+            && self.device_address.is_none_or(|o| o == id.device_address)
+            && self.device_class.is_none_or(|o| device.device_class().is_ok_and(|c| c == o));
+        if !fits {
+            return false;
+        }
+        if let Some(ports) = &self.port_numbers {
+            if !device.port_numbers().is_ok_and(|o| &o == ports) {
+                return false;
+            }
+        }
+        if let Some(class) = self.interface_class {
+            let found = device.get_active_configuration().is_ok_and(|cfg| {
+                cfg.interfaces.iter()
+                    .flat_map(|o| o.alt_settings.iter())
+                    .any(|o| o.device_class == class)
+            });
+            if !found {
+                return false;
+            }
+        }
+        if let Some(sn) = &self.serial_number {
+            if !device.serial_number().is_ok_and(|o| &o == sn) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.manufacturer {
+            if !device.manufacturer().is_ok_and(|o| glob_match(pattern, &o)) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.product {
+            if !device.product().is_ok_and(|o| glob_match(pattern, &o)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::error::Error;
+    use crate::mock::MockDevice;
+    use crate::mock::test::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_find() {
+        let ctx = context();
+        MockDevice::new(0xA005, 1).with_serial_number("AAA").with_product("HackRF One").with_port_numbers(&[1, 2]).plug_into(&ctx);
+        MockDevice::new(0xA005, 1).with_serial_number("BBB").with_product("HackRF One").with_port_numbers(&[1, 3]).plug_into(&ctx);
+        MockDevice::new(0xA005, 2).with_serial_number("CCC").with_product("Other").plug_into(&ctx);
+
+        let all = ctx.find(&DeviceFilter::new().vid(0xA005)).unwrap();
+        assert_eq!(all.len(), 3);
+
+        let hackrf = ctx.find(&DeviceFilter::new().vid(0xA005).product("HackRF*")).unwrap();
+        assert_eq!(hackrf.len(), 2);
+
+        let device = ctx.find_one(&DeviceFilter::new().vid(0xA005).serial_number("BBB")).unwrap();
+        assert_eq!(device.port_numbers().unwrap(), vec![1, 3]);
+
+        let device = ctx.find_one(&DeviceFilter::new().vid(0xA005).port_numbers(&[1, 2])).unwrap();
+        assert_eq!(device.serial_number().unwrap(), "AAA");
+
+        let r = ctx.find_one(&DeviceFilter::new().vid(0xA005).product("?ther").pid(1));
+        assert!(matches!(r, Err(Error::NotFound)));
+    }
+}
@@ -0,0 +1,91 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::{Stream, StreamExt};
+use crate::define::*;
+use crate::device::UsbDevice;
+
+pub enum HotplugEvent {
+    Arrived(UsbDevice),
+    Left(DeviceId),
+}
+
+/// Which devices a [`HotplugStream`] reports. `None` matches any value.
+#[derive(Debug, Clone, Default)]
+pub struct HotplugFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    /// bDeviceClass of the device descriptor.
+    pub class: Option<u8>,
+    /// Also report devices already connected when the stream is created.
+    pub enumerate: bool,
+}
+
+impl HotplugFilter {
+    #[allow(unused)]
+    pub(crate) fn matches(&self, descriptor: &DeviceDescriptor) -> bool {
+        self.vid.is_none_or(|o| o == descriptor.idVendor)
+            && self.pid.is_none_or(|o| o == descriptor.idProduct)
+            && self.class.is_none_or(|o| o == descriptor.bDeviceClass)
+    }
+}
+
+/// Device arrival and removal events, stops reporting when dropped.
+pub struct HotplugStream {
+    rx: UnboundedReceiver<HotplugEvent>,
+    _registration: Box<dyn Send>,
+}
+
+impl HotplugStream {
+    pub(crate) fn new(rx: UnboundedReceiver<HotplugEvent>, registration: impl Send + 'static) -> Self {
+        Self {
+            rx,
+            _registration: Box::new(registration),
+        }
+    }
+}
+
+impl Stream for HotplugStream {
+    type Item = HotplugEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use futures::StreamExt;
+    use crate::mock::MockDevice;
+    use crate::mock::test::*;
+    use super::*;
+
+    fn arrived_id(event: Option<HotplugEvent>) -> DeviceId {
+        match event {
+            Some(HotplugEvent::Arrived(device)) => device.id(),
+            _ => panic!("expect arrived"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hotplug() {
+        let ctx = context();
+        let existing = MockDevice::new(0xA003, 1).plug_into(&ctx);
+        let mut events = ctx.hotplug(HotplugFilter {
+            vid: Some(0xA003),
+            enumerate: true,
+            ..Default::default()
+        }).unwrap();
+        let existing_id = arrived_id(events.next().await);
+
+        MockDevice::new(0xA004, 1).plug_into(&ctx);
+        let mock = MockDevice::new(0xA003, 2).with_bus_address(3, 7).plug_into(&ctx);
+        let id = arrived_id(events.next().await);
+        assert_eq!(id, DeviceId { bus_number: 3, device_address: 7 });
+
+        mock.unplug();
+        existing.unplug();
+        assert!(matches!(events.next().await, Some(HotplugEvent::Left(o)) if o == id));
+        assert!(matches!(events.next().await, Some(HotplugEvent::Left(o)) if o == existing_id));
+    }
+}
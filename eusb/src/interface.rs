@@ -0,0 +1,348 @@
+use std::time::Duration;
+use log::warn;
+use crate::define::*;
+use crate::device::UsbDevice;
+use crate::endpoint::{EndpointIsoPipIn, EndpointIsoPipOut, EndpointPipIn, EndpointPipOut};
+use crate::error::*;
+
+/// An interface claimed through [`UsbDevice::claim_interface`], released when dropped.
+/// Pipes opened on its endpoints keep it claimed until they are dropped too.
+///
+/// A kernel driver detached by [`UsbDevice::detach_and_claim_interface`] is
+/// attached again on release, drop the pipes first for that.
+pub struct ClaimedInterface<'a> {
+    device: &'a UsbDevice,
+    number: u8,
+    alt_setting: u8,
+    reattach: bool,
+}
+
+impl<'a> ClaimedInterface<'a> {
+    pub(crate) fn new(device: &'a UsbDevice, number: u8, reattach: bool) -> Self {
+        Self { device, number, alt_setting: 0, reattach }
+    }
+
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    pub fn device(&self) -> &'a UsbDevice {
+        self.device
+    }
+
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    /// Switch the alternate setting. Endpoints obtained before borrow the
+    /// interface, so they can not outlive the setting they belong to.
+    pub fn set_alt_setting(&mut self, alt_setting: u8) -> Result {
+        let descriptor = self.descriptor()?;
+        if !descriptor.alt_settings.iter().any(|o| o.alt_setting == alt_setting) {
+            return Err(Error::NotFound);
+        }
+        self.device.ctx().set_interface_alt_setting(self.number, alt_setting)?;
+        self.alt_setting = alt_setting;
+        Ok(())
+    }
+
+    /// Descriptor of the current alternate setting.
+    pub fn alt_setting_descriptor(&self) -> Result<InterfaceDescriptor> {
+        self.descriptor()?.alt_settings.into_iter()
+            .find(|o| o.alt_setting == self.alt_setting)
+            .ok_or(Error::NotFound)
+    }
+
+    /// Descriptors of all alternate settings of the interface in the active configuration.
+    pub fn descriptor(&self) -> Result<InterfaceAltSettingDescriptor> {
+        let cfg = self.device.get_active_configuration()?;
+        cfg.interfaces.into_iter()
+            .find(|o| o.alt_settings.first().map(|a| a.num) == Some(self.number))
+            .ok_or(Error::NotFound)
+    }
+
+    /// Endpoints of the current alternate setting.
+    pub fn endpoints(&self) -> Result<Vec<Endpoint<'_>>> {
+        Ok(self.alt_setting_descriptor()?.endpoints.into_iter()
+            .map(|descriptor| Endpoint { interface: self, descriptor })
+            .collect())
+    }
+
+    /// Endpoint by address, including the direction bit, e.g. `0x81` for IN 1.
+    pub fn endpoint(&self, address: u8) -> Result<Endpoint<'_>> {
+        self.endpoints()?.into_iter()
+            .find(|o| o.address() == address)
+            .ok_or(Error::NotFound)
+    }
+}
+
+impl Drop for ClaimedInterface<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.device.release_claimed(self.number) {
+            warn!("release interface [{}] fail: {}", self.number, e);
+        }
+        if self.reattach {
+            if let Err(e) = self.device.attach_kernel_driver(self.number) {
+                warn!("attach kernel driver [{}] fail: {}", self.number, e);
+            }
+        }
+    }
+}
+
+fn endpoint_address(descriptor: &EndpointDescriptor) -> u8 {
+    match descriptor.direction {
+        Direction::In => 0x80 | descriptor.num,
+        Direction::Out => descriptor.num,
+    }
+}
+
+/// Endpoint of a claimed interface. Transfers use the endpoint type from its descriptor.
+pub struct Endpoint<'a> {
+    interface: &'a ClaimedInterface<'a>,
+    descriptor: EndpointDescriptor,
+}
+
+impl Endpoint<'_> {
+    pub fn descriptor(&self) -> &EndpointDescriptor {
+        &self.descriptor
+    }
+
+    pub fn address(&self) -> u8 {
+        endpoint_address(&self.descriptor)
+    }
+
+    fn check_direction(&self, direction: Direction) -> Result {
+        if self.descriptor.direction != direction {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    pub async fn transfer_in(&self, capacity: usize, timeout: Duration) -> Result<Vec<u8>> {
+        self.check_direction(Direction::In)?;
+        let device = self.interface.device;
+        match self.descriptor.transfer_type {
+            EndpointTransferType::Bulk => device.bulk_transfer_in(self.descriptor.num, capacity, timeout).await,
+            EndpointTransferType::Interrupt => device.interrupt_transfer_in(self.descriptor.num, capacity, timeout).await,
+            _ => Err(Error::NotSupported),
+        }
+    }
+
+    pub async fn transfer_out(&self, data: &[u8], timeout: Duration) -> Result<usize> {
+        self.check_direction(Direction::Out)?;
+        let device = self.interface.device;
+        match self.descriptor.transfer_type {
+            EndpointTransferType::Bulk => device.bulk_transfer_out(self.descriptor.num, data, timeout).await,
+            EndpointTransferType::Interrupt => device.interrupt_transfer_out(self.descriptor.num, data, timeout).await,
+            _ => Err(Error::NotSupported),
+        }
+    }
+
+    pub fn pip_in(&self, pip_config: PipConfig) -> Result<EndpointPipIn> {
+        self.check_direction(Direction::In)?;
+        let device = self.interface.device;
+        match self.descriptor.transfer_type {
+            EndpointTransferType::Bulk => device.bulk_transfer_pip_in(self.descriptor.num, pip_config),
+            EndpointTransferType::Interrupt => device.interrupt_transfer_pip_in(self.descriptor.num, pip_config),
+            _ => Err(Error::NotSupported),
+        }
+    }
+
+    pub fn iso_pip_in(&self, num_iso_packages: usize, pip_config: PipConfig) -> Result<EndpointIsoPipIn> {
+        self.check_direction(Direction::In)?;
+        if self.descriptor.transfer_type != EndpointTransferType::Isochronous {
+            return Err(Error::NotSupported);
+        }
+        self.interface.device.iso_transfer_pip_in(self.descriptor.num, num_iso_packages, pip_config)
+    }
+
+    pub fn pip_out(&self, pip_config: PipConfig) -> Result<EndpointPipOut> {
+        self.check_direction(Direction::Out)?;
+        if self.descriptor.transfer_type != EndpointTransferType::Bulk {
+            return Err(Error::NotSupported);
+        }
+        self.interface.device.bulk_transfer_pip_out(self.descriptor.num, pip_config)
+    }
+
+    pub fn iso_pip_out(&self, pip_config: PipConfig) -> Result<EndpointIsoPipOut> {
+        self.check_direction(Direction::Out)?;
+        if self.descriptor.transfer_type != EndpointTransferType::Isochronous {
+            return Err(Error::NotSupported);
+        }
+        self.interface.device.iso_transfer_pip_out(self.descriptor.num, pip_config)
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::mock::MockResponse;
+    use crate::mock::test::*;
+    use super::*;
+
+    fn endpoint(num: u8, direction: Direction, transfer_type: EndpointTransferType) -> EndpointDescriptor {
+        EndpointDescriptor {
+            transfer_type,
+            max_packet_size: 64,
+            ..EndpointDescriptor::new(num, direction)
+        }
+    }
+
+    fn alt_setting(num: u8, alt_setting: u8, endpoints: Vec<EndpointDescriptor>) -> InterfaceDescriptor {
+        InterfaceDescriptor {
+            num,
+            alt_setting,
+            device_class: DeviceClass::VendorSpec,
+            device_sub_class: DeviceClass::PerInterface,
+            protocol: DeviceClass::PerInterface,
+            endpoints,
+            interface: String::new(),
+            extra: vec![],
+        }
+    }
+
+    fn interface(num: u8, endpoints: Vec<EndpointDescriptor>) -> InterfaceAltSettingDescriptor {
+        InterfaceAltSettingDescriptor {
+            alt_settings: vec![alt_setting(num, 0, endpoints)],
+        }
+    }
+
+    fn config(interfaces: Vec<InterfaceAltSettingDescriptor>) -> ConfigDescriptor {
+        ConfigDescriptor {
+            value: 1,
+            interfaces,
+            extra: vec![],
+            max_power: 100,
+            configuration: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_claim_interface() {
+        let (mock, device) = open(mock_device()
+            .with_config(config(vec![
+                interface(0, vec![
+                    endpoint(1, Direction::In, EndpointTransferType::Bulk),
+                    endpoint(1, Direction::Out, EndpointTransferType::Bulk),
+                ]),
+                interface(1, vec![
+                    endpoint(2, Direction::In, EndpointTransferType::Interrupt),
+                ]),
+            ]))
+            .with_kernel_driver(1));
+
+        {
+            let data = device.claim_interface(0).unwrap();
+            assert!(mock.is_claimed(0));
+            assert!(matches!(device.claim_interface(0), Err(Error::Busy)));
+            assert_eq!(data.endpoints().unwrap().len(), 2);
+
+            mock.push(0x81, MockResponse::Data(vec![1, 2, 3]));
+            let ep = data.endpoint(0x81).unwrap();
+            assert_eq!(ep.transfer_in(64, Duration::ZERO).await.unwrap(), vec![1, 2, 3]);
+            assert!(matches!(ep.transfer_out(&[0], Duration::ZERO).await, Err(Error::InvalidParam)));
+
+            mock.push(0x01, MockResponse::Ack);
+            let ep = data.endpoint(0x01).unwrap();
+            assert_eq!(ep.transfer_out(&[4, 5], Duration::ZERO).await.unwrap(), 2);
+            assert_eq!(mock.take_written(0x01), vec![vec![4, 5]]);
+
+            assert!(matches!(data.endpoint(0x82), Err(Error::NotFound)));
+        }
+        assert!(!mock.is_claimed(0));
+
+        assert!(matches!(device.claim_interface(1), Err(Error::Busy)));
+        {
+            let _hid = device.detach_and_claim_interface(1).unwrap();
+            assert!(!mock.kernel_driver_active(1));
+            assert!(mock.is_claimed(1));
+        }
+        assert!(!mock.is_claimed(1));
+        assert!(mock.kernel_driver_active(1));
+
+        assert!(matches!(device.claim_interface(2), Err(Error::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_interface_control_request() {
+        let (mock, device) = open(mock_device()
+            .with_config(config(vec![interface(0, vec![]), interface(1, vec![])]))
+            .with_kernel_driver(1));
+        let request = |recipient, transfer_type| ControlTransferRequest {
+            recipient,
+            transfer_type,
+            request: 0x01,
+            value: 0x0100,
+            index: 0x02FF,
+            ..Default::default()
+        };
+
+        mock.push(0x00, MockResponse::Ack);
+        device.control_transfer_out(request(UsbControlRecipient::Interface(0), UsbControlTransferType::Class), &[1]).await.unwrap();
+        assert!(mock.is_claimed(0));
+
+        assert!(matches!(
+            device.control_transfer_in(request(UsbControlRecipient::Interface(1), UsbControlTransferType::Vendor), 1).await,
+            Err(Error::Busy)));
+
+        mock.push(0x80, MockResponse::Data(vec![0, 0]));
+        device.control_transfer_in(request(UsbControlRecipient::Interface(1), UsbControlTransferType::Standard), 2).await.unwrap();
+        assert!(!mock.is_claimed(1));
+
+        mock.push(0x80, MockResponse::Data(vec![0, 0]));
+        device.control_transfer_in(request(UsbControlRecipient::Endpoint(0x81), UsbControlTransferType::Standard), 2).await.unwrap();
+
+        mock.push(0x00, MockResponse::Ack);
+        #[allow(deprecated)]
+        let recipient = UsbControlRecipient::SpecifiedInterface;
+        device.control_transfer_out(request(recipient, UsbControlTransferType::Vendor), &[]).await.unwrap();
+        assert!(!mock.is_claimed(1));
+
+        assert_eq!(mock.take_control_setups(), vec![
+            ControlSetup { request_type: 0x21, request: 0x01, value: 0x0100, index: 0x0200, length: 1 },
+            ControlSetup { request_type: 0x81, request: 0x01, value: 0x0100, index: 0x0201, length: 2 },
+            ControlSetup { request_type: 0x82, request: 0x01, value: 0x0100, index: 0x0281, length: 2 },
+            ControlSetup { request_type: 0x41, request: 0x01, value: 0x0100, index: 0x02FF, length: 0 },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_alt_setting() {
+        let (mock, device) = open(mock_device()
+            .with_config(config(vec![
+                InterfaceAltSettingDescriptor {
+                    alt_settings: vec![
+                        alt_setting(0, 0, vec![]),
+                        alt_setting(0, 1, vec![endpoint(1, Direction::In, EndpointTransferType::Isochronous)]),
+                        alt_setting(0, 2, vec![endpoint(1, Direction::In, EndpointTransferType::Bulk)]),
+                    ],
+                },
+            ])));
+
+        {
+            let mut interface = device.claim_interface(0).unwrap();
+            assert_eq!(interface.alt_setting(), 0);
+            assert_eq!(mock.alt_setting(0), Some(0));
+            assert!(interface.endpoints().unwrap().is_empty());
+            assert!(matches!(interface.endpoint(0x81), Err(Error::NotFound)));
+
+            interface.set_alt_setting(1).unwrap();
+            assert_eq!(mock.alt_setting(0), Some(1));
+            let ep = interface.endpoint(0x81).unwrap();
+            assert_eq!(ep.descriptor().transfer_type, EndpointTransferType::Isochronous);
+            assert!(matches!(ep.transfer_in(64, Duration::ZERO).await, Err(Error::NotSupported)));
+
+            interface.set_alt_setting(2).unwrap();
+            mock.push(0x81, MockResponse::Data(vec![7]));
+            let ep = interface.endpoint(0x81).unwrap();
+            assert_eq!(ep.transfer_in(64, Duration::ZERO).await.unwrap(), vec![7]);
+
+            assert!(matches!(interface.set_alt_setting(3), Err(Error::NotFound)));
+            assert_eq!(interface.alt_setting(), 2);
+            assert_eq!(mock.alt_setting(0), Some(2));
+        }
+        assert_eq!(mock.alt_setting(0), None);
+
+        let interface = device.claim_interface(0).unwrap();
+        assert_eq!(interface.alt_setting(), 0);
+    }
+}
@@ -1,10 +1,22 @@
 pub(crate) mod define;
-pub(crate) mod manager;
+mod context;
 mod device;
 mod platform;
 pub mod error;
+pub mod buffer;
 pub mod prelude;
 mod utils;
 pub mod endpoint;
+pub mod hotplug;
+pub mod filter;
+pub mod interface;
+pub mod streams;
+pub mod standard;
+#[cfg(unix)]
+pub mod reactor;
+#[cfg(mock)]
+pub mod mock;
+#[cfg(feature = "record")]
+pub mod record;
 
 
@@ -0,0 +1,412 @@
+//! Virtual USB devices for testing without hardware.
+//!
+//! Enabled by the `mock` feature, which adds a backend next to libusb. A
+//! context built [`with_mock`](crate::context::UsbContextBuilder::with_mock)
+//! serves the devices plugged into it with [`MockDevice::plug_into`], driven
+//! through the same [`UsbDevice`](crate::UsbDevice) API as real ones. Other
+//! contexts keep using libusb. Turn off the default `libusb` feature to build
+//! without a C toolchain, every context then uses the mock.
+//!
+//! Every transfer takes the next [`MockResponse`] queued on its endpoint
+//! address (`0x80`/`0x00` for control IN/OUT). A transfer without a queued
+//! response stays pending until one is pushed. Transfer timeouts are not
+//! simulated, queue [`MockResponse::Timeout`] instead.
+//!
+//! ```ignore
+//! let ctx = UsbContext::builder().with_mock().build()?;
+//! let mock = MockDevice::new(0x1D50, 0x6089).with_product("HackRF One").plug_into(&ctx);
+//! mock.push(0x80, MockResponse::Data(b"2023.01.1".to_vec()));
+//! let device = ctx.open_with_vid_pid(0x1D50, 0x6089)?;
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use crate::define::*;
+use crate::context::UsbContext;
+use crate::platform::mock::MockDeviceState;
+
+static NEXT_ADDRESS: AtomicU8 = AtomicU8::new(1);
+
+/// Scripted reaction of a virtual device to one transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockResponse {
+    /// Data returned to an IN transfer. More data than requested overflows.
+    Data(Vec<u8>),
+    /// Accept the whole payload of an OUT transfer.
+    Ack,
+    /// The endpoint answers with a STALL handshake.
+    Stall,
+    /// The transfer times out.
+    Timeout,
+    /// An IN transfer receives these bytes, then times out.
+    PartialData(Vec<u8>),
+    /// An OUT transfer gets this many bytes through, then times out.
+    PartialAck(usize),
+    /// An OUT transfer gets this many bytes through, then the endpoint stalls.
+    PartialStall(usize),
+    /// The device is unplugged while handling the transfer.
+    Disconnect,
+    /// One response per packet of an isochronous transfer.
+    Iso(Vec<MockResponse>),
+}
+
+/// Description of a virtual device, registered with [`MockDevice::plug_into`].
+pub struct MockDevice {
+    descriptor: DeviceDescriptor,
+    configs: Vec<ConfigDescriptor>,
+    strings: HashMap<u8, String>,
+    bus_number: u8,
+    device_address: u8,
+    port_numbers: Vec<u8>,
+    kernel_drivers: HashSet<u8>,
+}
+
+impl MockDevice {
+    pub fn new(vid: u16, pid: u16) -> Self {
+        Self {
+            descriptor: DeviceDescriptor {
+                bLength: 18,
+                bDescriptorType: 1,
+                bcdUSB: 0x0200,
+                bMaxPacketSize0: 64,
+                idVendor: vid,
+                idProduct: pid,
+                ..Default::default()
+            },
+            configs: vec![],
+            strings: HashMap::new(),
+            bus_number: 1,
+            device_address: 0,
+            port_numbers: vec![],
+            kernel_drivers: HashSet::new(),
+        }
+    }
+
+    /// Replace the device descriptor. `bNumConfigurations` is kept in sync with the configs.
+    pub fn with_descriptor(mut self, descriptor: DeviceDescriptor) -> Self {
+        self.descriptor = descriptor;
+        self
+    }
+
+    /// Add a configuration. The first one added is active when plugged.
+    pub fn with_config(mut self, config: ConfigDescriptor) -> Self {
+        self.configs.push(config);
+        self
+    }
+
+    pub fn with_string(mut self, index: u8, value: &str) -> Self {
+        self.strings.insert(index, value.to_string());
+        self
+    }
+
+    pub fn with_manufacturer(mut self, value: &str) -> Self {
+        self.descriptor.iManufacturer = self.add_string(value);
+        self
+    }
+
+    pub fn with_product(mut self, value: &str) -> Self {
+        self.descriptor.iProduct = self.add_string(value);
+        self
+    }
+
+    pub fn with_serial_number(mut self, value: &str) -> Self {
+        self.descriptor.iSerialNumber = self.add_string(value);
+        self
+    }
+
+    /// Bus number and device address. An address of 0 is assigned on plug.
+    pub fn with_bus_address(mut self, bus_number: u8, device_address: u8) -> Self {
+        self.bus_number = bus_number;
+        self.device_address = device_address;
+        self
+    }
+
+    /// Ports from the root hub to the device.
+    pub fn with_port_numbers(mut self, port_numbers: &[u8]) -> Self {
+        self.port_numbers = port_numbers.to_vec();
+        self
+    }
+
+    /// Bind a kernel driver to an interface, so claiming it fails with `Busy` until detached.
+    pub fn with_kernel_driver(mut self, interface_number: u8) -> Self {
+        self.kernel_drivers.insert(interface_number);
+        self
+    }
+
+    /// Attach the device to the [global](UsbContext::global) context,
+    /// making it visible to the associated functions of
+    /// [`UsbDevice`](crate::UsbDevice).
+    ///
+    /// # Panics
+    ///
+    /// Unless eusb is built without the `libusb` feature, the global context
+    /// uses libusb and this panics. Use [`plug_into`](Self::plug_into) then.
+    pub fn plug(self) -> MockHandle {
+        self.plug_into(&UsbContext::global().unwrap())
+    }
+
+    /// Attach the device to `context` only.
+    ///
+    /// # Panics
+    ///
+    /// If `context` was not built [`with_mock`](crate::context::UsbContextBuilder::with_mock).
+    pub fn plug_into(mut self, context: &UsbContext) -> MockHandle {
+        let manager = context.platform.clone().as_mock()
+            .expect("mock devices plug into a context built with_mock");
+        if self.configs.is_empty() {
+            self.configs.push(ConfigDescriptor {
+                value: 1,
+                interfaces: vec![],
+                extra: vec![],
+                max_power: 0,
+                configuration: String::new(),
+            });
+        }
+        self.descriptor.bNumConfigurations = self.configs.len() as _;
+        if self.device_address == 0 {
+            self.device_address = NEXT_ADDRESS.fetch_add(1, Ordering::Relaxed);
+        }
+        let state = Arc::new(MockDeviceState::new(
+            self.descriptor, self.configs, self.strings, self.bus_number, self.device_address, self.port_numbers,
+            self.kernel_drivers));
+        manager.plug(state.clone());
+        MockHandle { state }
+    }
+
+    fn add_string(&mut self, value: &str) -> u8 {
+        let index = (1..=u8::MAX).find(|i| !self.strings.contains_key(i)).unwrap();
+        self.strings.insert(index, value.to_string());
+        index
+    }
+}
+
+/// Test side of a plugged virtual device.
+#[derive(Clone)]
+pub struct MockHandle {
+    state: Arc<MockDeviceState>,
+}
+
+impl MockHandle {
+    /// Queue a response on an endpoint address, e.g. `0x81` for bulk IN 1.
+    pub fn push(&self, endpoint: u8, response: MockResponse) {
+        self.state.push_response(endpoint, response);
+    }
+
+    /// Number of transfers waiting for a response on an endpoint address.
+    pub fn pending(&self, endpoint: u8) -> usize {
+        self.state.pending(endpoint)
+    }
+
+    /// Payloads of the acknowledged OUT transfers on an endpoint address since the last call.
+    pub fn take_written(&self, endpoint: u8) -> Vec<Vec<u8>> {
+        self.state.take_written(endpoint)
+    }
+
+    /// Setup packets of the control transfers issued since the last call.
+    pub fn take_control_setups(&self) -> Vec<ControlSetup> {
+        self.state.take_control_setups()
+    }
+
+    pub fn is_claimed(&self, interface_number: u8) -> bool {
+        self.state.is_claimed(interface_number)
+    }
+
+    /// Whether an endpoint address stalled and the host has not cleared the halt since.
+    pub fn is_halted(&self, endpoint: u8) -> bool {
+        self.state.is_halted(endpoint)
+    }
+
+    /// Bulk streams allocated on an endpoint address, `None` once freed.
+    pub fn streams(&self, endpoint: u8) -> Option<u32> {
+        self.state.streams(endpoint)
+    }
+
+    /// Current alt setting of an interface, `None` while it is not claimed.
+    pub fn alt_setting(&self, interface_number: u8) -> Option<u8> {
+        self.state.alt_setting(interface_number)
+    }
+
+    pub fn kernel_driver_active(&self, interface_number: u8) -> bool {
+        self.state.kernel_driver_active(interface_number).unwrap_or(false)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.is_connected()
+    }
+
+    /// Detach the device. Pending and later transfers fail with `NoDevice`.
+    pub fn unplug(&self) {
+        self.state.disconnect();
+    }
+}
+
+/// Fixtures of the tests driving virtual devices.
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::context::UsbContext;
+    use crate::device::UsbDevice;
+    use super::*;
+
+    /// A context of its own, so tests running in parallel never see each
+    /// other's devices.
+    pub(crate) fn context() -> UsbContext {
+        UsbContext::builder().with_mock().build().unwrap()
+    }
+
+    /// A virtual device without interfaces, to be passed to [`open`].
+    pub(crate) fn mock_device() -> MockDevice {
+        MockDevice::new(0x1D50, 0x6089)
+    }
+
+    /// Plug `mock` into a new [`context`] and open it.
+    pub(crate) fn open(mock: MockDevice) -> (MockHandle, UsbDevice) {
+        let ctx = context();
+        let (vid, pid) = (mock.descriptor.idVendor, mock.descriptor.idProduct);
+        let handle = mock.plug_into(&ctx);
+        (handle, ctx.open_with_vid_pid(vid, pid).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::error::{Error, TransferContext};
+    use crate::prelude::*;
+    use super::*;
+    use super::test::*;
+
+    #[tokio::test]
+    async fn test_descriptors() {
+        let ctx = context();
+        let mock = MockDevice::new(0xA001, 1)
+            .with_manufacturer("eusb")
+            .with_product("mock")
+            .with_serial_number("0001")
+            .plug_into(&ctx);
+        let device = ctx.open_with_vid_pid(0xA001, 1).unwrap();
+
+        assert_eq!(device.manufacturer().unwrap(), "eusb");
+        assert_eq!(device.product().unwrap(), "mock");
+        assert_eq!(device.serial_number().unwrap(), "0001");
+        assert_eq!(device.get_active_configuration().unwrap().value, 1);
+        assert_eq!(ctx.list().unwrap().len(), 1);
+
+        mock.unplug();
+        assert!(matches!(ctx.open_with_vid_pid(0xA001, 1), Err(Error::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_control_transfer() {
+        let (mock, device) = open(mock_device());
+
+        mock.push(0x80, MockResponse::Data(b"v1.0".to_vec()));
+        let data = device.control_transfer_in(ControlTransferRequest {
+            recipient: UsbControlRecipient::Device,
+            transfer_type: UsbControlTransferType::Vendor,
+            request: 15,
+            ..Default::default()
+        }, 30).await.unwrap();
+        assert_eq!(data, b"v1.0");
+
+        mock.push(0x00, MockResponse::Ack);
+        let n = device.control_transfer_out(ControlTransferRequest {
+            recipient: UsbControlRecipient::Device,
+            transfer_type: UsbControlTransferType::Vendor,
+            request: 1,
+            value: 1,
+            ..Default::default()
+        }, &[1, 2]).await.unwrap();
+        assert_eq!(n, 2);
+
+        let setups = mock.take_control_setups();
+        assert_eq!(setups[0], ControlSetup { request_type: 0xC0, request: 15, value: 0, index: 0, length: 30 });
+        assert_eq!(setups[1], ControlSetup { request_type: 0x40, request: 1, value: 1, index: 0, length: 2 });
+        assert_eq!(mock.take_written(0x00), vec![vec![1, 2]]);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_and_interrupt() {
+        let (mock, device) = open(mock_device());
+        let timeout = Duration::from_secs(1);
+
+        mock.push(0x81, MockResponse::Data(vec![1, 2, 3]));
+        assert_eq!(device.bulk_transfer_in(1, 64, timeout).await.unwrap(), vec![1, 2, 3]);
+
+        mock.push(0x81, MockResponse::Data(vec![0; 65]));
+        let e = device.bulk_transfer_in(1, 64, timeout).await.unwrap_err();
+        assert!(matches!(e.kind(), Error::Overflow));
+        assert_eq!(e.context(), Some(&TransferContext {
+            device: device.id(),
+            transfer_type: EndpointTransferType::Bulk,
+            endpoint: 0x81,
+            actual_length: 64,
+            data: vec![0; 64],
+        }));
+
+        mock.push(0x02, MockResponse::Ack);
+        assert_eq!(device.bulk_transfer_out(2, &[4, 5], timeout).await.unwrap(), 2);
+        assert_eq!(mock.take_written(0x02), vec![vec![4, 5]]);
+
+        mock.push(0x83, MockResponse::Stall);
+        assert!(device.interrupt_transfer_in(3, 8, timeout).await.is_err());
+
+        mock.push(0x83, MockResponse::Timeout);
+        assert!(matches!(device.interrupt_transfer_in(3, 8, timeout).await.unwrap_err().kind(), Error::Timeout));
+    }
+
+    #[tokio::test]
+    async fn test_iso_transfer() {
+        let (mock, device) = open(mock_device());
+
+        mock.push(0x81, MockResponse::Iso(vec![
+            MockResponse::Data(vec![1; 4]),
+            MockResponse::Data(vec![2; 2]),
+        ]));
+        let packs = device.iso_transfer_in(1, 2, 4, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(packs, vec![vec![1; 4], vec![2; 2]]);
+    }
+
+    #[tokio::test]
+    async fn test_pending_and_disconnect() {
+        let (mock, device) = open(mock_device());
+
+        let pusher = mock.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            pusher.push(0x81, MockResponse::Data(vec![7]));
+        });
+        assert_eq!(device.bulk_transfer_in(1, 8, Duration::ZERO).await.unwrap(), vec![7]);
+
+        mock.push(0x81, MockResponse::Disconnect);
+        assert!(matches!(device.bulk_transfer_in(1, 8, Duration::ZERO).await.unwrap_err().kind(), Error::NoDevice));
+        assert!(!mock.is_connected());
+        assert!(matches!(device.bulk_transfer_in(1, 8, Duration::ZERO).await.unwrap_err().kind(), Error::NoDevice));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_on_drop() {
+        let (mock, device) = open(mock_device());
+
+        let r = tokio::time::timeout(Duration::from_millis(10), device.bulk_transfer_in(1, 8, Duration::ZERO)).await;
+        assert!(r.is_err());
+        assert_eq!(mock.pending(0x81), 0);
+
+        let mut transfer = Box::pin(device.bulk_transfer_out(1, &[1, 2], Duration::ZERO));
+        assert!(futures::poll!(transfer.as_mut()).is_pending());
+        assert_eq!(mock.pending(0x01), 1);
+        drop(transfer);
+        assert_eq!(mock.pending(0x01), 0);
+
+        tokio::select! {
+            _ = device.interrupt_transfer_in(2, 8, Duration::ZERO) => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+        }
+        assert_eq!(mock.pending(0x82), 0);
+
+        mock.push(0x81, MockResponse::Data(vec![3]));
+        assert_eq!(device.bulk_transfer_in(1, 8, Duration::ZERO).await.unwrap(), vec![3]);
+        mock.push(0x01, MockResponse::Ack);
+        assert_eq!(device.bulk_transfer_out(1, &[4], Duration::ZERO).await.unwrap(), 1);
+        assert_eq!(mock.take_written(0x01), vec![vec![4]]);
+    }
+}
@@ -1,78 +1,207 @@
+use std::cell::Cell;
+use std::ffi::c_int;
 use std::ptr::{null_mut, slice_from_raw_parts};
+use std::time::Duration;
+use libc::timeval;
 use log::debug;
 use crate::error::*;
 use libusb_src::*;
-use crate::platform::libusb::device::Device;
 use crate::platform::libusb::errors::*;
-use crate::platform::libusb::device_handle::DeviceHandle;
+use crate::platform::libusb::hotplug::{hotplug_cb, HotplugSender};
+use crate::hotplug::HotplugFilter;
 use crate::platform::*;
+#[cfg(unix)]
+use std::sync::Arc;
+#[cfg(unix)]
+use std::ffi::{c_short, c_void};
+#[cfg(unix)]
+use crate::reactor::{PollFd, PollfdWatch};
 
+thread_local! {
+    /// Set while the thread is in [`Context::handle_events`].
+    static HANDLING_EVENTS: Cell<bool> = const { Cell::new(false) };
+}
 
-pub(crate) struct Context(*mut libusb_context);
+pub(crate) struct Context {
+    ptr: *mut libusb_context,
+    /// `user_data` of the pollfd notifiers.
+    #[cfg(unix)]
+    pollfd_watch: Arc<PollfdWatch>,
+}
 
 unsafe impl Send for Context {}
 
 unsafe impl Sync for Context {}
 
 impl Context {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new() -> Result<Self> {
         unsafe {
             let mut ptr = null_mut();
 
             #[cfg(target_os = "android")]
-            check_err( libusb_set_option(ptr, LIBUSB_OPTION_NO_DEVICE_DISCOVERY)).unwrap();
+            check_err( libusb_set_option(ptr, LIBUSB_OPTION_NO_DEVICE_DISCOVERY))?;
 
-            check_err(libusb_init(&mut ptr)).unwrap();
+            check_err(libusb_init(&mut ptr))?;
             debug!("libusb_init");
-            Self(ptr)
+            let ctx = Self {
+                ptr,
+                #[cfg(unix)]
+                pollfd_watch: Default::default(),
+            };
+            #[cfg(unix)]
+            libusb_set_pollfd_notifiers(
+                ptr, Some(pollfd_added_cb), Some(pollfd_removed_cb), Arc::as_ptr(&ctx.pollfd_watch) as _);
+            Ok(ctx)
         }
     }
 
-    pub(crate) fn device_list(&self) -> Result<Vec<Device>> {
+    /// Referenced devices, each one must be unreferenced.
+    pub(crate) fn device_list(&self) -> Result<Vec<*mut libusb_device>> {
         unsafe {
             let mut devs_raw: *const *mut libusb_device = null_mut();
-            let count = check_err(libusb_get_device_list(self.0, &mut devs_raw) as _)? as usize;
+            let count = check_err(libusb_get_device_list(self.ptr, &mut devs_raw) as _)? as usize;
             let list = &*slice_from_raw_parts(devs_raw, count);
-            let out: Vec<Device> = list.iter().copied().map(|o| o.into()).collect();
+            let out = list.to_vec();
             libusb_free_device_list(devs_raw, 0);
             Ok(out)
         }
     }
-    pub(crate) fn handle_events(&self, )->Result{
+    pub(crate) fn handle_events(&self, timeout: Duration)->Result{
+        let tv = timeval {
+            tv_sec: timeout.as_secs() as _,
+            tv_usec: timeout.subsec_micros() as _,
+        };
+        HANDLING_EVENTS.with(|o| o.set(true));
+        let r = unsafe { libusb_handle_events_timeout_completed(self.ptr, &tv, null_mut()) };
+        HANDLING_EVENTS.with(|o| o.set(false));
+        check_err(r)?;
+        Ok(())
+    }
+
+    /// Make the thread handling events return from libusb.
+    pub(crate) fn interrupt_event_handler(&self) {
+        unsafe { libusb_interrupt_event_handler(self.ptr) }
+    }
+
+    /// Wait until no thread is handling events, so every callback dispatched
+    /// before returned. Does nothing on a thread handling events.
+    pub(crate) fn wait_event_handler(&self) {
+        if HANDLING_EVENTS.with(|o| o.get()) {
+            return;
+        }
+        self.interrupt_event_handler();
         unsafe {
-            check_err(libusb_handle_events(self.0))?;
+            libusb_lock_events(self.ptr);
+            libusb_unlock_events(self.ptr);
         }
-        Ok(())
     }
-    pub(crate) fn open_device_with_vid_pid(&self, vid: u16, pid: u16 )->Result<DeviceHandle>{
+    pub(crate) fn open_device_with_vid_pid(&self, vid: u16, pid: u16 )->Result<*mut libusb_device_handle>{
         unsafe {
-            let h = libusb_open_device_with_vid_pid(self.0, vid, pid);
+            let h = libusb_open_device_with_vid_pid(self.ptr, vid, pid);
             if h.is_null() {
                 return Err(Error::NotFound);
             }
-            Ok(DeviceHandle::from(h))
+            Ok(h)
         }
     }
 
     #[cfg(unix)]
-    pub(crate) fn open_device_with_fd(&self, fd: RawFd)->Result<DeviceHandle>{
+    pub(crate) fn open_device_with_fd(&self, fd: RawFd)->Result<*mut libusb_device_handle>{
         unsafe {
             let mut handle= null_mut();
-            check_err( libusb_wrap_sys_device(self.0, fd as _, &mut handle))?;
-            Ok(DeviceHandle::from(handle))
+            check_err( libusb_wrap_sys_device(self.ptr, fd as _, &mut handle))?;
+            Ok(handle)
         }
     }
 
-    pub(crate) fn exit(&self){
+    pub(crate) fn hotplug_register(&self, filter: &HotplugFilter, sender: *const HotplugSender)->Result<libusb_hotplug_callback_handle>{
         unsafe {
-            if !self.0.is_null() {
-                debug!("libusb_exit");
-                libusb_exit(self.0);
+            if libusb_has_capability(LIBUSB_CAP_HAS_HOTPLUG) == 0 {
+                return Err(Error::NotSupported);
             }
+            let mut handle = 0;
+            check_err(libusb_hotplug_register_callback(
+                self.ptr,
+                LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+                if filter.enumerate { LIBUSB_HOTPLUG_ENUMERATE } else { LIBUSB_HOTPLUG_NO_FLAGS },
+                filter.vid.map(|o| o as c_int).unwrap_or(LIBUSB_HOTPLUG_MATCH_ANY),
+                filter.pid.map(|o| o as c_int).unwrap_or(LIBUSB_HOTPLUG_MATCH_ANY),
+                filter.class.map(|o| o as c_int).unwrap_or(LIBUSB_HOTPLUG_MATCH_ANY),
+                hotplug_cb,
+                sender as _,
+                &mut handle,
+            ))?;
+            debug!("hotplug register");
+            Ok(handle)
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn pollfd_watch(&self) -> Arc<PollfdWatch> {
+        self.pollfd_watch.clone()
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn pollfds(&self) -> Result<Vec<PollFd>> {
+        unsafe {
+            let list = libusb_get_pollfds(self.ptr);
+            if list.is_null() {
+                return Err(Error::NotSupported);
+            }
+            let mut out = vec![];
+            let mut one = list;
+            while !(*one).is_null() {
+                let events = (**one).events;
+                out.push(PollFd {
+                    fd: (**one).fd,
+                    readable: events & libc::POLLIN != 0,
+                    writable: events & libc::POLLOUT != 0,
+                });
+                one = one.add(1);
+            }
+            libusb_free_pollfds(list);
+            Ok(out)
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn next_timeout(&self) -> Result<Option<Duration>> {
+        // Without a timerfd, a transfer submitted later may time out earlier.
+        const RECHECK: Duration = Duration::from_millis(100);
+
+        unsafe {
+            let mut tv = timeval { tv_sec: 0, tv_usec: 0 };
+            let r = check_err(libusb_get_next_timeout(self.ptr, &mut tv))?;
+            let timeout = (r == 1).then(|| Duration::new(tv.tv_sec as _, tv.tv_usec as u32 * 1000));
+            if libusb_pollfds_handle_timeouts(self.ptr) != 0 {
+                return Ok(timeout);
+            }
+            Ok(Some(timeout.map_or(RECHECK, |o| o.min(RECHECK))))
         }
     }
-}
 
+    pub(crate) unsafe fn hotplug_deregister(&self, handle: libusb_hotplug_callback_handle){
+        libusb_hotplug_deregister_callback(self.ptr, handle);
+    }
+}
 
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ptr.is_null() {
+                debug!("libusb_exit");
+                libusb_exit(self.ptr);
+            }
+        }
+    }
+}
 
+#[cfg(unix)]
+extern "system" fn pollfd_added_cb(_fd: c_int, _events: c_short, user_data: *mut c_void) {
+    unsafe { (*(user_data as *const PollfdWatch)).added() }
+}
 
+#[cfg(unix)]
+extern "system" fn pollfd_removed_cb(fd: c_int, user_data: *mut c_void) {
+    unsafe { (*(user_data as *const PollfdWatch)).removed(fd) }
+}
@@ -1,14 +1,15 @@
+use std::collections::HashMap;
 use std::future::Future;
-use std::ptr::{null, null_mut, slice_from_raw_parts, slice_from_raw_parts_mut};
+use std::ptr::{null, null_mut, slice_from_raw_parts};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use libusb_src::*;
 
-use crate::define::{ConfigDescriptor, ControlTransferRequest, DeviceClass, DeviceDescriptor, Direction, PipConfig, Speed};
-use crate::platform::{AsyncResult, DeviceCtx};
+use crate::define::{ConfigDescriptor, ControlTransferRequest, DeviceClass, DeviceDescriptor, Direction, IsoPacket, IsoPacketResult, PipConfig, Speed};
+use crate::platform::{AsyncBufResult, AsyncResult, DeviceCtx, EndpointPipInInner, EndpointPipOutInner, ManagerCtxImpl};
 use crate::platform::libusb::{class_from_lib, config_descriptor_convert, status_to_result, ToLib};
-use crate::platform::libusb::device_handle::{DeviceHandle, sync_cb, TransferDirection};
-use crate::platform::libusb::endpoint::EndpointPipInImpl;
+use crate::platform::libusb::device_handle::{DeviceHandle, InterfaceUse, sync_cb, TransferDirection};
+use crate::platform::libusb::endpoint::{EndpointPipInImpl, EndpointPipOutImpl};
 use crate::platform::libusb::errors::*;
 use crate::platform::libusb::transfer::Transfer;
 
@@ -62,16 +63,9 @@ impl DeviceCtxImpl {
         f(h)
     }
 
-    fn open_endpoint(&self, endpoint: u8) -> Result {
-        let cfg = self.dev.get_active_config_descriptor(None)?;
-        let interface_num = endpoint_get_interface_num(&cfg, endpoint);
-        self.use_opened(|h| {
-            h.handle.claim_interface(interface_num)?;
-            Ok(())
-        })?;
-
-
-        Ok(())
+    fn open_endpoint(&self, endpoint: u8) -> Result<InterfaceUse> {
+        let handle = open(&self.dev, &self.opened)?;
+        open_endpoint(endpoint, &self.dev, &handle)
     }
 
 
@@ -87,6 +81,24 @@ impl DeviceCtxImpl {
         }
     }
 
+    /// Run `f` on the opened device with the interface of `endpoint` claimed,
+    /// handing `buf` back if it can not run.
+    fn buf_transfer<F, O>(&self, endpoint: u8, buf: Vec<u8>, f: F) -> AsyncBufResult
+        where F: FnOnce(Arc<DeviceHandle>, Vec<u8>) -> O + Send + 'static,
+              O: Future<Output=(Vec<u8>, Result<usize>)> + Send + 'static
+    {
+        let opened = self.opened.clone();
+        let dev = self.dev.clone();
+        Box::pin(async move {
+            let r = open(&dev, &opened)
+                .and_then(|handle| Ok((open_endpoint(endpoint, &dev, &handle)?, handle)));
+            match r {
+                Ok((_interface, handle)) => f(handle, buf).await,
+                Err(e) => (buf, Err(e)),
+            }
+        })
+    }
+
     fn get_configuration_descriptor(&self, index: u8) -> Result<ConfigDescriptor> {
         let g = self.opened.lock().unwrap();
         let handle = g.as_ref().map(|o| o.handle.as_ref());
@@ -108,24 +120,35 @@ fn open(dev: &Arc<Device>, opened: &Arc<Mutex<Option<OpenedDevice>>>) -> Result<
     Ok(h)
 }
 
-fn endpoint_get_interface_num(cfg: &ConfigDescriptor, endpoint: u8) -> u8 {
+/// Interface owning `endpoint`, preferring a claimed interface whose
+/// current alt setting has the endpoint.
+fn endpoint_get_interface_num(cfg: &ConfigDescriptor, endpoint: u8, claimed: &HashMap<u8, u8>) -> Option<u8> {
+    let mut found = None;
     for alt in &cfg.interfaces {
         for interface in &alt.alt_settings {
             for ep in &interface.endpoints {
-                if ep.num == endpoint {
-                    return interface.num;
+                if ep.num == endpoint & LIBUSB_ENDPOINT_ADDRESS_MASK {
+                    if claimed.get(&interface.num) == Some(&interface.alt_setting) {
+                        return Some(interface.num);
+                    }
+                    found.get_or_insert(interface.num);
                 }
             }
         }
     }
-    0
+    found
+}
+
+/// Claim the interface of `endpoint` for as long as the returned guard lives.
+fn open_endpoint(endpoint: u8, dev: &Arc<Device>, handle: &Arc<DeviceHandle>) -> Result<InterfaceUse> {
+    handle.use_interface(endpoint_interface(endpoint, dev, handle)?)
 }
 
-fn open_endpoint(endpoint: u8, dev: &Arc<Device>, handle: &Arc<DeviceHandle>) -> Result {
+/// Fails with `NotFound` if no interface of the active configuration has `endpoint`.
+fn endpoint_interface(endpoint: u8, dev: &Arc<Device>, handle: &DeviceHandle) -> Result<u8> {
     let cfg = dev.get_active_config_descriptor(None)?;
-    let interface_num = endpoint_get_interface_num(&cfg, endpoint);
-    handle.claim_interface(interface_num)?;
-    Ok(())
+    endpoint_get_interface_num(&cfg, endpoint, &handle.claimed_alt_settings())
+        .ok_or(Error::NotFound)
 }
 macro_rules! async_opened {
     ($self:ident, $a: ident, $b: ident, $f: expr) => {
@@ -238,6 +261,10 @@ impl DeviceCtx for DeviceCtxImpl {
         }
     }
 
+    fn port_numbers(&self) -> Result<Vec<u8>> {
+        self.dev.port_numbers()
+    }
+
     fn get_active_configuration(&self) -> Result<ConfigDescriptor> {
         let g = self.opened.lock().unwrap();
         let handle = g.as_ref().map(|o| o.handle.as_ref());
@@ -245,16 +272,49 @@ impl DeviceCtx for DeviceCtxImpl {
     }
 
 
+    fn claim_interface(&self, interface_number: u8) -> Result {
+        self.use_opened(|h| h.handle.claim_interface(interface_number))
+    }
+
+    fn release_interface(&self, interface_number: u8) -> Result {
+        self.use_opened(|h| h.handle.release_interface(interface_number))
+    }
+
+    fn set_interface_alt_setting(&self, interface_number: u8, alt_setting: u8) -> Result {
+        self.use_opened(|h| h.handle.set_interface_alt_setting(interface_number, alt_setting))
+    }
+
+    fn kernel_driver_active(&self, interface_number: u8) -> Result<bool> {
+        self.use_opened(|h| h.handle.kernel_driver_active(interface_number))
+    }
+
+    fn detach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.use_opened(|h| h.handle.detach_kernel_driver(interface_number))
+    }
+
+    fn attach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.use_opened(|h| h.handle.attach_kernel_driver(interface_number))
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result {
+        self.use_opened(|h| h.handle.clear_halt(endpoint))
+    }
+
     fn control_transfer_in(
         &self, control_transfer_request: ControlTransferRequest, capacity: usize) -> AsyncResult<Vec<u8>> {
         let rt: u8 = Direction::In.to_lib() | control_transfer_request.transfer_type.to_lib() | control_transfer_request.recipient.to_lib();
+        let index = control_transfer_request.setup(Direction::In, 0).index;
+        let interface = control_transfer_request.interface_to_claim();
 
         async_opened!(self, _dev, handle, {
+            if let Some(interface) = interface {
+                handle.claim_interface(interface)?;
+            }
             let tran = handle.control_transfer(
                     TransferDirection::In { len: capacity }, rt as _,
                     control_transfer_request.request,
                     control_transfer_request.value,
-                    control_transfer_request.index,
+                    index,
                     control_transfer_request.timeout).await?;
                 Ok(tran.control_transfer_get_data().to_vec())
         })
@@ -264,14 +324,19 @@ impl DeviceCtx for DeviceCtxImpl {
         &self,
         control_transfer_request: ControlTransferRequest, data: &[u8]) -> AsyncResult<usize> {
         let rt: u8 = Direction::Out.to_lib() | control_transfer_request.transfer_type.to_lib() | control_transfer_request.recipient.to_lib();
+        let index = control_transfer_request.setup(Direction::Out, 0).index;
+        let interface = control_transfer_request.interface_to_claim();
         let data = data.to_vec();
 
         async_opened!(self, _dev, handle, {
+            if let Some(interface) = interface {
+                handle.claim_interface(interface)?;
+            }
             let tran = handle.control_transfer(
                 TransferDirection::Out { data }, rt,
                 control_transfer_request.request,
                 control_transfer_request.value,
-                control_transfer_request.index,
+                index,
                 control_transfer_request.timeout).await?;
             Ok(tran.actual_length())
         })
@@ -279,13 +344,13 @@ impl DeviceCtx for DeviceCtxImpl {
 
     fn bulk_transfer_in(&self, endpoint: u8, capacity: usize, timeout: Duration) -> AsyncResult<Vec<u8>> {
         async_opened!(self, dev, handle, {
-            open_endpoint(endpoint, &dev, &handle)?;
+            let _interface = open_endpoint(endpoint, &dev, &handle)?;
 
-            let tran = handle.bulk_transfer(
+            let mut tran = handle.bulk_transfer(
                 TransferDirection::In { len: capacity },
                 endpoint, timeout, false).await?;
 
-            Ok(tran.data[..tran.actual_length()].to_vec())
+            Ok(tran.take_data())
         })
     }
 
@@ -293,7 +358,7 @@ impl DeviceCtx for DeviceCtxImpl {
         let data = data.to_vec();
 
         async_opened!(self, dev, handle, {
-            open_endpoint(endpoint, &dev, &handle)?;
+            let _interface = open_endpoint(endpoint, &dev, &handle)?;
 
             let tran = handle.bulk_transfer(
                 TransferDirection::Out { data },
@@ -303,15 +368,76 @@ impl DeviceCtx for DeviceCtxImpl {
         })
     }
 
+    fn bulk_transfer_in_buf(&self, endpoint: u8, buf: Vec<u8>, timeout: Duration) -> AsyncBufResult {
+        self.buf_transfer(endpoint, buf, move |handle, buf| async move {
+            let tran = unsafe {
+                let mut tran = Transfer::bulk_transfer(endpoint, sync_cb, TransferDirection::In { len: 0 }, timeout);
+                tran.set_buffer(buf);
+                tran
+            };
+            let (mut tran, r) = handle.submit_and_wait(tran).await;
+            let len = tran.actual_length();
+            (std::mem::take(&mut tran.data), r.map(|_| len))
+        })
+    }
+
+    fn bulk_transfer_out_buf(&self, endpoint: u8, data: Vec<u8>, len: usize, timeout: Duration) -> AsyncBufResult {
+        self.buf_transfer(endpoint, data, move |handle, data| async move {
+            let tran = unsafe {
+                let tran = Transfer::bulk_transfer(endpoint, sync_cb, TransferDirection::Out { data }, timeout);
+                (*tran.ptr).length = len as _;
+                tran
+            };
+            let (mut tran, r) = handle.submit_and_wait(tran).await;
+            let len = tran.actual_length();
+            (std::mem::take(&mut tran.data), r.map(|_| len))
+        })
+    }
+
+    fn alloc_streams(&self, num_streams: u32, endpoints: &[u8]) -> Result<u32> {
+        let handle = open(&self.dev, &self.opened)?;
+        for &endpoint in endpoints {
+            handle.claim_interface(endpoint_interface(endpoint, &self.dev, &handle)?)?;
+        }
+        handle.alloc_streams(num_streams, endpoints)
+    }
+
+    fn free_streams(&self, endpoints: &[u8]) -> Result {
+        let handle = open(&self.dev, &self.opened)?;
+        handle.free_streams(endpoints)
+    }
+
+    fn bulk_stream_transfer_in(&self, endpoint: u8, stream_id: u32, capacity: usize, timeout: Duration) -> AsyncResult<Vec<u8>> {
+        async_opened!(self, _dev, handle, {
+            let mut tran = handle.bulk_stream_transfer(
+                TransferDirection::In { len: capacity },
+                endpoint, stream_id, timeout).await?;
+
+            Ok(tran.take_data())
+        })
+    }
+
+    fn bulk_stream_transfer_out(&self, endpoint: u8, stream_id: u32, data: &[u8], timeout: Duration) -> AsyncResult<usize> {
+        let data = data.to_vec();
+
+        async_opened!(self, _dev, handle, {
+            let tran = handle.bulk_stream_transfer(
+                TransferDirection::Out { data },
+                endpoint, stream_id, timeout).await?;
+
+            Ok(tran.actual_length())
+        })
+    }
+
     fn interrupt_transfer_in(&self, endpoint: u8, capacity: usize, timeout: Duration) -> AsyncResult<Vec<u8>> {
         async_opened!(self, dev, handle, {
-            open_endpoint(endpoint, &dev, &handle)?;
+            let _interface = open_endpoint(endpoint, &dev, &handle)?;
 
-            let tran = handle.bulk_transfer(
+            let mut tran = handle.bulk_transfer(
                 TransferDirection::In { len: capacity },
                 endpoint, timeout, true).await?;
 
-            Ok(tran.data[..tran.actual_length()].to_vec())
+            Ok(tran.take_data())
         })
     }
 
@@ -319,7 +445,7 @@ impl DeviceCtx for DeviceCtxImpl {
         let data = data.to_vec();
 
         async_opened!(self, dev, handle, {
-            open_endpoint(endpoint, &dev, &handle)?;
+            let _interface = open_endpoint(endpoint, &dev, &handle)?;
 
             let tran = handle.bulk_transfer(
                 TransferDirection::Out { data },
@@ -331,7 +457,7 @@ impl DeviceCtx for DeviceCtxImpl {
 
     fn iso_transfer_in(&self, endpoint: u8, num_iso_packages: usize, package_capacity: usize, timeout: Duration) -> AsyncResult<Vec<Vec<u8>>> {
         async_opened!(self, dev, handle, {
-            open_endpoint(endpoint, &dev, &handle)?;
+            let _interface = open_endpoint(endpoint, &dev, &handle)?;
 
             let tran = handle.iso_transfer(
                 TransferDirection::In { len: num_iso_packages * package_capacity },
@@ -352,42 +478,53 @@ impl DeviceCtx for DeviceCtxImpl {
         })
     }
 
-    fn iso_transfer_out(&self, endpoint: u8, mut packs: Vec<Vec<u8>>, timeout: Duration) -> AsyncResult<Vec<usize>> {
+    fn iso_transfer_out(&self, endpoint: u8, packs: Vec<Vec<u8>>, timeout: Duration) -> AsyncResult<Vec<IsoPacketResult>> {
         async_opened!(self, dev, handle, {
-            open_endpoint(endpoint, &dev, &handle)?;
-            let mut out = vec![0usize; packs.len()];
-            let pack_lens: Vec<_> = packs.iter().map(|o|o.len()).collect();
-            let num_iso_packets = pack_lens.len();
-            let mut data = vec![];
-            while let Some(mut o) = packs.pop(){
-                data.append(&mut o);
-            }
-            unsafe {
-                let tran = Transfer::iso_transfer(endpoint, num_iso_packets as _, sync_cb,  TransferDirection::Out{ data  }, timeout);
-                let mut packs_raw = &mut*slice_from_raw_parts_mut((*tran.ptr).iso_packet_desc.as_mut_ptr(), num_iso_packets);
-                for (i,raw) in packs_raw.iter_mut().enumerate(){
-                    raw.length = pack_lens[i] as _;
-                }
-                let tran_new =  handle.do_sync_transfer(tran).await?;
-                packs_raw = &mut*slice_from_raw_parts_mut((*tran_new.ptr).iso_packet_desc.as_mut_ptr(), num_iso_packets);
-                 for (i,raw) in packs_raw.iter_mut().enumerate(){
-                    out[i] = raw.actual_length as _;
-                }
+            let _interface = open_endpoint(endpoint, &dev, &handle)?;
+            if packs.is_empty() {
+                return Err(Error::InvalidParam);
             }
-            Ok(out)
+            let tran = unsafe { Transfer::iso_transfer_out(endpoint, packs, sync_cb, timeout) };
+            let tran = handle.do_sync_transfer(tran).await?;
+            Ok(tran.iso_packet_results())
         })
     }
 
 
-    fn bulk_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<EndpointPipInImpl> {
+    fn bulk_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
         let handle = open(&self.dev, &self.opened)?;
-        self.open_endpoint(endpoint)?;
-        EndpointPipInImpl::new(&handle, endpoint, pip_config)
+        let interface = self.open_endpoint(endpoint)?;
+        EndpointPipInImpl::open(&handle, interface, endpoint, pip_config, false)
+    }
+
+    fn interrupt_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
+        let handle = open(&self.dev, &self.opened)?;
+        let interface = self.open_endpoint(endpoint)?;
+        EndpointPipInImpl::open(&handle, interface, endpoint, pip_config, true)
+    }
+
+    fn iso_transfer_pip_in(&self, endpoint: u8, num_iso_packages: usize, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner<Vec<IsoPacket>>>> {
+        let handle = open(&self.dev, &self.opened)?;
+        let interface = self.open_endpoint(endpoint)?;
+        Ok(Box::new(EndpointPipInImpl::new_iso(&handle, interface, endpoint, num_iso_packages, pip_config)?))
+    }
+
+    fn bulk_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner>> {
+        let handle = open(&self.dev, &self.opened)?;
+        let interface = self.open_endpoint(endpoint)?;
+        Ok(Box::new(EndpointPipOutImpl::<Vec<u8>>::new(&handle, interface, endpoint & 0x7F, pip_config)))
+    }
+
+    fn iso_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner<Vec<Vec<u8>>>>> {
+        let handle = open(&self.dev, &self.opened)?;
+        let interface = self.open_endpoint(endpoint)?;
+        Ok(Box::new(EndpointPipOutImpl::<Vec<Vec<u8>>>::new(&handle, interface, endpoint & 0x7F, pip_config)))
     }
 }
 
 
-pub(crate) struct Device(*mut libusb_device);
+/// A referenced libusb device, which keeps its context alive.
+pub(crate) struct Device(*mut libusb_device, Arc<ManagerCtxImpl>);
 
 unsafe impl Send for Device {}
 
@@ -395,11 +532,16 @@ unsafe impl Sync for Device {}
 
 #[allow(unused)]
 impl Device {
+    /// Takes over a reference of `ptr`.
+    pub fn new(ptr: *mut libusb_device, manager: Arc<ManagerCtxImpl>) -> Self {
+        Self(ptr, manager)
+    }
+
     pub fn open(&self) -> Result<DeviceHandle> {
         unsafe {
             let mut ptr = null_mut();
             check_err(libusb_open(self.0, &mut ptr))?;
-            let h = DeviceHandle::from(ptr);
+            let h = DeviceHandle::new(ptr, self.1.clone());
             Ok(h)
         }
     }
@@ -418,6 +560,15 @@ impl Device {
         }
     }
 
+    pub fn port_numbers(&self) -> Result<Vec<u8>> {
+        unsafe {
+            // USB 3.0 limits the depth of a hub chain to 7.
+            let mut ports = [0u8; 7];
+            let len = check_err(libusb_get_port_numbers(self.0, ports.as_mut_ptr(), ports.len() as _))?;
+            Ok(ports[..len as usize].to_vec())
+        }
+    }
+
     pub fn get_max_packet_size(&self, endpoint: usize) -> Result<usize> {
         unsafe {
             let r = check_err(libusb_get_max_packet_size(self.0, endpoint as _))?;
@@ -474,13 +625,6 @@ impl Device {
 }
 
 
-impl From<*mut libusb_device> for Device {
-    fn from(value: *mut libusb_device) -> Self {
-        Self(value)
-    }
-}
-
-
 impl Drop for Device {
     fn drop(&mut self) {
         unsafe {
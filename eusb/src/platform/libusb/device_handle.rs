@@ -1,14 +1,13 @@
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll, Waker};
 use std::time::Duration;
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use libusb_src::*;
-use crate::manager::Manager;
+use crate::platform::ManagerCtxImpl;
 use crate::platform::libusb::device::Device;
 use crate::platform::libusb::transfer::Transfer;
 use super::errors::*;
@@ -16,28 +15,55 @@ pub(crate) use super::transfer::TransferDirection;
 
 pub(crate) struct DeviceHandle {
     pub(crate) ptr: *mut libusb_device_handle,
-    claimed: RwLock<HashSet<u8>>,
+    claimed: RwLock<HashMap<u8, Claim>>,
+    /// Handles events as long as the device is open.
+    manager: Arc<ManagerCtxImpl>,
 }
 
-unsafe impl Send for DeviceHandle {}
+/// A claimed interface.
+#[derive(Default)]
+struct Claim {
+    alt_setting: u8,
+    /// Pipes and transfers holding an [`InterfaceUse`].
+    users: usize,
+    /// Released once the last user is done.
+    release: bool,
+}
 
-unsafe impl Sync for DeviceHandle {}
+/// Keeps an interface claimed while transfers may be in flight on it, even
+/// if its [`ClaimedInterface`](crate::interface::ClaimedInterface) is dropped
+/// meanwhile.
+pub(crate) struct InterfaceUse {
+    handle: Arc<DeviceHandle>,
+    interface_number: u8,
+}
 
-impl From<*mut libusb_device_handle> for DeviceHandle {
-    fn from(value: *mut libusb_device_handle) -> Self {
-        Manager::get().platform.open_device();
-        Self { ptr: value, claimed: RwLock::new(HashSet::new()) }
+impl Drop for InterfaceUse {
+    fn drop(&mut self) {
+        let mut g = self.handle.claimed.write().unwrap();
+        if let Some(claim) = g.get_mut(&self.interface_number) {
+            claim.users -= 1;
+            if claim.users == 0 && claim.release {
+                g.remove(&self.interface_number);
+                if let Err(e) = self.handle.release_claimed(self.interface_number) {
+                    warn!("release interface [{}] fail: {}", self.interface_number, e);
+                }
+            }
+        }
     }
 }
 
+unsafe impl Send for DeviceHandle {}
+
+unsafe impl Sync for DeviceHandle {}
+
 impl Drop for DeviceHandle {
     fn drop(&mut self) {
         unsafe {
             if !self.ptr.is_null() {
-                Manager::get().platform.close_device();
-                let g = self.claimed.read().unwrap();
-                for one in g.iter() {
-                    let _ = self.release_interface(*one);
+                self.manager.close_device();
+                for one in self.claimed_interfaces() {
+                    let _ = self.release_interface(one);
                 }
                 debug!("device close");
                 libusb_close(self.ptr);
@@ -48,27 +74,83 @@ impl Drop for DeviceHandle {
 
 #[allow(unused)]
 impl DeviceHandle {
+    pub fn new(ptr: *mut libusb_device_handle, manager: Arc<ManagerCtxImpl>) -> Self {
+        manager.open_device();
+        Self { ptr, claimed: RwLock::new(HashMap::new()), manager }
+    }
+
     pub fn claim_interface(&self, interface_number: u8) -> Result {
-        unsafe {
-            {
-                let g = self.claimed.read().unwrap();
-                if g.contains(&interface_number){
-                    return Ok(())
-                }
-            }
+        let mut g = self.claimed.write().unwrap();
+        self.claim(&mut g, interface_number)
+    }
 
-            trace!("claim interface [{:3}] begin", interface_number);
+    fn claim(&self, claimed: &mut HashMap<u8, Claim>, interface_number: u8) -> Result {
+        if let Some(claim) = claimed.get_mut(&interface_number) {
+            claim.release = false;
+            return Ok(());
+        }
+        trace!("claim interface [{:3}] begin", interface_number);
+        unsafe {
             check_err(libusb_claim_interface(self.ptr, interface_number as _))?;
-            let mut g = self.claimed.write().unwrap();
-            g.insert(interface_number);
-            debug!("claim interface [{:3}]", interface_number);
-            Ok(())
         }
+        claimed.insert(interface_number, Claim::default());
+        debug!("claim interface [{:3}]", interface_number);
+        Ok(())
+    }
+
+    /// Keep the interface claimed until the returned guard is dropped. An
+    /// interface not claimed yet is claimed for its guards only, the last one
+    /// releases it.
+    pub fn use_interface(self: &Arc<Self>, interface_number: u8) -> Result<InterfaceUse> {
+        let mut g = self.claimed.write().unwrap();
+        if !g.contains_key(&interface_number) {
+            self.claim(&mut g, interface_number)?;
+            g.get_mut(&interface_number).unwrap().release = true;
+        }
+        g.get_mut(&interface_number).unwrap().users += 1;
+        Ok(InterfaceUse { handle: self.clone(), interface_number })
     }
+
+    /// Release the interface, or once its last [`InterfaceUse`] is dropped.
     pub fn release_interface(&self, interface_number: u8) -> Result {
+        let mut g = self.claimed.write().unwrap();
+        if let Some(claim) = g.get_mut(&interface_number) {
+            if claim.users > 0 {
+                claim.release = true;
+                debug!("release interface [{:3}] when idle", interface_number);
+                return Ok(());
+            }
+        }
+        self.release_claimed(interface_number)?;
+        g.remove(&interface_number);
+        Ok(())
+    }
+
+    fn release_claimed(&self, interface_number: u8) -> Result {
         unsafe {
             check_err(libusb_release_interface(self.ptr, interface_number as _))?;
-            debug!("release interface [{:3}]", interface_number);
+        }
+        debug!("release interface [{:3}]", interface_number);
+        Ok(())
+    }
+
+    pub fn claimed_interfaces(&self) -> Vec<u8> {
+        let g = self.claimed.read().unwrap();
+        g.keys().copied().collect()
+    }
+
+    /// Current alt setting of every claimed interface.
+    pub fn claimed_alt_settings(&self) -> HashMap<u8, u8> {
+        let g = self.claimed.read().unwrap();
+        g.iter().map(|(k, v)| (*k, v.alt_setting)).collect()
+    }
+
+    pub fn set_interface_alt_setting(&self, interface_number: u8, alt_setting: u8) -> Result {
+        unsafe {
+            check_err(libusb_set_interface_alt_setting(self.ptr, interface_number as _, alt_setting as _))?;
+            let mut g = self.claimed.write().unwrap();
+            g.entry(interface_number).or_default().alt_setting = alt_setting;
+            debug!("interface [{:3}] alt setting [{}]", interface_number, alt_setting);
             Ok(())
         }
     }
@@ -93,6 +175,23 @@ impl DeviceHandle {
         }
     }
 
+    /// Returns the number of streams allocated, which may be less than requested.
+    pub fn alloc_streams(&self, num_streams: u32, endpoints: &[u8]) -> Result<u32> {
+        let mut endpoints = endpoints.to_vec();
+        unsafe {
+            let r = check_err(libusb_alloc_streams(self.ptr, num_streams, endpoints.as_mut_ptr(), endpoints.len() as _))?;
+            Ok(r as _)
+        }
+    }
+
+    pub fn free_streams(&self, endpoints: &[u8]) -> Result {
+        let mut endpoints = endpoints.to_vec();
+        unsafe {
+            check_err(libusb_free_streams(self.ptr, endpoints.as_mut_ptr(), endpoints.len() as _))?;
+            Ok(())
+        }
+    }
+
     pub fn get_string_descriptor_ascii(&self, index: u8) -> Result<String> {
         unsafe {
             let mut buff = [0u8; 1024];
@@ -130,30 +229,48 @@ impl DeviceHandle {
         Ok(())
     }
 
-    pub fn get_device(&self) -> Device {
+    pub fn attach_kernel_driver(&self, interface_number: u8)->Result{
         unsafe {
-            let mut dev = libusb_get_device(self.ptr);
-            dev = libusb_ref_device(dev);
-            dev
-        }.into()
+            check_err(libusb_attach_kernel_driver(self.ptr,interface_number as _))?;
+        }
+        Ok(())
     }
 
-    pub async fn do_sync_transfer(&self, mut transfer: Transfer) -> Result<Transfer> {
+    pub fn get_device(&self) -> Device {
+        let dev = unsafe {
+            libusb_ref_device(libusb_get_device(self.ptr))
+        };
+        Device::new(dev, self.manager.clone())
+    }
+
+    /// Submit `transfer` and wait for its completion. Dropping the returned
+    /// future cancels the transfer, which is freed once libusb hands it back.
+    pub async fn do_sync_transfer(self: &Arc<Self>, transfer: Transfer) -> Result<Transfer> {
+        let (transfer, r) = self.submit_and_wait(transfer).await;
+        r?;
+        Ok(transfer)
+    }
+
+    /// Like [`do_sync_transfer`](Self::do_sync_transfer), handing the transfer
+    /// back whatever the result.
+    pub async fn submit_and_wait(self: &Arc<Self>, mut transfer: Transfer) -> (Transfer, Result) {
         unsafe {
             transfer.set_handle(self.ptr);
-            let future = SyncTransfer::new();
-            let b = Arc::into_raw(future.inner.clone());
+            let inner = Arc::new(SyncTransferInner::new());
+            let b = Arc::into_raw(inner.clone());
             transfer.set_user_data(b as _);
-            let wp = SyncTransferInnerWrapper(b);
-            transfer.submit()?;
-            future.await;
-            Arc::from_raw(wp.0);
-            transfer.result()?;
-            Ok(transfer)
+            if let Err(e) = transfer.submit() {
+                drop(Arc::from_raw(b));
+                let e = e.in_transfer(transfer.context());
+                return (transfer, Err(e));
+            }
+            let transfer = SyncTransfer { inner, transfer: Some(transfer), handle: self.clone() }.await;
+            let r = transfer.result().map_err(|e| e.in_transfer(transfer.context()));
+            (transfer, r)
         }
     }
 
-    pub async fn control_transfer(&self, direction: TransferDirection, request_type: u8, request: u8, value: u16, index: u16, timeout: Duration) -> Result<Transfer> {
+    pub async fn control_transfer(self: &Arc<Self>, direction: TransferDirection, request_type: u8, request: u8, value: u16, index: u16, timeout: Duration) -> Result<Transfer> {
         unsafe {
             let transfer = Transfer::control_transfer(
                 sync_cb, direction, request_type, request, value, index, timeout);
@@ -161,7 +278,7 @@ impl DeviceHandle {
             self.do_sync_transfer(transfer).await
         }
     }
-    pub async fn bulk_transfer(&self, direction: TransferDirection, endpoint: u8, timeout: Duration, is_interrupt: bool) -> Result<Transfer> {
+    pub async fn bulk_transfer(self: &Arc<Self>, direction: TransferDirection, endpoint: u8, timeout: Duration, is_interrupt: bool) -> Result<Transfer> {
         unsafe {
             let transfer = Transfer::bulk_transfer(endpoint, sync_cb, direction, timeout);
             (*transfer.ptr).transfer_type = if is_interrupt {
@@ -172,7 +289,14 @@ impl DeviceHandle {
             self.do_sync_transfer(transfer).await
         }
     }
-    pub async fn iso_transfer(&self,direction: TransferDirection, endpoint: u8, num_iso_packets: usize, timeout: Duration) -> Result<Transfer> {
+    pub async fn bulk_stream_transfer(self: &Arc<Self>, direction: TransferDirection, endpoint: u8, stream_id: u32, timeout: Duration) -> Result<Transfer> {
+        unsafe {
+            let transfer = Transfer::bulk_transfer(endpoint, sync_cb, direction, timeout);
+            libusb_transfer_set_stream_id(transfer.ptr, stream_id);
+            self.do_sync_transfer(transfer).await
+        }
+    }
+    pub async fn iso_transfer(self: &Arc<Self>,direction: TransferDirection, endpoint: u8, num_iso_packets: usize, timeout: Duration) -> Result<Transfer> {
         unsafe {
             let transfer = Transfer::iso_transfer(endpoint, num_iso_packets as _, sync_cb, direction, timeout);
             self.do_sync_transfer(transfer).await
@@ -182,14 +306,19 @@ impl DeviceHandle {
 
 pub(crate) extern "system" fn sync_cb(transfer: *mut libusb_transfer) {
     unsafe {
-        let sync = (*transfer).user_data as *const SyncTransferInner;
-        (*sync).is_ok.store(true, Ordering::SeqCst);
-        let wake = {
-            let g = (*sync).waker.lock().unwrap();
-            g.as_ref().cloned()
+        let sync = Arc::from_raw((*transfer).user_data as *const SyncTransferInner);
+        let state = {
+            let mut g = sync.state.lock().unwrap();
+            std::mem::replace(&mut *g, SyncState::Done)
         };
-        if let Some(w) = wake {
-            w.wake();
+        match state {
+            SyncState::Pending(Some(w)) => w.wake(),
+            SyncState::Abandoned(t, handle) => {
+                trace!("cancelled transfer returned");
+                drop(t);
+                drop(handle);
+            }
+            _ => {}
         }
     }
 }
@@ -207,42 +336,58 @@ impl Drop for AutoDetachKernelDriverGuard {
 }
 
 
+/// A submitted transfer, owned by the future until the callback fires.
 struct SyncTransfer {
     inner: Arc<SyncTransferInner>,
+    transfer: Option<Transfer>,
+    handle: Arc<DeviceHandle>,
 }
 
+enum SyncState {
+    Pending(Option<Waker>),
+    /// The future was dropped, the transfer is kept here until libusb returns
+    /// it, along with the handle it was submitted on.
+    Abandoned(Transfer, Arc<DeviceHandle>),
+    Done,
+}
 
+/// Shared with `sync_cb`, which holds one reference through `user_data`.
 struct SyncTransferInner {
-    is_ok: AtomicBool,
-    waker: Mutex<Option<Waker>>,
+    state: Mutex<SyncState>,
 }
-struct SyncTransferInnerWrapper(*const SyncTransferInner);
-unsafe impl Send for SyncTransferInnerWrapper{}
-
 
-impl SyncTransfer {
+impl SyncTransferInner {
     fn new() -> Self {
         Self {
-            inner: Arc::new(SyncTransferInner {
-                is_ok: AtomicBool::new(false),
-                waker: Mutex::new(None),
-            })
+            state: Mutex::new(SyncState::Pending(None)),
         }
     }
 }
 
 impl Future for SyncTransfer {
-    type Output = ();
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.inner.is_ok.load(Ordering::SeqCst) {
-            Poll::Ready(())
-        } else {
-            {
-                let mut g = self.inner.waker.lock().unwrap();
-                *g = Some(cx.waker().clone());
+    type Output = Transfer;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        {
+            let mut g = self.inner.state.lock().unwrap();
+            if let SyncState::Pending(waker) = &mut *g {
+                *waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(self.transfer.take().unwrap())
+    }
+}
+
+impl Drop for SyncTransfer {
+    fn drop(&mut self) {
+        if let Some(transfer) = self.transfer.take() {
+            let mut g = self.inner.state.lock().unwrap();
+            if let SyncState::Pending(_) = &*g {
+                let _ = transfer.cancel();
+                trace!("transfer cancelled");
+                *g = SyncState::Abandoned(transfer, self.handle.clone());
             }
-            Poll::Pending
         }
     }
 }
@@ -1,117 +1,470 @@
-use std::ptr::slice_from_raw_parts;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ptr::{null_mut, slice_from_raw_parts};
 use std::sync::{Arc};
 use std::time::Duration;
-use libusb_src::{libusb_clear_halt, libusb_submit_transfer, LIBUSB_SUCCESS, libusb_transfer, LIBUSB_TRANSFER_CANCELLED};
-use crate::define::PipConfig;
-use crate::platform::libusb::device_handle::{DeviceHandle, TransferDirection};
-use crate::platform::libusb::transfer::{ToResult, Transfer};
-use futures::channel::mpsc::{channel, Receiver, Sender};
-use futures::{FutureExt, StreamExt};
-use futures::future::{BoxFuture, LocalBoxFuture};
-use log::{trace, warn};
+use libusb_src::{libusb_clear_halt, libusb_dev_mem_alloc, libusb_dev_mem_free, libusb_device_handle, libusb_cancel_transfer, libusb_submit_transfer, libusb_transfer, LIBUSB_TRANSFER_TYPE_INTERRUPT};
+use crate::buffer::PooledBuffer;
+use crate::define::{IsoPacket, PipConfig};
+use crate::platform::libusb::device_handle::{DeviceHandle, InterfaceUse, TransferDirection};
+use crate::platform::libusb::status_to_result;
+use crate::platform::libusb::transfer::{transfer_context, ToResult, Transfer};
+use std::task::{Context, Poll};
+use log::{debug, trace, warn};
 use super::errors::*;
-use crate::platform::EndpointPipInInner;
+use crate::platform::{EndpointPipInInner, EndpointPipOutInner, TransferDone};
+use crate::platform::pip::{PipQueue, Pushed};
 
-pub(crate) struct EndpointPipInImpl {
-    transfers: Vec<Transfer>,
-    rx: Receiver<Vec<u8>>,
+pub(crate) struct EndpointPipInImpl<R: PipReader = PoolReader> {
+    queue: Arc<PipQueue<ParkedTransfer, R::Item>>,
+    /// Handed to the queue on drop, see [`PipTransfers`].
+    transfers: Option<PipTransfers<R>>,
 }
 
-unsafe impl Send for EndpointPipInImpl {}
+/// The transfers of a pipe. Dropping the pipe does not wait for them, the
+/// callback of the last one to return frees them.
+struct PipTransfers<R: PipReader> {
+    transfers: Vec<Transfer>,
+    queue: Arc<PipQueue<ParkedTransfer, R::Item>>,
+    /// Device memory buffers are freed through the handle, which must stay
+    /// open until then.
+    _handle: Arc<DeviceHandle>,
+    _interface: InterfaceUse,
+}
 
+unsafe impl<R: PipReader> Send for PipTransfers<R> {}
 
-impl Drop for EndpointPipInImpl {
+impl<R: PipReader> Drop for PipTransfers<R> {
     fn drop(&mut self) {
         for t in &self.transfers {
+            unsafe { R::release(t.ptr, &self.queue) };
+        }
+    }
+}
+
+/// A transfer paused by [`OverflowPolicy::Block`](crate::define::OverflowPolicy::Block).
+pub(crate) struct ParkedTransfer(*mut libusb_transfer);
+
+unsafe impl Send for ParkedTransfer {}
+
+/// How an IN pipe gets what it yields out of a completed transfer.
+pub(crate) trait PipReader: 'static {
+    type Item: Send + 'static;
+
+    /// # Safety
+    /// `transfer` is a completed transfer of the pipe.
+    unsafe fn read(transfer: *mut libusb_transfer, queue: &PipQueue<ParkedTransfer, Self::Item>) -> Self::Item;
+
+    /// Free what `read` left in a transfer the pipe is done with.
+    ///
+    /// # Safety
+    /// `transfer` is not in flight and is never submitted again.
+    unsafe fn release(_transfer: *mut libusb_transfer, _queue: &PipQueue<ParkedTransfer, Self::Item>) {}
+}
+
+/// Hands out the filled buffer and gives the transfer a fresh one from the pool.
+pub(crate) struct PoolReader;
+
+impl PipReader for PoolReader {
+    type Item = PooledBuffer;
+
+    unsafe fn read(transfer: *mut libusb_transfer, queue: &PipQueue<ParkedTransfer, PooledBuffer>) -> PooledBuffer {
+        let len = (*transfer).actual_length as usize;
+        let filled = take_buffer(transfer);
+        give_buffer(transfer, queue.pool().take());
+        PooledBuffer::new(filled, len, Some(queue.pool().clone()))
+    }
+
+    unsafe fn release(transfer: *mut libusb_transfer, queue: &PipQueue<ParkedTransfer, PooledBuffer>) {
+        queue.pool().put(take_buffer(transfer));
+    }
+}
+
+/// Let `transfer` fill `data`, whose length must equal its capacity.
+unsafe fn give_buffer(transfer: *mut libusb_transfer, data: Vec<u8>) {
+    let mut data = ManuallyDrop::new(data);
+    (*transfer).buffer = data.as_mut_ptr();
+    (*transfer).length = data.len() as _;
+}
+
+/// Take back the buffer of [`give_buffer`].
+unsafe fn take_buffer(transfer: *mut libusb_transfer) -> Vec<u8> {
+    let len = (*transfer).length as usize;
+    Vec::from_raw_parts((*transfer).buffer, len, len)
+}
+
+/// Copies out of a buffer mapped by usbfs, which the transfer keeps for its
+/// whole life. The kernel fills it in place instead of copying through its
+/// own, so the copy moves to user space rather than going away.
+pub(crate) struct DeviceMemoryReader;
+
+impl PipReader for DeviceMemoryReader {
+    type Item = PooledBuffer;
+
+    unsafe fn read(transfer: *mut libusb_transfer, queue: &PipQueue<ParkedTransfer, PooledBuffer>) -> PooledBuffer {
+        let len = (*transfer).actual_length as usize;
+        let mut buf = queue.pool().get();
+        buf[..len].copy_from_slice(&*slice_from_raw_parts((*transfer).buffer as *const u8, len));
+        buf.truncate(len);
+        buf
+    }
+
+    unsafe fn release(transfer: *mut libusb_transfer, _queue: &PipQueue<ParkedTransfer, PooledBuffer>) {
+        libusb_dev_mem_free((*transfer).dev_handle, (*transfer).buffer, (*transfer).length as _);
+        (*transfer).buffer = null_mut();
+    }
+}
+
+/// Device memory for the transfers of a pipe, the buffers not handed to a
+/// transfer are freed on drop.
+struct DeviceMemory {
+    handle: *mut libusb_device_handle,
+    size: usize,
+    buffers: Vec<*mut u8>,
+}
+
+impl DeviceMemory {
+    /// `None` if the platform or the device can not map `count` buffers.
+    fn alloc(handle: &DeviceHandle, size: usize, count: usize) -> Option<Self> {
+        let mut mem = Self { handle: handle.ptr, size, buffers: Vec::with_capacity(count) };
+        for _ in 0..count {
+            let ptr = unsafe { libusb_dev_mem_alloc(handle.ptr, size) };
+            if ptr.is_null() {
+                return None;
+            }
+            mem.buffers.push(ptr);
+        }
+        Some(mem)
+    }
+}
+
+impl Drop for DeviceMemory {
+    fn drop(&mut self) {
+        for ptr in self.buffers.drain(..) {
+            unsafe { libusb_dev_mem_free(self.handle, ptr, self.size) };
+        }
+    }
+}
+
+pub(crate) struct IsoReader;
+
+impl PipReader for IsoReader {
+    type Item = Vec<IsoPacket>;
+
+    unsafe fn read(transfer: *mut libusb_transfer, queue: &PipQueue<ParkedTransfer, Vec<IsoPacket>>) -> Vec<IsoPacket> {
+        let num = (*transfer).num_iso_packets as usize;
+        let descs = &*slice_from_raw_parts((*transfer).iso_packet_desc.as_ptr(), num);
+        let first = queue.next_position(num as _);
+        let mut begin = 0;
+        let mut packets = Vec::with_capacity(num);
+        for (position, desc) in (first..).zip(descs) {
+            let data = slice_from_raw_parts(
+                (*transfer).buffer.add(begin) as *const u8,
+                desc.actual_length as _);
+            packets.push(IsoPacket {
+                data: (*data).to_vec(),
+                status: status_to_result(desc.status),
+                position,
+            });
+            begin += desc.length as usize;
+        }
+        packets
+    }
+}
+
+impl<R: PipReader> Drop for EndpointPipInImpl<R> {
+    fn drop(&mut self) {
+        for t in self.queue.close() {
+            unsafe { drop(Arc::from_raw((*t.0).user_data as *const PipQueue<ParkedTransfer, R::Item>)) };
+        }
+        let transfers = match self.transfers.take() {
+            Some(transfers) => transfers,
+            None => return,
+        };
+        // A callback resubmitting after this cancels its transfer itself, as
+        // it sees the pipe closed.
+        for t in &transfers.transfers {
             let _ = t.cancel();
         }
-        loop {
-            std::thread::sleep(Duration::from_micros(50));
-            let mut all_ok = true;
-            for t in &self.transfers{
-                let r = t.result();
-                if r.is_ok(){
-                    all_ok = false;
-                    break;
-                }
+        self.queue.when_idle(Box::new(move || drop(transfers)));
+    }
+}
+
+impl EndpointPipInImpl {
+    /// Bulk or interrupt pipe, in device memory if the config asks for it and
+    /// the platform provides it.
+    pub fn open(handle: &Arc<DeviceHandle>, interface: InterfaceUse, endpoint: u8, config: PipConfig, is_interrupt: bool) -> Result<Box<dyn EndpointPipInInner>> {
+        let timeout = config.timeout;
+        let size = config.package_size;
+        let new_transfer = move || unsafe {
+            let transfer = Transfer::bulk_transfer(endpoint, pip_cb::<PoolReader>, TransferDirection::In { len: 0 }, timeout);
+            if is_interrupt {
+                (*transfer.ptr).transfer_type = LIBUSB_TRANSFER_TYPE_INTERRUPT;
             }
-            if all_ok{
-                break;
+            transfer
+        };
+
+        if config.device_memory {
+            match DeviceMemory::alloc(handle, size, config.request_num) {
+                Some(mut mem) => {
+                    let pip = EndpointPipInImpl::<DeviceMemoryReader>::start(handle, interface, config, |_| unsafe {
+                        let mut transfer = new_transfer();
+                        transfer.set_callback(pip_cb::<DeviceMemoryReader>);
+                        (*transfer.ptr).buffer = mem.buffers.pop().unwrap();
+                        (*transfer.ptr).length = size as _;
+                        transfer
+                    })?;
+                    return Ok(Box::new(pip));
+                }
+                None => debug!("ep[{}] device memory not available, use heap buffers", endpoint),
             }
         }
+
+        let pip = Self::start(handle, interface, config, |queue| unsafe {
+            let transfer = new_transfer();
+            give_buffer(transfer.ptr, queue.pool().take());
+            transfer
+        })?;
+        Ok(Box::new(pip))
     }
 }
 
-impl EndpointPipInImpl {
-    pub fn new(handle: &Arc<DeviceHandle>, endpoint: u8, config: PipConfig) -> Result<Self> {
+impl EndpointPipInImpl<IsoReader> {
+    pub fn new_iso(handle: &Arc<DeviceHandle>, interface: InterfaceUse, endpoint: u8, num_iso_packages: usize, config: PipConfig) -> Result<Self> {
+        let timeout = config.timeout;
+        let len = num_iso_packages * config.package_size;
+        Self::start(handle, interface, config, |_| unsafe {
+            Transfer::iso_transfer(endpoint, num_iso_packages as _, pip_cb::<IsoReader>, TransferDirection::In { len }, timeout)
+        })
+    }
+}
+
+impl<R: PipReader> EndpointPipInImpl<R> {
+    /// Submit `request_num` transfers made by `new_transfer`, whose callback must be `pip_cb::<R>`.
+    fn start(
+        handle: &Arc<DeviceHandle>,
+        interface: InterfaceUse,
+        config: PipConfig,
+        mut new_transfer: impl FnMut(&PipQueue<ParkedTransfer, R::Item>) -> Transfer,
+    ) -> Result<Self> {
         let handle_ptr = handle.ptr;
         let mut transfers = Vec::with_capacity(config.request_num);
-        let (tx, rx) = channel::<Vec<u8>>(config.cache_size);
+        let queue = Arc::new(PipQueue::new(&config));
 
         unsafe {
             for _ in 0..transfers.capacity() {
-                let tb = Box::new(tx.clone());
-                let tx_ptr = Box::into_raw(tb);
+                let queue_ptr = Arc::into_raw(queue.clone());
 
-                let mut transfer = Transfer::bulk_transfer(endpoint, pip_cb, TransferDirection::In { len: config.package_size }, config.timeout);
+                let mut transfer = new_transfer(&queue);
                 transfer.set_handle(handle_ptr);
-                transfer.set_user_data(tx_ptr as _);
-                transfer.submit()?;
+                transfer.set_user_data(queue_ptr as _);
+                if let Err(e) = transfer.submit() {
+                    drop(Arc::from_raw(queue_ptr));
+                    R::release(transfer.ptr, &queue);
+                    for _ in transfers.len()..transfers.capacity() {
+                        queue.stop();
+                    }
+                    drop(Self::new(transfers, queue, handle, interface));
+                    return Err(e);
+                }
                 transfers.push(transfer);
             }
         }
-        Ok(Self {
-            transfers,
-            rx,
-        })
+        Ok(Self::new(transfers, queue, handle, interface))
+    }
+
+    fn new(
+        transfers: Vec<Transfer>,
+        queue: Arc<PipQueue<ParkedTransfer, R::Item>>,
+        handle: &Arc<DeviceHandle>,
+        interface: InterfaceUse,
+    ) -> Self {
+        Self {
+            queue: queue.clone(),
+            transfers: Some(PipTransfers { transfers, queue, _handle: handle.clone(), _interface: interface }),
+        }
     }
 }
 
 
-impl EndpointPipInInner for EndpointPipInImpl {
-    fn next(&mut self) -> BoxFuture<Option<Vec<u8>>> {
-        self.rx.next().boxed()
+impl<R: PipReader> EndpointPipInInner<R::Item> for EndpointPipInImpl<R> {
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<R::Item>>> {
+        let (r, resume) = self.queue.poll_next(cx);
+        for t in resume {
+            unsafe {
+                if let Err(e) = check_err(libusb_submit_transfer(t.0)) {
+                    drop(Arc::from_raw((*t.0).user_data as *const PipQueue<ParkedTransfer, R::Item>));
+                    self.queue.stop_with(e.in_transfer(transfer_context(t.0)));
+                }
+            }
+        }
+        r
+    }
+
+    fn dropped(&self) -> u64 {
+        self.queue.dropped()
+    }
+
+    fn error(&self) -> Option<Error> {
+        self.queue.error()
     }
 }
 
 
-extern "system" fn pip_cb(transfer: *mut libusb_transfer) {
+extern "system" fn pip_cb<R: PipReader>(transfer: *mut libusb_transfer) {
     unsafe {
-        let tx_ptr = (*transfer).user_data as *mut Sender<Vec<u8>>;
+        let queue_ptr = (*transfer).user_data as *const PipQueue<ParkedTransfer, R::Item>;
         let result = (*transfer).to_result();
-        let mut tx = Box::from_raw(tx_ptr);
-        if tx.is_closed() {
-            trace!("tx closed");
-            (*transfer).status = LIBUSB_TRANSFER_CANCELLED;
+        let queue = Arc::from_raw(queue_ptr);
+        if queue.is_closed() {
+            trace!("pip closed");
+            queue.stop();
             return;
         }
 
         match result {
             Ok(_) => {
-                let data = (*slice_from_raw_parts(
-                    (*transfer).buffer as *const u8,
-                    (*transfer).actual_length as _)).to_vec();
+                let data = R::read(transfer, &queue);
 
-                if tx.try_send(data).is_err() {
-                    warn!("ep[{}] overflow", (*transfer).endpoint);
+                match queue.push((*transfer).endpoint, data, ParkedTransfer(transfer)) {
+                    Pushed::Resubmit(_) => {}
+                    Pushed::Parked => {
+                        // The reference in user_data stays with the parked transfer.
+                        let _ = Arc::into_raw(queue);
+                        return;
+                    }
+                    Pushed::Stopped => return,
                 }
             }
             Err(e) => {
                 match e {
-                    Error::Pipe => {
-                        warn!("pip error");
-                        if libusb_clear_halt((*transfer).dev_handle, (*transfer).endpoint) != LIBUSB_SUCCESS {
+                    Error::Stall => {
+                        warn!("ep[{}] stall", (*transfer).endpoint);
+                        let clear_halt = || check_err(libusb_clear_halt((*transfer).dev_handle, (*transfer).endpoint)).map(|_| ());
+                        if !queue.stalled(e.in_transfer(transfer_context(transfer)), clear_halt) {
                             return;
                         }
                     }
                     _ => {
                         trace!("transfer err: {}" ,e);
+                        queue.stop_with(e.in_transfer(transfer_context(transfer)));
                         return;
                     }
                 }
             }
         }
-        (*transfer).user_data = Box::into_raw(tx) as _;
-        libusb_submit_transfer(transfer);
+        let queue_ptr = Arc::into_raw(queue);
+        (*transfer).user_data = queue_ptr as _;
+        if let Err(e) = check_err(libusb_submit_transfer(transfer)) {
+            let queue = Arc::from_raw(queue_ptr);
+            queue.stop_with(e.in_transfer(transfer_context(transfer)));
+        } else if (*queue_ptr).is_closed() {
+            // The pipe was dropped meanwhile and may have cancelled the
+            // transfer before it was resubmitted. It can not complete before
+            // this callback returns, which keeps both alive.
+            let _ = libusb_cancel_transfer(transfer);
+        }
+    }
+}
+
+pub(crate) struct EndpointPipOutImpl<I: PipOutItem = Vec<u8>> {
+    handle: Arc<DeviceHandle>,
+    interface: Arc<InterfaceUse>,
+    endpoint: u8,
+    timeout: Duration,
+    _item: PhantomData<fn(I)>,
+}
+
+/// What an OUT pipe sends with one transfer.
+pub(crate) trait PipOutItem: Send + Sized + 'static {
+    /// # Safety
+    /// The callback of the transfer must be `pip_out_cb::<Self>`.
+    unsafe fn transfer(self, endpoint: u8, timeout: Duration) -> Result<Transfer>;
+    /// Bytes sent by a completed transfer.
+    fn sent(transfer: &Transfer) -> usize;
+}
+
+impl PipOutItem for Vec<u8> {
+    unsafe fn transfer(self, endpoint: u8, timeout: Duration) -> Result<Transfer> {
+        Ok(Transfer::bulk_transfer(endpoint, pip_out_cb::<Self>, TransferDirection::Out { data: self }, timeout))
+    }
+
+    fn sent(transfer: &Transfer) -> usize {
+        transfer.actual_length()
+    }
+}
+
+impl PipOutItem for Vec<Vec<u8>> {
+    unsafe fn transfer(self, endpoint: u8, timeout: Duration) -> Result<Transfer> {
+        if self.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Transfer::iso_transfer_out(endpoint, self, pip_out_cb::<Self>, timeout))
+    }
+
+    fn sent(transfer: &Transfer) -> usize {
+        let mut sent = 0;
+        for (i, packet) in transfer.iso_packet_results().into_iter().enumerate() {
+            match packet.status {
+                Ok(_) => sent += packet.actual_length,
+                Err(e) => warn!("ep[{}] packet {} fail: {}", unsafe { (*transfer.ptr).endpoint }, i, e),
+            }
+        }
+        sent
+    }
+}
+
+impl<I: PipOutItem> EndpointPipOutImpl<I> {
+    pub fn new(handle: &Arc<DeviceHandle>, interface: InterfaceUse, endpoint: u8, config: PipConfig) -> Self {
+        Self {
+            handle: handle.clone(),
+            interface: Arc::new(interface),
+            endpoint,
+            timeout: config.timeout,
+            _item: PhantomData,
+        }
+    }
+}
+
+/// Owned by `pip_out_cb` while the transfer is in flight, the handle stays
+/// open and the interface claimed until every queued buffer is returned.
+struct PipOutTransfer {
+    transfer: Transfer,
+    _handle: Arc<DeviceHandle>,
+    _interface: Arc<InterfaceUse>,
+    done: TransferDone,
+}
+
+impl<I: PipOutItem> EndpointPipOutInner<I> for EndpointPipOutImpl<I> {
+    fn submit(&mut self, data: I, done: TransferDone) -> Result {
+        unsafe {
+            let mut transfer = data.transfer(self.endpoint, self.timeout)?;
+            transfer.set_handle(self.handle.ptr);
+            let ptr = transfer.ptr;
+            let ctx = Box::into_raw(Box::new(PipOutTransfer {
+                transfer,
+                _handle: self.handle.clone(),
+                _interface: self.interface.clone(),
+                done,
+            }));
+            (*ptr).user_data = ctx as _;
+            if let Err(e) = check_err(libusb_submit_transfer(ptr)) {
+                drop(Box::from_raw(ctx));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+extern "system" fn pip_out_cb<I: PipOutItem>(transfer: *mut libusb_transfer) {
+    unsafe {
+        let ctx = Box::from_raw((*transfer).user_data as *mut PipOutTransfer);
+        let PipOutTransfer { transfer, _handle, _interface, done } = *ctx;
+        let result = transfer.result()
+            .map(|_| I::sent(&transfer))
+            .map_err(|e| e.in_transfer(transfer.context()));
+        done(result);
+        drop(transfer);
     }
 }
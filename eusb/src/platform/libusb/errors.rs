@@ -1,4 +1,4 @@
-use std::ffi::c_int;
+use std::ffi::{c_int, CStr};
 pub(crate) use crate::error::*;
 use libusb_src::*;
 
@@ -6,7 +6,7 @@ use libusb_src::*;
 pub(crate) fn check_err(r: c_int) -> Result<i32> {
     if r >= 0 { Ok(r as _) } else {
         let e = match r {
-            LIBUSB_ERROR_IO            =>Error::Io("Usb".to_string()),
+            LIBUSB_ERROR_IO            =>Error::Io(strerror(r)),
             LIBUSB_ERROR_INVALID_PARAM =>Error::InvalidParam,
             LIBUSB_ERROR_ACCESS        =>Error::Access,
             LIBUSB_ERROR_NO_DEVICE     =>Error::NoDevice,
@@ -14,15 +14,23 @@ pub(crate) fn check_err(r: c_int) -> Result<i32> {
             LIBUSB_ERROR_BUSY          =>Error::Busy,
             LIBUSB_ERROR_TIMEOUT       =>Error::Timeout,
             LIBUSB_ERROR_OVERFLOW      =>Error::Overflow,
-            LIBUSB_ERROR_PIPE          =>Error::Pipe,
+            LIBUSB_ERROR_PIPE          =>Error::Stall,
             LIBUSB_ERROR_INTERRUPTED   =>Error::Interrupted,
             LIBUSB_ERROR_NO_MEM        =>Error::NoMem,
             LIBUSB_ERROR_NOT_SUPPORTED =>Error::NotSupported,
-            _ => Error::Other("Libusb Unknown".to_string())
+            _ => Error::Other(error_name(r))
         };
 
         Err(e)
     }
 }
 
+/// Description of a libusb error code, in the language set for libusb.
+pub(crate) fn strerror(r: c_int) -> String {
+    unsafe { CStr::from_ptr(libusb_strerror(r)).to_string_lossy().into_owned() }
+}
 
+/// Name of a libusb error code, e.g. `LIBUSB_ERROR_IO`.
+pub(crate) fn error_name(r: c_int) -> String {
+    unsafe { CStr::from_ptr(libusb_error_name(r)).to_string_lossy().into_owned() }
+}
@@ -0,0 +1,66 @@
+use std::ffi::{c_int, c_void};
+use std::sync::Arc;
+use futures::channel::mpsc::UnboundedSender;
+use log::debug;
+use libusb_src::*;
+use crate::define::DeviceId;
+use crate::hotplug::HotplugEvent;
+use crate::platform::{DeviceCtxImpl, ManagerCtxImpl};
+use crate::platform::libusb::device::Device;
+
+/// `user_data` of [`hotplug_cb`], which owns a reference to it until libusb
+/// no longer runs the callback.
+pub(crate) struct HotplugSender {
+    pub(crate) tx: UnboundedSender<HotplugEvent>,
+    /// Context of the arrived devices.
+    pub(crate) manager: Arc<ManagerCtxImpl>,
+}
+
+/// Keeps a libusb hotplug callback registered, deregisters it on drop.
+pub(crate) struct HotplugRegistration {
+    pub(crate) manager: Arc<ManagerCtxImpl>,
+    pub(crate) handle: libusb_hotplug_callback_handle,
+    pub(crate) sender: *const HotplugSender,
+}
+
+unsafe impl Send for HotplugRegistration {}
+
+impl Drop for HotplugRegistration {
+    fn drop(&mut self) {
+        unsafe {
+            self.manager.ctx.hotplug_deregister(self.handle);
+            // A callback already dispatched may still be running on the
+            // thread handling events.
+            self.manager.ctx.wait_event_handler();
+            drop(Arc::from_raw(self.sender));
+        }
+        debug!("hotplug deregister");
+        self.manager.hotplug_deregistered();
+    }
+}
+
+pub(crate) extern "system" fn hotplug_cb(
+    _ctx: *mut libusb_context,
+    device: *mut libusb_device,
+    event: libusb_hotplug_event,
+    user_data: *mut c_void,
+) -> c_int {
+    unsafe {
+        // Keep the sender alive even if the registration is dropped meanwhile.
+        Arc::increment_strong_count(user_data as *const HotplugSender);
+        let sender = Arc::from_raw(user_data as *const HotplugSender);
+        let event = match event {
+            LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED => {
+                let dev = Device::new(libusb_ref_device(device), sender.manager.clone());
+                HotplugEvent::Arrived(DeviceCtxImpl::from(dev).into())
+            }
+            LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT => HotplugEvent::Left(DeviceId {
+                bus_number: libusb_get_bus_number(device),
+                device_address: libusb_get_device_address(device),
+            }),
+            _ => return 0,
+        };
+        let _ = sender.tx.unbounded_send(event);
+    }
+    0
+}
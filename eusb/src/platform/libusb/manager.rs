@@ -1,70 +1,129 @@
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::JoinHandle;
-use log::{debug};
-use thread_priority::{ThreadBuilderExt, ThreadPriority};
+use std::time::Duration;
+use log::{debug, warn};
+use crate::context::UsbContextBuilder;
 use crate::device::UsbDevice;
+use futures::channel::mpsc::unbounded;
+use crate::hotplug::{HotplugFilter, HotplugStream};
 use crate::platform::libusb::context::Context;
+use crate::platform::libusb::device::Device;
+use crate::platform::libusb::device_handle::DeviceHandle;
+use crate::platform::libusb::hotplug::{HotplugRegistration, HotplugSender};
 use crate::platform::*;
 
 pub(crate) struct ManagerCtxImpl {
-    ctx: Arc<Context>,
+    pub(crate) ctx: Arc<Context>,
     event: Arc<Mutex<EventControllerCtx>>,
     cond: Arc<Condvar>,
+    /// `None` without an event thread.
     join: Mutex<Option<JoinHandle<()>>>,
 }
 
 
 impl ManagerCtx for ManagerCtxImpl {
-    fn new() -> Self {
-        let ctx = Arc::new(Context::new());
+    fn new(builder: &UsbContextBuilder) -> Result<Self> {
+        let ctx = Arc::new(Context::new()?);
         let event = Arc::new(Mutex::new(EventControllerCtx {
             device_count: 0,
+            hotplug_count: 0,
             is_exit: false,
         }));
         let cond = Arc::new(Condvar::new());
-        let join = work_event(ctx.clone(), event.clone(), cond.clone());
+        let join = if builder.event_thread {
+            Some(work_event(builder, ctx.clone(), event.clone(), cond.clone())?)
+        } else {
+            None
+        };
 
-        Self {
+        Ok(Self {
             ctx,
             event,
             cond,
-            join: Mutex::new(Some(join)),
-        }
+            join: Mutex::new(join),
+        })
     }
 
-    fn device_list(&self) -> Result<Vec<UsbDevice>> {
-        let ctx = self.ctx.clone();
-        let mut d = ctx.device_list()?;
+    fn device_list(self: Arc<Self>) -> Result<Vec<UsbDevice>> {
+        let mut d = self.ctx.device_list()?;
         let mut out = Vec::with_capacity(d.len());
         while let Some(one) = d.pop() {
-            let device: DeviceCtxImpl = one.into();
+            let device: DeviceCtxImpl = Device::new(one, self.clone()).into();
             out.push(device.into());
         }
         Ok(out)
     }
 
-    fn open_device_with_vid_pid(&self, vid: u16, pid: u16) -> Result<UsbDevice> {
+    fn open_device_with_vid_pid(self: Arc<Self>, vid: u16, pid: u16) -> Result<UsbDevice> {
         let handle = self.ctx.open_device_with_vid_pid(vid, pid)?;
-        let dev = DeviceCtxImpl::from(handle);
+        let dev = DeviceCtxImpl::from(DeviceHandle::new(handle, self.clone()));
         Ok(dev.into())
     }
 
     #[cfg(unix)]
-    fn open_device_with_fd(&self, fd: RawFd) -> Result<UsbDevice> {
+    fn open_device_with_fd(self: Arc<Self>, fd: RawFd) -> Result<UsbDevice> {
         let handle = self.ctx.open_device_with_fd(fd)?;
-        let dev = DeviceCtxImpl::from(handle);
+        let dev = DeviceCtxImpl::from(DeviceHandle::new(handle, self.clone()));
         Ok(dev.into())
     }
 
-    fn close(&self) {
+    fn hotplug(self: Arc<Self>, filter: HotplugFilter) -> Result<HotplugStream> {
+        let (tx, rx) = unbounded();
+        let sender = Arc::into_raw(Arc::new(HotplugSender { tx, manager: self.clone() }));
+        self.hotplug_registered();
+        let handle = match self.ctx.hotplug_register(&filter, sender) {
+            Ok(handle) => handle,
+            Err(e) => {
+                unsafe { drop(Arc::from_raw(sender)); }
+                self.hotplug_deregistered();
+                return Err(e);
+            }
+        };
+        let registration = HotplugRegistration {
+            manager: self.clone(),
+            handle,
+            sender,
+        };
+        Ok(HotplugStream::new(rx, registration))
+    }
+
+    fn handle_events(&self, timeout: Duration) -> Result {
+        self.ctx.handle_events(timeout)
+    }
+
+    #[cfg(unix)]
+    fn pollfd_watch(&self) -> Arc<PollfdWatch> {
+        self.ctx.pollfd_watch()
+    }
+
+    #[cfg(unix)]
+    fn pollfds(&self) -> Result<Vec<PollFd>> {
+        self.ctx.pollfds()
+    }
+
+    #[cfg(unix)]
+    fn next_timeout(&self) -> Result<Option<Duration>> {
+        self.ctx.next_timeout()
+    }
+}
+
+/// Stops the event thread. The libusb context is freed once the thread let
+/// go of it.
+impl Drop for ManagerCtxImpl {
+    fn drop(&mut self) {
         {
             let mut ctx = self.event.lock().unwrap();
             ctx.is_exit = true;
             self.cond.notify_all();
         }
+        // Do not wait for the event timeout if the thread is in libusb.
+        self.ctx.interrupt_event_handler();
         let mut g = self.join.lock().unwrap();
         if let Some(j) = g.take() {
-            j.join().unwrap();
+            // The last device may be dropped by a callback on the event thread.
+            if j.thread().id() != std::thread::current().id() {
+                j.join().unwrap();
+            }
         }
     }
 }
@@ -85,40 +144,85 @@ impl ManagerCtxImpl {
         debug!("device cnt: {}", ctx.device_count);
         self.cond.notify_all();
     }
+
+    pub(crate)  fn hotplug_registered(&self){
+        let mut ctx = self.event.lock().unwrap();
+        ctx.hotplug_count+=1;
+        debug!("hotplug cnt: {}", ctx.hotplug_count);
+        self.cond.notify_all();
+    }
+
+    pub(crate)  fn hotplug_deregistered(&self){
+        let mut ctx = self.event.lock().unwrap();
+        ctx.hotplug_count-=1;
+        debug!("hotplug cnt: {}", ctx.hotplug_count);
+        self.cond.notify_all();
+    }
 }
 
 fn work_event(
+    builder: &UsbContextBuilder,
     ctx: Arc<Context>,
     event: Arc<Mutex<EventControllerCtx>>,
     cond: Arc<Condvar>,
-) -> JoinHandle<()> {
-    std::thread::Builder::new()
-        .name("USB main event".into()).spawn_with_priority(ThreadPriority::Max, move |result| {
-        if result.is_err() {
-            println!("Set priority result fail: {:?}", result);
+) -> Result<JoinHandle<()>> {
+    let priority = builder.priority;
+    let affinity = builder.affinity.clone();
+    let timeout = builder.event_timeout;
+    let join = std::thread::Builder::new()
+        .name(builder.thread_name.clone()).spawn(move || {
+        if let Some(priority) = priority {
+            if let Err(e) = thread_priority::set_current_thread_priority(priority.to_os()) {
+                warn!("set event thread priority {:?} fail: {:?}", priority, e);
+            }
+        }
+        if !affinity.is_empty() {
+            if let Err(e) = set_current_thread_affinity(&affinity) {
+                warn!("set event thread affinity {:?} fail: {}", affinity, e);
+            }
         }
 
-        let p = ctx;
-        let mut ctx = {
-            *event.lock().unwrap()
-        };
-
-        while !ctx.is_exit {
-            if ctx.device_count > 0 {
-                let _ = p.handle_events();
-                ctx = *event.lock().unwrap()
-            } else {
+        loop {
+            {
                 let mut g = event.lock().unwrap();
-                g = cond.wait(g).unwrap();
-                ctx = *g;
+                while !g.is_exit && g.device_count == 0 && g.hotplug_count == 0 {
+                    g = cond.wait(g).unwrap();
+                }
+                if g.is_exit {
+                    break;
+                }
+            }
+            let _ = ctx.handle_events(timeout);
+        }
+    })?;
+    Ok(join)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_current_thread_affinity(cpus: &[usize]) -> std::io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(std::io::ErrorKind::InvalidInput.into());
             }
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
         }
-        p.exit();
-    }).unwrap()
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_current_thread_affinity(_cpus: &[usize]) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
 }
 
 #[derive(Clone, Debug, Copy)]
 struct EventControllerCtx {
     device_count: usize,
+    hotplug_count: usize,
     is_exit: bool,
 }
@@ -12,6 +12,7 @@ mod device_handle;
 pub(crate)mod manager;
 mod transfer;
 pub(crate) mod endpoint;
+mod hotplug;
 
 
 pub(crate) unsafe  fn  config_descriptor_convert(raw: *const libusb_config_descriptor, handle: Option<&DeviceHandle>, speed: Speed)->ConfigDescriptor{
@@ -195,13 +196,15 @@ impl ToLib for UsbControlTransferType {
 }
 
 impl ToLib for UsbControlRecipient {
+    #[allow(deprecated)]
     fn to_lib(self) -> u8 {
         let t: u8 = match self {
             UsbControlRecipient::Device => LIBUSB_RECIPIENT_DEVICE,
-            UsbControlRecipient::Endpoint => LIBUSB_RECIPIENT_ENDPOINT,
+            UsbControlRecipient::Interface(_)
+            | UsbControlRecipient::SpecifiedInterface
+            | UsbControlRecipient::DefaultInterface => LIBUSB_RECIPIENT_INTERFACE,
+            UsbControlRecipient::Endpoint(_) => LIBUSB_RECIPIENT_ENDPOINT,
             UsbControlRecipient::Other => LIBUSB_RECIPIENT_OTHER,
-            UsbControlRecipient::DefaultInterface
-            | UsbControlRecipient::SpecifiedInterface => LIBUSB_RECIPIENT_INTERFACE,
         };
         t
     }
@@ -213,8 +216,9 @@ pub(crate) fn status_to_result(status: c_int)-> crate::error::Result {
         LIBUSB_TRANSFER_OVERFLOW => Err(Error::Overflow),
         LIBUSB_TRANSFER_TIMED_OUT => Err(Error::Timeout),
         LIBUSB_TRANSFER_CANCELLED => Err(Error::Cancelled),
-        LIBUSB_TRANSFER_STALL => Err(Error::NotSupported),
+        LIBUSB_TRANSFER_STALL => Err(Error::Stall),
         LIBUSB_TRANSFER_NO_DEVICE => Err(Error::NoDevice),
-        _ => Err(Error::Other("Unknown".to_string())),
+        LIBUSB_TRANSFER_ERROR => Err(Error::Io(errors::error_name(status))),
+        _ => Err(Error::Other(format!("unknown transfer status {}", status))),
     }
 }
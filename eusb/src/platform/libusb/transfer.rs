@@ -1,11 +1,13 @@
 use std::ffi::c_void;
-use std::ptr::{null_mut, slice_from_raw_parts_mut};
+use std::ptr::{null_mut, slice_from_raw_parts, slice_from_raw_parts_mut};
 use std::time::Duration;
 
 use log::trace;
 
 use libusb_src::*;
 
+use crate::define::{DeviceId, EndpointTransferType, IsoPacketResult};
+use crate::error::TransferContext;
 use crate::platform::libusb::status_to_result;
 
 use super::errors::*;
@@ -38,9 +40,8 @@ impl Transfer {
     pub fn new_with_direction(iso_packets: i32, direction: TransferDirection) -> Self {
         match direction {
             TransferDirection::Out { data } => {
-                let mut t = Self::new(iso_packets, data.len());
-                t.data.copy_from_slice(&data);
-                t
+                let ptr = unsafe { libusb_alloc_transfer(iso_packets) };
+                Self { ptr, data }
             }
             TransferDirection::In { len } => {
                 Self::new(iso_packets, len)
@@ -123,6 +124,36 @@ impl Transfer {
         t
     }
 
+    /// Isochronous OUT transfer sending one packet per entry of `packs`, in order.
+    pub unsafe fn iso_transfer_out(
+        endpoint: u8,
+        packs: Vec<Vec<u8>>,
+        callback: libusb_transfer_cb_fn,
+        timeout: Duration,
+    ) -> Self {
+        let num_iso_packets = packs.len();
+        let lens: Vec<_> = packs.iter().map(|o| o.len()).collect();
+        let t = Self::iso_transfer(endpoint, num_iso_packets as _, callback, TransferDirection::Out { data: packs.concat() }, timeout);
+        let descs = &mut *slice_from_raw_parts_mut((*t.ptr).iso_packet_desc.as_mut_ptr(), num_iso_packets);
+        for (desc, len) in descs.iter_mut().zip(lens) {
+            desc.length = len as _;
+        }
+        t
+    }
+
+    /// Length and status of each packet of a completed isochronous transfer.
+    pub fn iso_packet_results(&self) -> Vec<IsoPacketResult> {
+        unsafe {
+            let descs = &*slice_from_raw_parts((*self.ptr).iso_packet_desc.as_ptr(), (*self.ptr).num_iso_packets as _);
+            descs.iter()
+                .map(|o| IsoPacketResult {
+                    actual_length: o.actual_length as _,
+                    status: status_to_result(o.status),
+                })
+                .collect()
+        }
+    }
+
     pub fn control_transfer_get_data(&self) -> &[u8] {
         unsafe {
             let p = libusb_control_transfer_get_data(self.ptr);
@@ -130,6 +161,20 @@ impl Transfer {
             &*slice_from_raw_parts_mut(p, l)
         }
     }
+    /// Transfer the whole of `data` instead of the current buffer.
+    pub unsafe fn set_buffer(&mut self, mut data: Vec<u8>) {
+        (*self.ptr).buffer = data.as_mut_ptr();
+        (*self.ptr).length = data.len() as _;
+        self.data = data;
+    }
+
+    /// Hand out the buffer of a completed transfer, cut to the received length.
+    pub fn take_data(&mut self) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.data);
+        data.truncate(self.actual_length());
+        data
+    }
+
     pub unsafe fn set_handle(&mut self, handle: *mut libusb_device_handle) {
         (*self.ptr).dev_handle = handle;
     }
@@ -166,6 +211,43 @@ impl Transfer {
             (*self.ptr).to_result()
         }
     }
+
+    pub fn context(&self) -> TransferContext {
+        unsafe { transfer_context(self.ptr) }
+    }
+}
+
+/// Context for the errors of a transfer whose handle is set.
+pub(crate) unsafe fn transfer_context(transfer: *const libusb_transfer) -> TransferContext {
+    let dev = libusb_get_device((*transfer).dev_handle);
+    let transfer_type = match (*transfer).transfer_type {
+        LIBUSB_TRANSFER_TYPE_CONTROL => EndpointTransferType::Control,
+        LIBUSB_TRANSFER_TYPE_ISOCHRONOUS => EndpointTransferType::Isochronous,
+        LIBUSB_TRANSFER_TYPE_INTERRUPT => EndpointTransferType::Interrupt,
+        _ => EndpointTransferType::Bulk,
+    };
+    let endpoint = match transfer_type {
+        // The direction of a control transfer is in bmRequestType.
+        EndpointTransferType::Control => *(*transfer).buffer & LIBUSB_ENDPOINT_DIR_MASK,
+        _ => (*transfer).endpoint,
+    };
+    let actual_length = (*transfer).actual_length as usize;
+    let data = match transfer_type {
+        _ if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN => vec![],
+        EndpointTransferType::Control => (*slice_from_raw_parts(libusb_control_transfer_get_data(transfer as _), actual_length)).to_vec(),
+        EndpointTransferType::Isochronous => vec![],
+        _ => (*slice_from_raw_parts((*transfer).buffer, actual_length)).to_vec(),
+    };
+    TransferContext {
+        device: DeviceId {
+            bus_number: libusb_get_bus_number(dev),
+            device_address: libusb_get_device_address(dev),
+        },
+        transfer_type,
+        endpoint,
+        actual_length,
+        data,
+    }
 }
 
 pub(crate) trait ToResult {
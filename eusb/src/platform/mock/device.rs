@@ -0,0 +1,328 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::channel::oneshot;
+use crate::define::*;
+use crate::error::*;
+use crate::platform::{AsyncBufResult, AsyncResult, DeviceCtx, EndpointPipInInner, EndpointPipOutInner};
+use crate::platform::mock::{class_from_code, Completion, MockDeviceState, MockTransfer, next_owner, TransferKind};
+use crate::platform::mock::endpoint::{EndpointPipInImpl, EndpointPipOutImpl};
+use crate::platform::mock::manager::ManagerCtxImpl;
+
+pub(crate) struct DeviceCtxImpl {
+    pub(crate) state: Arc<MockDeviceState>,
+    /// Keeps the context alive, like a libusb device does.
+    _manager: Arc<ManagerCtxImpl>,
+}
+
+impl DeviceCtxImpl {
+    pub(crate) fn new(state: Arc<MockDeviceState>, manager: Arc<ManagerCtxImpl>) -> Self {
+        Self { state, _manager: manager }
+    }
+}
+
+/// Cancels the transfer if the future is dropped before it completes.
+struct CancelOnDrop {
+    state: Arc<MockDeviceState>,
+    owner: u64,
+    done: bool,
+}
+
+impl CancelOnDrop {
+    fn disarm(&mut self) {
+        self.done = true;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if !self.done {
+            self.state.cancel(self.owner);
+        }
+    }
+}
+
+fn do_transfer(state: &Arc<MockDeviceState>, transfer: MockTransfer) -> impl Future<Output=Result<Completion>> {
+    let (tx, rx) = oneshot::channel();
+    let mut guard = CancelOnDrop {
+        state: state.clone(),
+        owner: transfer.owner,
+        done: false,
+    };
+    state.submit(transfer, Box::new(move |completion| {
+        let _ = tx.send(completion);
+    }));
+    async move {
+        let r = rx.await;
+        guard.disarm();
+        let mut completion = r.map_err(|_| Error::Cancelled)?;
+        std::mem::replace(&mut completion.result, Ok(()))?;
+        Ok(completion)
+    }
+}
+
+/// Stream IDs start at 1, 0 addresses the endpoint without streams.
+fn check_stream(state: &MockDeviceState, endpoint: u8, stream_id: u32) -> Result {
+    match state.streams(endpoint) {
+        Some(num_streams) if (1..=num_streams).contains(&stream_id) => Ok(()),
+        _ => Err(Error::InvalidParam),
+    }
+}
+
+fn data_transfer(endpoint: u8, kind: TransferKind, data: Vec<u8>, capacity: usize) -> MockTransfer {
+    MockTransfer {
+        endpoint,
+        kind,
+        data,
+        capacity,
+        owner: next_owner(),
+    }
+}
+
+impl DeviceCtx for DeviceCtxImpl {
+    fn device_descriptor(&self) -> Result<DeviceDescriptor> {
+        Ok(self.state.descriptor.clone())
+    }
+
+    fn get_string_ascii(&self, index: u8) -> Result<String> {
+        if !self.state.is_connected() {
+            return Err(Error::NoDevice);
+        }
+        if index == 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.state.strings.get(&index).cloned().ok_or(Error::Stall)
+    }
+
+    fn device_class(&self) -> Result<DeviceClass> {
+        Ok(class_from_code(self.state.descriptor.bDeviceClass))
+    }
+
+    fn device_subclass(&self) -> Result<DeviceClass> {
+        Ok(class_from_code(self.state.descriptor.bDeviceSubClass))
+    }
+
+    fn device_protocol(&self) -> Result<DeviceClass> {
+        Ok(class_from_code(self.state.descriptor.bDeviceProtocol))
+    }
+
+    fn config_list(&self) -> Result<Vec<ConfigDescriptor>> {
+        Ok(self.state.configs.clone())
+    }
+
+    fn set_config_by_value(&self, config_value: u8) -> Result {
+        self.state.set_active_config(config_value)
+    }
+
+    fn serial_number(&self) -> Result<String> {
+        self.get_string_ascii(self.state.descriptor.iSerialNumber)
+    }
+
+    fn bus_number(&self) -> u8 {
+        self.state.bus_number
+    }
+
+    fn device_address(&self) -> u8 {
+        self.state.device_address
+    }
+
+    fn port_numbers(&self) -> Result<Vec<u8>> {
+        Ok(self.state.port_numbers.clone())
+    }
+
+    fn get_active_configuration(&self) -> Result<ConfigDescriptor> {
+        let value = self.state.active_config();
+        self.state.configs.iter()
+            .find(|o| o.value == value)
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn claim_interface(&self, interface_number: u8) -> Result {
+        self.state.claim_interface(interface_number)
+    }
+
+    fn release_interface(&self, interface_number: u8) -> Result {
+        self.state.release_interface(interface_number)
+    }
+
+    fn set_interface_alt_setting(&self, interface_number: u8, alt_setting: u8) -> Result {
+        self.state.set_interface_alt_setting(interface_number, alt_setting)
+    }
+
+    fn kernel_driver_active(&self, interface_number: u8) -> Result<bool> {
+        self.state.kernel_driver_active(interface_number)
+    }
+
+    fn detach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.state.detach_kernel_driver(interface_number)
+    }
+
+    fn attach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.state.attach_kernel_driver(interface_number)
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result {
+        self.state.clear_halt(endpoint)
+    }
+
+    fn control_transfer_in(&self, control_transfer_request: ControlTransferRequest, capacity: usize) -> AsyncResult<Vec<u8>> {
+        let setup = control_transfer_request.setup(Direction::In, capacity as _);
+        if let Some(interface) = control_transfer_request.interface_to_claim() {
+            if let Err(e) = self.state.claim_interface(interface) {
+                return Box::pin(async move { Err(e) });
+            }
+        }
+        let f = do_transfer(&self.state, data_transfer(0x80, TransferKind::Control(setup), vec![], capacity));
+        Box::pin(async move {
+            Ok(f.await?.data)
+        })
+    }
+
+    fn control_transfer_out(&self, control_transfer_request: ControlTransferRequest, data: &[u8]) -> AsyncResult<usize> {
+        let setup = control_transfer_request.setup(Direction::Out, data.len() as _);
+        if let Some(interface) = control_transfer_request.interface_to_claim() {
+            if let Err(e) = self.state.claim_interface(interface) {
+                return Box::pin(async move { Err(e) });
+            }
+        }
+        let f = do_transfer(&self.state, data_transfer(0x00, TransferKind::Control(setup), data.to_vec(), 0));
+        Box::pin(async move {
+            Ok(f.await?.actual_length)
+        })
+    }
+
+    fn bulk_transfer_in(&self, endpoint: u8, capacity: usize, _timeout: Duration) -> AsyncResult<Vec<u8>> {
+        let f = do_transfer(&self.state, data_transfer(endpoint | 0x80, TransferKind::Bulk, vec![], capacity));
+        Box::pin(async move {
+            Ok(f.await?.data)
+        })
+    }
+
+    fn bulk_transfer_out(&self, endpoint: u8, data: &[u8], _timeout: Duration) -> AsyncResult<usize> {
+        let f = do_transfer(&self.state, data_transfer(endpoint & 0x7F, TransferKind::Bulk, data.to_vec(), 0));
+        Box::pin(async move {
+            Ok(f.await?.actual_length)
+        })
+    }
+
+    fn alloc_streams(&self, num_streams: u32, endpoints: &[u8]) -> Result<u32> {
+        self.state.alloc_streams(num_streams, endpoints)
+    }
+
+    fn free_streams(&self, endpoints: &[u8]) -> Result {
+        self.state.free_streams(endpoints)
+    }
+
+    fn bulk_stream_transfer_in(&self, endpoint: u8, stream_id: u32, capacity: usize, timeout: Duration) -> AsyncResult<Vec<u8>> {
+        if let Err(e) = check_stream(&self.state, endpoint | 0x80, stream_id) {
+            return Box::pin(async move { Err(e) });
+        }
+        self.bulk_transfer_in(endpoint, capacity, timeout)
+    }
+
+    fn bulk_stream_transfer_out(&self, endpoint: u8, stream_id: u32, data: &[u8], timeout: Duration) -> AsyncResult<usize> {
+        if let Err(e) = check_stream(&self.state, endpoint & 0x7F, stream_id) {
+            return Box::pin(async move { Err(e) });
+        }
+        self.bulk_transfer_out(endpoint, data, timeout)
+    }
+
+    fn bulk_transfer_in_buf(&self, endpoint: u8, mut buf: Vec<u8>, _timeout: Duration) -> AsyncBufResult {
+        let f = do_transfer(&self.state, data_transfer(endpoint | 0x80, TransferKind::Bulk, vec![], buf.len()));
+        Box::pin(async move {
+            match f.await {
+                Ok(o) => {
+                    buf[..o.data.len()].copy_from_slice(&o.data);
+                    (buf, Ok(o.data.len()))
+                }
+                Err(e) => (buf, Err(e)),
+            }
+        })
+    }
+
+    fn bulk_transfer_out_buf(&self, endpoint: u8, data: Vec<u8>, len: usize, timeout: Duration) -> AsyncBufResult {
+        let f = self.bulk_transfer_out(endpoint, &data[..len], timeout);
+        Box::pin(async move {
+            let r = f.await;
+            (data, r)
+        })
+    }
+
+    fn interrupt_transfer_in(&self, endpoint: u8, capacity: usize, _timeout: Duration) -> AsyncResult<Vec<u8>> {
+        let f = do_transfer(&self.state, data_transfer(endpoint | 0x80, TransferKind::Interrupt, vec![], capacity));
+        Box::pin(async move {
+            Ok(f.await?.data)
+        })
+    }
+
+    fn interrupt_transfer_out(&self, endpoint: u8, data: &[u8], _timeout: Duration) -> AsyncResult<usize> {
+        let f = do_transfer(&self.state, data_transfer(endpoint & 0x7F, TransferKind::Interrupt, data.to_vec(), 0));
+        Box::pin(async move {
+            Ok(f.await?.actual_length)
+        })
+    }
+
+    fn iso_transfer_in(&self, endpoint: u8, num_iso_packages: usize, package_capacity: usize, _timeout: Duration) -> AsyncResult<Vec<Vec<u8>>> {
+        let kind = TransferKind::Iso(vec![package_capacity; num_iso_packages]);
+        let f = do_transfer(&self.state, data_transfer(endpoint | 0x80, kind, vec![], num_iso_packages * package_capacity));
+        Box::pin(async move {
+            let completion = f.await?;
+            let mut packs = Vec::with_capacity(num_iso_packages);
+            for packet in completion.iso_packets {
+                packet.result?;
+                packs.push(packet.data);
+            }
+            Ok(packs)
+        })
+    }
+
+    fn iso_transfer_out(&self, endpoint: u8, packs: Vec<Vec<u8>>, _timeout: Duration) -> AsyncResult<Vec<IsoPacketResult>> {
+        if packs.is_empty() {
+            return Box::pin(async { Err(Error::InvalidParam) });
+        }
+        let kind = TransferKind::Iso(packs.iter().map(|o| o.len()).collect());
+        let f = do_transfer(&self.state, data_transfer(endpoint & 0x7F, kind, packs.concat(), 0));
+        Box::pin(async move {
+            let completion = f.await?;
+            Ok(completion.iso_packets.into_iter()
+                .map(|o| IsoPacketResult { actual_length: o.actual_length, status: o.result })
+                .collect())
+        })
+    }
+
+    fn bulk_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
+        if !self.state.is_connected() {
+            return Err(Error::NoDevice);
+        }
+        Ok(Box::new(EndpointPipInImpl::new(&self.state, endpoint, pip_config, false)))
+    }
+
+    fn interrupt_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
+        if !self.state.is_connected() {
+            return Err(Error::NoDevice);
+        }
+        Ok(Box::new(EndpointPipInImpl::new(&self.state, endpoint, pip_config, true)))
+    }
+
+    fn iso_transfer_pip_in(&self, endpoint: u8, num_iso_packages: usize, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner<Vec<IsoPacket>>>> {
+        if !self.state.is_connected() {
+            return Err(Error::NoDevice);
+        }
+        Ok(Box::new(EndpointPipInImpl::new_iso(&self.state, endpoint, num_iso_packages, pip_config)))
+    }
+
+    fn bulk_transfer_pip_out(&self, endpoint: u8, _pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner>> {
+        if !self.state.is_connected() {
+            return Err(Error::NoDevice);
+        }
+        Ok(Box::new(EndpointPipOutImpl::<Vec<u8>>::new(&self.state, endpoint)))
+    }
+
+    fn iso_transfer_pip_out(&self, endpoint: u8, _pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner<Vec<Vec<u8>>>>> {
+        if !self.state.is_connected() {
+            return Err(Error::NoDevice);
+        }
+        Ok(Box::new(EndpointPipOutImpl::<Vec<Vec<u8>>>::new(&self.state, endpoint)))
+    }
+}
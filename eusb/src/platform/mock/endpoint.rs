@@ -0,0 +1,238 @@
+use std::marker::PhantomData;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use log::{trace, warn};
+use crate::buffer::PooledBuffer;
+use crate::define::{IsoPacket, PipConfig};
+use crate::error::*;
+use crate::platform::{EndpointPipInInner, EndpointPipOutInner, TransferDone};
+use crate::platform::pip::{PipQueue, Pushed};
+use crate::platform::mock::{Completion, MockDeviceState, MockTransfer, next_owner, TransferKind};
+
+pub(crate) struct EndpointPipInImpl<I: PipItem = PooledBuffer> {
+    state: Arc<MockDeviceState>,
+    owner: u64,
+    pip: Arc<PipIn<I>>,
+}
+
+/// What an IN pipe yields for one completed transfer.
+pub(crate) trait PipItem: Send + Sized + 'static {
+    fn read(completion: Completion, queue: &PipQueue<(), Self>) -> Self;
+}
+
+impl PipItem for PooledBuffer {
+    fn read(completion: Completion, queue: &PipQueue<(), Self>) -> Self {
+        let mut buf = queue.pool().get();
+        buf[..completion.data.len()].copy_from_slice(&completion.data);
+        buf.truncate(completion.data.len());
+        buf
+    }
+}
+
+impl PipItem for Vec<IsoPacket> {
+    fn read(completion: Completion, queue: &PipQueue<(), Self>) -> Self {
+        let first = queue.next_position(completion.iso_packets.len() as _);
+        completion.iso_packets.into_iter()
+            .zip(first..)
+            .map(|(packet, position)| IsoPacket {
+                data: packet.data,
+                status: packet.result,
+                position,
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PipKind {
+    Bulk,
+    Interrupt,
+    /// Number of packets per transfer.
+    Iso(usize),
+}
+
+/// What the completion callbacks need to resubmit.
+struct PipIn<I> {
+    endpoint: u8,
+    kind: PipKind,
+    package_size: usize,
+    owner: u64,
+    queue: PipQueue<(), I>,
+}
+
+impl EndpointPipInImpl {
+    pub fn new(state: &Arc<MockDeviceState>, endpoint: u8, config: PipConfig, is_interrupt: bool) -> Self {
+        let kind = if is_interrupt { PipKind::Interrupt } else { PipKind::Bulk };
+        Self::start(state, endpoint, config, kind)
+    }
+}
+
+impl EndpointPipInImpl<Vec<IsoPacket>> {
+    pub fn new_iso(state: &Arc<MockDeviceState>, endpoint: u8, num_iso_packages: usize, config: PipConfig) -> Self {
+        Self::start(state, endpoint, config, PipKind::Iso(num_iso_packages))
+    }
+}
+
+impl<I: PipItem> EndpointPipInImpl<I> {
+    fn start(state: &Arc<MockDeviceState>, endpoint: u8, config: PipConfig, kind: PipKind) -> Self {
+        let owner = next_owner();
+        let pip = Arc::new(PipIn {
+            endpoint: endpoint | 0x80,
+            kind,
+            package_size: config.package_size,
+            owner,
+            queue: PipQueue::new(&config),
+        });
+        for _ in 0..config.request_num {
+            pip_submit(state, &pip);
+        }
+        Self {
+            state: state.clone(),
+            owner,
+            pip,
+        }
+    }
+}
+
+impl<I: PipItem> Drop for EndpointPipInImpl<I> {
+    fn drop(&mut self) {
+        self.pip.queue.close();
+        self.state.cancel_owner(self.owner);
+    }
+}
+
+impl<I: PipItem> EndpointPipInInner<I> for EndpointPipInImpl<I> {
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<I>>> {
+        let (r, resume) = self.pip.queue.poll_next(cx);
+        for _ in resume {
+            pip_submit(&self.state, &self.pip);
+        }
+        r
+    }
+
+    fn dropped(&self) -> u64 {
+        self.pip.queue.dropped()
+    }
+
+    fn error(&self) -> Option<Error> {
+        self.pip.queue.error()
+    }
+}
+
+fn pip_submit<I: PipItem>(state: &Arc<MockDeviceState>, pip: &Arc<PipIn<I>>) {
+    let weak = Arc::downgrade(state);
+    let (kind, capacity) = match pip.kind {
+        PipKind::Bulk => (TransferKind::Bulk, pip.package_size),
+        PipKind::Interrupt => (TransferKind::Interrupt, pip.package_size),
+        PipKind::Iso(num) => (TransferKind::Iso(vec![pip.package_size; num]), num * pip.package_size),
+    };
+    let transfer = MockTransfer {
+        endpoint: pip.endpoint,
+        kind,
+        data: vec![],
+        capacity,
+        owner: pip.owner,
+    };
+    let pip = pip.clone();
+    state.submit(transfer, Box::new(move |completion| {
+        pip_cb(weak, pip, completion);
+    }));
+}
+
+fn pip_cb<I: PipItem>(state: Weak<MockDeviceState>, pip: Arc<PipIn<I>>, completion: Completion) {
+    if pip.queue.is_closed() {
+        trace!("pip closed");
+        pip.queue.stop();
+        return;
+    }
+    match completion.result.clone() {
+        Ok(_) => {
+            let data = I::read(completion, &pip.queue);
+            if !matches!(pip.queue.push(pip.endpoint, data, ()), Pushed::Resubmit(_)) {
+                return;
+            }
+        }
+        Err(e) => {
+            match e.kind() {
+                Error::Stall => {
+                    warn!("ep[{}] stall", pip.endpoint);
+                    let clear_halt = || match state.upgrade() {
+                        Some(state) => state.clear_halt(pip.endpoint),
+                        None => Err(Error::NoDevice),
+                    };
+                    if !pip.queue.stalled(e, clear_halt) {
+                        return;
+                    }
+                }
+                _ => {
+                    trace!("transfer err: {}", e);
+                    pip.queue.stop_with(e);
+                    return;
+                }
+            }
+        }
+    }
+    match state.upgrade() {
+        Some(state) => pip_submit(&state, &pip),
+        None => pip.queue.stop_with(Error::NoDevice),
+    }
+}
+
+pub(crate) struct EndpointPipOutImpl<I: PipOutItem = Vec<u8>> {
+    state: Arc<MockDeviceState>,
+    endpoint: u8,
+    owner: u64,
+    _item: PhantomData<fn(I)>,
+}
+
+/// What an OUT pipe sends with one transfer.
+pub(crate) trait PipOutItem: Send + 'static {
+    /// Kind and payload of the transfer.
+    fn into_transfer(self) -> Result<(TransferKind, Vec<u8>)>;
+}
+
+impl PipOutItem for Vec<u8> {
+    fn into_transfer(self) -> Result<(TransferKind, Vec<u8>)> {
+        Ok((TransferKind::Bulk, self))
+    }
+}
+
+impl PipOutItem for Vec<Vec<u8>> {
+    fn into_transfer(self) -> Result<(TransferKind, Vec<u8>)> {
+        if self.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        Ok((TransferKind::Iso(self.iter().map(|o| o.len()).collect()), self.concat()))
+    }
+}
+
+impl<I: PipOutItem> EndpointPipOutImpl<I> {
+    pub fn new(state: &Arc<MockDeviceState>, endpoint: u8) -> Self {
+        Self {
+            state: state.clone(),
+            endpoint: endpoint & 0x7F,
+            owner: next_owner(),
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<I: PipOutItem> EndpointPipOutInner<I> for EndpointPipOutImpl<I> {
+    fn submit(&mut self, data: I, done: TransferDone) -> Result {
+        if !self.state.is_connected() {
+            return Err(Error::NoDevice);
+        }
+        let (kind, data) = data.into_transfer()?;
+        let transfer = MockTransfer {
+            endpoint: self.endpoint,
+            kind,
+            data,
+            capacity: 0,
+            owner: self.owner,
+        };
+        self.state.submit(transfer, Box::new(move |completion| {
+            done(completion.result.map(|_| completion.actual_length));
+        }));
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use log::debug;
+use crate::define::DeviceId;
+use crate::device::UsbDevice;
+use crate::error::*;
+use crate::hotplug::{HotplugEvent, HotplugFilter, HotplugStream};
+use crate::platform::*;
+use crate::platform::mock::{dispatch, MockDeviceState, next_owner, Ready};
+use crate::platform::mock::device::DeviceCtxImpl;
+#[cfg(unix)]
+use crate::reactor::{PollFd, PollfdWatch};
+
+pub(crate) struct ManagerCtxImpl {
+    devices: Mutex<Vec<Arc<MockDeviceState>>>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    event_thread: bool,
+    /// Completions waiting for [`handle_events`](ManagerCtx::handle_events)
+    /// without an event thread.
+    deferred: Mutex<Ready>,
+    deferred_cond: Condvar,
+    /// There are no descriptors to watch, only deferred completions to
+    /// notify.
+    #[cfg(unix)]
+    pollfd_watch: Arc<PollfdWatch>,
+}
+
+struct Subscriber {
+    id: u64,
+    filter: HotplugFilter,
+    tx: UnboundedSender<HotplugEvent>,
+}
+
+struct HotplugRegistration(u64, Arc<ManagerCtxImpl>);
+
+impl Drop for HotplugRegistration {
+    fn drop(&mut self) {
+        let mut g = self.1.subscribers.lock().unwrap();
+        g.retain(|o| o.id != self.0);
+    }
+}
+
+impl ManagerCtx for ManagerCtxImpl {
+    fn new(builder: &UsbContextBuilder) -> Result<Self> {
+        Ok(Self {
+            devices: Mutex::new(vec![]),
+            subscribers: Mutex::new(vec![]),
+            event_thread: builder.event_thread,
+            deferred: Mutex::new(vec![]),
+            deferred_cond: Condvar::new(),
+            #[cfg(unix)]
+            pollfd_watch: Default::default(),
+        })
+    }
+
+    fn device_list(self: Arc<Self>) -> Result<Vec<UsbDevice>> {
+        let g = self.devices.lock().unwrap();
+        Ok(g.iter().map(|o| DeviceCtxImpl::new(o.clone(), self.clone()).into()).collect())
+    }
+
+    fn open_device_with_vid_pid(self: Arc<Self>, vid: u16, pid: u16) -> Result<UsbDevice> {
+        let g = self.devices.lock().unwrap();
+        let state = g.iter()
+            .find(|o| o.descriptor.idVendor == vid && o.descriptor.idProduct == pid)
+            .ok_or(Error::NotFound)?;
+        Ok(DeviceCtxImpl::new(state.clone(), self.clone()).into())
+    }
+
+    #[cfg(unix)]
+    fn open_device_with_fd(self: Arc<Self>, _fd: RawFd) -> Result<UsbDevice> {
+        Err(Error::NotSupported)
+    }
+
+    fn hotplug(self: Arc<Self>, filter: HotplugFilter) -> Result<HotplugStream> {
+        let (tx, rx) = unbounded();
+        let id = next_owner();
+        let devices = self.devices.lock().unwrap();
+        if filter.enumerate {
+            for state in devices.iter().filter(|o| filter.matches(&o.descriptor)) {
+                let _ = tx.unbounded_send(HotplugEvent::Arrived(DeviceCtxImpl::new(state.clone(), self.clone()).into()));
+            }
+        }
+        self.subscribers.lock().unwrap().push(Subscriber { id, filter, tx });
+        Ok(HotplugStream::new(rx, HotplugRegistration(id, self.clone())))
+    }
+
+    /// Run the completions deferred without an event thread, waiting up to
+    /// `timeout` for one.
+    fn handle_events(&self, timeout: Duration) -> Result {
+        let g = self.deferred.lock().unwrap();
+        let (mut g, _) = self.deferred_cond.wait_timeout_while(g, timeout, |o| o.is_empty()).unwrap();
+        let ready = std::mem::take(&mut *g);
+        drop(g);
+        dispatch(ready);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn pollfd_watch(&self) -> Arc<PollfdWatch> {
+        self.pollfd_watch.clone()
+    }
+
+    #[cfg(unix)]
+    fn pollfds(&self) -> Result<Vec<PollFd>> {
+        Ok(vec![])
+    }
+
+    #[cfg(unix)]
+    fn next_timeout(&self) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    fn as_mock(self: Arc<Self>) -> Option<Arc<ManagerCtxImpl>> {
+        Some(self)
+    }
+}
+
+impl Drop for ManagerCtxImpl {
+    fn drop(&mut self) {
+        // Let an event driver see the context is gone.
+        #[cfg(unix)]
+        self.pollfd_watch.notify();
+    }
+}
+
+impl ManagerCtxImpl {
+    pub(crate) fn has_event_thread(&self) -> bool {
+        self.event_thread
+    }
+
+    /// Keep completions until events are handled, like libusb does.
+    pub(crate) fn defer(&self, ready: Ready) {
+        self.deferred.lock().unwrap().extend(ready);
+        self.deferred_cond.notify_all();
+        #[cfg(unix)]
+        self.pollfd_watch.notify();
+    }
+
+    pub(crate) fn plug(self: &Arc<Self>, state: Arc<MockDeviceState>) {
+        state.set_manager(Arc::downgrade(self));
+        debug!("mock device [0x{:04X}:0x{:04X}] plugged",
+            state.descriptor.idVendor, state.descriptor.idProduct);
+        let mut g = self.devices.lock().unwrap();
+        g.push(state.clone());
+        for subscriber in self.subscribers.lock().unwrap().iter() {
+            if subscriber.filter.matches(&state.descriptor) {
+                let device = DeviceCtxImpl::new(state.clone(), self.clone()).into();
+                let _ = subscriber.tx.unbounded_send(HotplugEvent::Arrived(device));
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, state: &MockDeviceState) {
+        let mut g = self.devices.lock().unwrap();
+        let len = g.len();
+        g.retain(|o| !std::ptr::eq(o.as_ref(), state));
+        if g.len() == len {
+            return;
+        }
+        let id = DeviceId {
+            bus_number: state.bus_number,
+            device_address: state.device_address,
+        };
+        for subscriber in self.subscribers.lock().unwrap().iter() {
+            if subscriber.filter.matches(&state.descriptor) {
+                let _ = subscriber.tx.unbounded_send(HotplugEvent::Left(id));
+            }
+        }
+    }
+}
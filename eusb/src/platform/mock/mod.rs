@@ -0,0 +1,682 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::define::*;
+use crate::error::*;
+use crate::platform::mock::manager::ManagerCtxImpl;
+use crate::mock::MockResponse;
+
+pub(crate) mod device;
+pub(crate) mod manager;
+pub(crate) mod endpoint;
+
+pub(crate) type Callback = Box<dyn FnOnce(Completion) + Send>;
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(1);
+
+/// Every submitted transfer belongs to an owner, so a pip or a dropped
+/// future can cancel all of its in-flight transfers at once.
+pub(crate) fn next_owner() -> u64 {
+    NEXT_OWNER.fetch_add(1, Ordering::Relaxed)
+}
+
+pub(crate) enum TransferKind {
+    Control(ControlSetup),
+    Bulk,
+    Interrupt,
+    /// Packet lengths.
+    Iso(Vec<usize>),
+}
+
+pub(crate) struct MockTransfer {
+    /// Endpoint address, including the direction bit.
+    pub endpoint: u8,
+    pub kind: TransferKind,
+    /// Payload of an OUT transfer.
+    pub data: Vec<u8>,
+    /// Buffer length of an IN transfer.
+    pub capacity: usize,
+    pub owner: u64,
+}
+
+impl MockTransfer {
+    fn is_in(&self) -> bool {
+        self.endpoint & 0x80 == 0x80
+    }
+}
+
+pub(crate) struct Completion {
+    pub result: Result,
+    pub data: Vec<u8>,
+    pub actual_length: usize,
+    pub iso_packets: Vec<IsoPacketCompletion>,
+}
+
+pub(crate) struct IsoPacketCompletion {
+    pub result: Result,
+    pub data: Vec<u8>,
+    pub actual_length: usize,
+}
+
+impl Completion {
+    fn err(e: Error) -> Self {
+        Self {
+            result: Err(e),
+            data: vec![],
+            actual_length: 0,
+            iso_packets: vec![],
+        }
+    }
+}
+
+pub(crate) struct MockDeviceState {
+    pub(crate) descriptor: DeviceDescriptor,
+    pub(crate) configs: Vec<ConfigDescriptor>,
+    pub(crate) strings: HashMap<u8, String>,
+    pub(crate) bus_number: u8,
+    pub(crate) device_address: u8,
+    pub(crate) port_numbers: Vec<u8>,
+    inner: Mutex<MockDeviceInner>,
+}
+
+struct MockDeviceInner {
+    connected: bool,
+    active_config: u8,
+    endpoints: HashMap<u8, EndpointQueue>,
+    control_setups: Vec<ControlSetup>,
+    closed_owners: HashSet<u64>,
+    /// Claimed interfaces with their current alt setting.
+    claimed: HashMap<u8, u8>,
+    kernel_drivers: HashSet<u8>,
+    /// Bulk streams allocated per endpoint address.
+    streams: HashMap<u8, u32>,
+    /// Context the device is plugged into.
+    manager: Weak<ManagerCtxImpl>,
+}
+
+#[derive(Default)]
+struct EndpointQueue {
+    responses: VecDeque<MockResponse>,
+    pending: VecDeque<Pending>,
+    written: Vec<Vec<u8>>,
+    /// Stalled until the host clears the halt.
+    halted: bool,
+}
+
+struct Pending {
+    transfer: MockTransfer,
+    callback: Callback,
+}
+
+type Ready = Vec<(Callback, Completion)>;
+
+impl MockDeviceState {
+    pub(crate) fn new(
+        descriptor: DeviceDescriptor,
+        configs: Vec<ConfigDescriptor>,
+        strings: HashMap<u8, String>,
+        bus_number: u8,
+        device_address: u8,
+        port_numbers: Vec<u8>,
+        kernel_drivers: HashSet<u8>,
+    ) -> Self {
+        let active_config = configs.first().map(|o| o.value).unwrap_or(0);
+        Self {
+            descriptor,
+            configs,
+            strings,
+            bus_number,
+            device_address,
+            port_numbers,
+            inner: Mutex::new(MockDeviceInner {
+                connected: true,
+                active_config,
+                endpoints: HashMap::new(),
+                control_setups: vec![],
+                closed_owners: HashSet::new(),
+                claimed: HashMap::new(),
+                kernel_drivers,
+                streams: HashMap::new(),
+                manager: Weak::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn set_manager(&self, manager: Weak<ManagerCtxImpl>) {
+        self.inner.lock().unwrap().manager = manager;
+    }
+
+    /// Take the device off the list of its context.
+    fn unplug(&self) {
+        let manager = self.inner.lock().unwrap().manager.upgrade();
+        if let Some(manager) = manager {
+            manager.remove(self);
+        }
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.inner.lock().unwrap().connected
+    }
+
+    pub(crate) fn active_config(&self) -> u8 {
+        self.inner.lock().unwrap().active_config
+    }
+
+    pub(crate) fn set_active_config(&self, value: u8) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if !self.configs.iter().any(|o| o.value == value) {
+            return Err(Error::NotFound);
+        }
+        g.active_config = value;
+        Ok(())
+    }
+
+    fn interface(&self, active_config: u8, interface_number: u8) -> Option<&InterfaceAltSettingDescriptor> {
+        self.configs.iter()
+            .find(|o| o.value == active_config)?
+            .interfaces.iter()
+            .find(|o| o.alt_settings.first().map(|a| a.num) == Some(interface_number))
+    }
+
+    pub(crate) fn claim_interface(&self, interface_number: u8) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if self.interface(g.active_config, interface_number).is_none() {
+            return Err(Error::NotFound);
+        }
+        if g.kernel_drivers.contains(&interface_number) {
+            return Err(Error::Busy);
+        }
+        g.claimed.entry(interface_number).or_insert(0);
+        Ok(())
+    }
+
+    pub(crate) fn release_interface(&self, interface_number: u8) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if g.claimed.remove(&interface_number).is_none() {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_claimed(&self, interface_number: u8) -> bool {
+        self.inner.lock().unwrap().claimed.contains_key(&interface_number)
+    }
+
+    /// Current alt setting of a claimed interface.
+    pub(crate) fn alt_setting(&self, interface_number: u8) -> Option<u8> {
+        self.inner.lock().unwrap().claimed.get(&interface_number).copied()
+    }
+
+    pub(crate) fn set_interface_alt_setting(&self, interface_number: u8, alt_setting: u8) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if !g.claimed.contains_key(&interface_number) {
+            return Err(Error::NotFound);
+        }
+        let interface = self.interface(g.active_config, interface_number).ok_or(Error::NotFound)?;
+        if !interface.alt_settings.iter().any(|o| o.alt_setting == alt_setting) {
+            return Err(Error::NotFound);
+        }
+        g.claimed.insert(interface_number, alt_setting);
+        Ok(())
+    }
+
+    pub(crate) fn kernel_driver_active(&self, interface_number: u8) -> Result<bool> {
+        let g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        Ok(g.kernel_drivers.contains(&interface_number))
+    }
+
+    pub(crate) fn detach_kernel_driver(&self, interface_number: u8) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if !g.kernel_drivers.remove(&interface_number) {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn attach_kernel_driver(&self, interface_number: u8) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if self.interface(g.active_config, interface_number).is_none() {
+            return Err(Error::NotFound);
+        }
+        if g.claimed.contains_key(&interface_number) || !g.kernel_drivers.insert(interface_number) {
+            return Err(Error::Busy);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn alloc_streams(&self, num_streams: u32, endpoints: &[u8]) -> Result<u32> {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if num_streams == 0 || endpoints.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        if endpoints.iter().any(|o| g.streams.contains_key(o)) {
+            return Err(Error::Busy);
+        }
+        for &endpoint in endpoints {
+            g.streams.insert(endpoint, num_streams);
+        }
+        Ok(num_streams)
+    }
+
+    pub(crate) fn free_streams(&self, endpoints: &[u8]) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        if !endpoints.iter().all(|o| g.streams.contains_key(o)) {
+            return Err(Error::NotFound);
+        }
+        for endpoint in endpoints {
+            g.streams.remove(endpoint);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear_halt(&self, endpoint: u8) -> Result {
+        let mut g = self.inner.lock().unwrap();
+        if !g.connected {
+            return Err(Error::NoDevice);
+        }
+        g.queue(endpoint).halted = false;
+        Ok(())
+    }
+
+    pub(crate) fn is_halted(&self, endpoint: u8) -> bool {
+        self.inner.lock().unwrap().endpoints.get(&endpoint).is_some_and(|o| o.halted)
+    }
+
+    /// Number of streams allocated on `endpoint`.
+    pub(crate) fn streams(&self, endpoint: u8) -> Option<u32> {
+        self.inner.lock().unwrap().streams.get(&endpoint).copied()
+    }
+
+    /// Hand a transfer to the virtual device. The callback runs once, either
+    /// right away if a response is queued or when one is pushed later.
+    pub(crate) fn submit(&self, transfer: MockTransfer, callback: Callback) {
+        let context = self.context(&transfer);
+        let callback: Callback = Box::new(move |mut completion| {
+            if let Err(e) = completion.result {
+                completion.result = Err(e.in_transfer(TransferContext {
+                    actual_length: completion.actual_length,
+                    data: std::mem::take(&mut completion.data),
+                    ..context
+                }));
+            }
+            callback(completion)
+        });
+        let mut ready = Ready::new();
+        {
+            let mut g = self.inner.lock().unwrap();
+            if !g.connected {
+                ready.push((callback, Completion::err(Error::NoDevice)));
+            } else if g.closed_owners.contains(&transfer.owner) {
+                ready.push((callback, Completion::err(Error::Cancelled)));
+            } else {
+                if let TransferKind::Control(setup) = &transfer.kind {
+                    g.control_setups.push(*setup);
+                }
+                let response = g.queue(transfer.endpoint).responses.pop_front();
+                match response {
+                    Some(response) => {
+                        let completion = g.complete(&transfer, response, &mut ready);
+                        ready.push((callback, completion));
+                    }
+                    None => g.queue(transfer.endpoint).pending.push_back(Pending { transfer, callback }),
+                }
+            }
+        }
+        self.finish(ready);
+    }
+
+    fn context(&self, transfer: &MockTransfer) -> TransferContext {
+        TransferContext {
+            device: DeviceId {
+                bus_number: self.bus_number,
+                device_address: self.device_address,
+            },
+            transfer_type: match transfer.kind {
+                TransferKind::Control(_) => EndpointTransferType::Control,
+                TransferKind::Bulk => EndpointTransferType::Bulk,
+                TransferKind::Interrupt => EndpointTransferType::Interrupt,
+                TransferKind::Iso(_) => EndpointTransferType::Isochronous,
+            },
+            endpoint: transfer.endpoint,
+            actual_length: 0,
+            data: vec![],
+        }
+    }
+
+    /// Queue a response on `endpoint`, completing the oldest pending transfer if any.
+    pub(crate) fn push_response(&self, endpoint: u8, response: MockResponse) {
+        let mut ready = Ready::new();
+        {
+            let mut g = self.inner.lock().unwrap();
+            if !g.connected {
+                return;
+            }
+            match g.queue(endpoint).pending.pop_front() {
+                Some(pending) => {
+                    let completion = g.complete(&pending.transfer, response, &mut ready);
+                    ready.push((pending.callback, completion));
+                }
+                None => g.queue(endpoint).responses.push_back(response),
+            }
+        }
+        self.finish(ready);
+    }
+
+    /// Cancel every pending transfer of `owner` and reject its future submissions.
+    pub(crate) fn cancel_owner(&self, owner: u64) {
+        let mut ready = Ready::new();
+        {
+            let mut g = self.inner.lock().unwrap();
+            g.closed_owners.insert(owner);
+            g.cancel(owner, &mut ready);
+        }
+        self.dispatch(ready);
+    }
+
+    /// Cancel the pending transfers of `owner`, e.g. when their future is dropped.
+    pub(crate) fn cancel(&self, owner: u64) {
+        let mut ready = Ready::new();
+        self.inner.lock().unwrap().cancel(owner, &mut ready);
+        self.dispatch(ready);
+    }
+
+    pub(crate) fn disconnect(&self) {
+        let mut ready = Ready::new();
+        let was_connected = {
+            let mut g = self.inner.lock().unwrap();
+            let was_connected = g.connected;
+            g.disconnect(&mut ready);
+            was_connected
+        };
+        if was_connected {
+            self.unplug();
+        }
+        self.dispatch(ready);
+    }
+
+    pub(crate) fn pending(&self, endpoint: u8) -> usize {
+        let g = self.inner.lock().unwrap();
+        g.endpoints.get(&endpoint).map(|o| o.pending.len()).unwrap_or(0)
+    }
+
+    pub(crate) fn take_written(&self, endpoint: u8) -> Vec<Vec<u8>> {
+        let mut g = self.inner.lock().unwrap();
+        match g.endpoints.get_mut(&endpoint) {
+            Some(queue) => std::mem::take(&mut queue.written),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn take_control_setups(&self) -> Vec<ControlSetup> {
+        let mut g = self.inner.lock().unwrap();
+        std::mem::take(&mut g.control_setups)
+    }
+
+    /// Completions run right away as if on the event thread, or once a
+    /// context without one handles events.
+    fn dispatch(&self, ready: Ready) {
+        if ready.is_empty() {
+            return;
+        }
+        let manager = self.inner.lock().unwrap().manager.upgrade();
+        match manager {
+            Some(manager) if !manager.has_event_thread() => manager.defer(ready),
+            _ => dispatch(ready),
+        }
+    }
+
+    fn finish(&self, ready: Ready) {
+        let disconnected = !self.is_connected();
+        if disconnected {
+            self.unplug();
+        }
+        self.dispatch(ready);
+    }
+}
+
+impl MockDeviceInner {
+    fn queue(&mut self, endpoint: u8) -> &mut EndpointQueue {
+        self.endpoints.entry(endpoint).or_default()
+    }
+
+    fn cancel(&mut self, owner: u64, ready: &mut Ready) {
+        for queue in self.endpoints.values_mut() {
+            let mut kept = VecDeque::with_capacity(queue.pending.len());
+            while let Some(pending) = queue.pending.pop_front() {
+                if pending.transfer.owner == owner {
+                    ready.push((pending.callback, Completion::err(Error::Cancelled)));
+                } else {
+                    kept.push_back(pending);
+                }
+            }
+            queue.pending = kept;
+        }
+    }
+
+    fn disconnect(&mut self, ready: &mut Ready) {
+        self.connected = false;
+        for queue in self.endpoints.values_mut() {
+            queue.responses.clear();
+            while let Some(pending) = queue.pending.pop_front() {
+                ready.push((pending.callback, Completion::err(Error::NoDevice)));
+            }
+        }
+    }
+
+    fn complete(&mut self, transfer: &MockTransfer, response: MockResponse, ready: &mut Ready) -> Completion {
+        match response {
+            MockResponse::Stall => {
+                // A control endpoint recovers by itself with the next setup packet.
+                if !matches!(transfer.kind, TransferKind::Control(_)) {
+                    self.queue(transfer.endpoint).halted = true;
+                }
+                Completion::err(Error::Stall)
+            }
+            MockResponse::PartialStall(len) if !transfer.is_in() => {
+                let len = len.min(transfer.data.len());
+                let queue = self.queue(transfer.endpoint);
+                queue.halted = true;
+                if len > 0 {
+                    queue.written.push(transfer.data[..len].to_vec());
+                }
+                Completion { actual_length: len, ..Completion::err(Error::Stall) }
+            }
+            MockResponse::Timeout => Completion::err(Error::Timeout),
+            MockResponse::Disconnect => {
+                self.disconnect(ready);
+                Completion::err(Error::NoDevice)
+            }
+            response => {
+                let completion = match &transfer.kind {
+                    TransferKind::Iso(lens) => complete_iso(transfer, lens, response),
+                    TransferKind::Control(_) => complete_data(transfer, response, true),
+                    _ => complete_data(transfer, response, false),
+                };
+                if !transfer.is_in() && completion.result.is_ok() {
+                    self.queue(transfer.endpoint).written.push(transfer.data.clone());
+                } else if !transfer.is_in() && completion.actual_length > 0 {
+                    self.queue(transfer.endpoint).written.push(transfer.data[..completion.actual_length].to_vec());
+                }
+                completion
+            }
+        }
+    }
+}
+
+fn mismatch() -> Completion {
+    Completion::err(Error::Other("mock response does not match the transfer".to_string()))
+}
+
+fn complete_data(transfer: &MockTransfer, response: MockResponse, truncate: bool) -> Completion {
+    match (transfer.is_in(), response) {
+        (true, MockResponse::Data(mut data)) => {
+            let mut result = Ok(());
+            if data.len() > transfer.capacity {
+                data.truncate(transfer.capacity);
+                if !truncate {
+                    result = Err(Error::Overflow);
+                }
+            }
+            Completion {
+                result,
+                actual_length: data.len(),
+                data,
+                iso_packets: vec![],
+            }
+        }
+        (false, MockResponse::Ack) => Completion {
+            result: Ok(()),
+            data: vec![],
+            actual_length: transfer.data.len(),
+            iso_packets: vec![],
+        },
+        (true, MockResponse::PartialData(mut data)) => {
+            data.truncate(transfer.capacity);
+            Completion {
+                result: Err(Error::Timeout),
+                actual_length: data.len(),
+                data,
+                iso_packets: vec![],
+            }
+        }
+        (false, MockResponse::PartialAck(len)) => Completion {
+            result: Err(Error::Timeout),
+            data: vec![],
+            actual_length: len.min(transfer.data.len()),
+            iso_packets: vec![],
+        },
+        _ => mismatch(),
+    }
+}
+
+fn complete_iso(transfer: &MockTransfer, lens: &[usize], response: MockResponse) -> Completion {
+    let mut packets = match response {
+        MockResponse::Iso(packets) => packets.into_iter(),
+        MockResponse::Ack if !transfer.is_in() => vec![].into_iter(),
+        _ => return mismatch(),
+    };
+    let mut iso_packets = Vec::with_capacity(lens.len());
+    let mut actual = 0;
+    for &len in lens {
+        let packet = match packets.next() {
+            Some(MockResponse::Stall) => Err(Error::Stall),
+            Some(MockResponse::Timeout) => Err(Error::Timeout),
+            Some(MockResponse::Data(data)) if transfer.is_in() => {
+                if data.len() > len {
+                    Err(Error::Overflow)
+                } else {
+                    Ok((data.len(), data))
+                }
+            }
+            Some(MockResponse::Ack) | None if !transfer.is_in() => Ok((len, vec![])),
+            None => Ok((0, vec![])),
+            Some(_) => return mismatch(),
+        };
+        iso_packets.push(match packet {
+            Ok((actual_length, data)) => {
+                actual += actual_length;
+                IsoPacketCompletion { result: Ok(()), data, actual_length }
+            }
+            Err(e) => IsoPacketCompletion { result: Err(e), data: vec![], actual_length: 0 },
+        });
+    }
+    Completion {
+        result: Ok(()),
+        data: vec![],
+        actual_length: actual,
+        iso_packets,
+    }
+}
+
+thread_local! {
+    static DISPATCH: RefCell<Option<VecDeque<(Callback, Completion)>>> = const { RefCell::new(None) };
+}
+
+struct DispatchGuard;
+
+impl Drop for DispatchGuard {
+    fn drop(&mut self) {
+        DISPATCH.with(|d| *d.borrow_mut() = None);
+    }
+}
+
+/// Run completion callbacks outside of the device lock. A callback that
+/// resubmits and completes again is queued instead of recursing, so a pip
+/// draining a long response queue does not grow the stack.
+fn dispatch(ready: Ready) {
+    if ready.is_empty() {
+        return;
+    }
+    let nested = DISPATCH.with(|d| {
+        let mut d = d.borrow_mut();
+        match d.as_mut() {
+            Some(queue) => {
+                queue.extend(ready);
+                true
+            }
+            None => {
+                *d = Some(ready.into_iter().collect());
+                false
+            }
+        }
+    });
+    if nested {
+        return;
+    }
+    let _guard = DispatchGuard;
+    while let Some((callback, completion)) = DISPATCH.with(|d| d.borrow_mut().as_mut().and_then(|q| q.pop_front())) {
+        callback(completion);
+    }
+}
+
+pub(crate) fn class_from_code(class: u8) -> DeviceClass {
+    match class {
+        0x00 => DeviceClass::PerInterface,
+        0x01 => DeviceClass::Audio,
+        0x02 => DeviceClass::Comm,
+        0x03 => DeviceClass::Hid,
+        0x05 => DeviceClass::Physical,
+        0x06 => DeviceClass::Image,
+        0x07 => DeviceClass::Printer,
+        0x08 => DeviceClass::MassStorage,
+        0x09 => DeviceClass::Hub,
+        0x0a => DeviceClass::Data,
+        0x0b => DeviceClass::SmartCard,
+        0x0d => DeviceClass::ContentSecurity,
+        0x0e => DeviceClass::Video,
+        0x0f => DeviceClass::PersonalHealthcare,
+        0xdc => DeviceClass::DiagnosticDevice,
+        0xe0 => DeviceClass::Wireless,
+        0xfe => DeviceClass::Application,
+        0xff => DeviceClass::VendorSpec,
+        _ => DeviceClass::Unknown,
+    }
+}
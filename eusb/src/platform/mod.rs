@@ -1,27 +1,81 @@
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use futures::future::{BoxFuture, LocalBoxFuture};
+use crate::buffer::PooledBuffer;
 use crate::error::*;
 use crate::device::UsbDevice;
+use crate::hotplug::{HotplugFilter, HotplugStream};
+use crate::context::UsbContextBuilder;
 use crate::define::*;
 #[cfg(unix)]
 pub use std::os::unix::io::RawFd;
+#[cfg(unix)]
+use crate::reactor::{PollFd, PollfdWatch};
 
 
 #[cfg(libusb)]
 pub(crate) mod libusb;
 
 #[cfg(libusb)]
-pub(crate) use libusb::{device::DeviceCtxImpl, manager::ManagerCtxImpl, endpoint::EndpointPipInImpl};
+pub(crate) use libusb::{device::DeviceCtxImpl, manager::ManagerCtxImpl};
+
+#[cfg(mock)]
+pub(crate) mod mock;
+
+pub(crate) mod pip;
+
+/// Backends built in, picked per context by the [`UsbContextBuilder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backend {
+    #[cfg(libusb)]
+    Libusb,
+    /// Virtual devices of [`crate::mock`].
+    #[cfg(mock)]
+    Mock,
+}
 
-type AsyncResult<T=()> =  Pin<Box<dyn Future<Output=Result<T>>>>;
+impl Default for Backend {
+    /// libusb, unless only the mock is built.
+    fn default() -> Self {
+        #[cfg(libusb)]
+        return Backend::Libusb;
+        #[cfg(not(libusb))]
+        Backend::Mock
+    }
+}
 
-pub(crate) trait EndpointPipInInner {
-    fn next(&mut self)-> BoxFuture<Option<Vec<u8>>>;
+pub(crate) fn new_manager(builder: &UsbContextBuilder) -> Result<Arc<dyn ManagerCtx>> {
+    Ok(match builder.backend {
+        #[cfg(libusb)]
+        Backend::Libusb => Arc::new(ManagerCtxImpl::new(builder)?),
+        #[cfg(mock)]
+        Backend::Mock => Arc::new(mock::manager::ManagerCtxImpl::new(builder)?),
+    })
 }
 
-pub(crate) trait DeviceCtx {
+pub(crate) type AsyncResult<T=()> =  Pin<Box<dyn Future<Output=Result<T>>>>;
+/// A transfer on a caller's buffer, handed back whatever the result.
+pub(crate) type AsyncBufResult = Pin<Box<dyn Future<Output=(Vec<u8>, Result<usize>)>>>;
+
+/// IN pipe of a backend, `I` is what one completed transfer yields.
+pub(crate) trait EndpointPipInInner<I = PooledBuffer>: Send {
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<I>>>;
+    fn dropped(&self) -> u64;
+    fn error(&self) -> Option<Error>;
+}
+
+/// Runs once when a transfer submitted to an [`EndpointPipOutInner`] completes.
+pub(crate) type TransferDone = Box<dyn FnOnce(Result<usize>) + Send>;
+
+/// OUT pipe of a backend, `I` is what one transfer sends.
+pub(crate) trait EndpointPipOutInner<I = Vec<u8>>: Send {
+    /// Submit one buffer without waiting for it.
+    fn submit(&mut self, data: I, done: TransferDone) -> Result;
+}
+
+pub(crate) trait DeviceCtx: Send + Sync {
     fn device_descriptor(&self) -> Result<DeviceDescriptor>;
     fn get_string_ascii(&self, index: u8)-> Result<String>;
     fn device_class(&self) -> Result<DeviceClass>;
@@ -32,23 +86,62 @@ pub(crate) trait DeviceCtx {
     fn serial_number(&self) -> Result<String>;
     fn bus_number(&self) -> u8;
     fn device_address(&self) -> u8;
+    fn port_numbers(&self) -> Result<Vec<u8>>;
     fn get_active_configuration(&self) -> Result<ConfigDescriptor>;
+    fn claim_interface(&self, interface_number: u8) -> Result;
+    fn release_interface(&self, interface_number: u8) -> Result;
+    fn set_interface_alt_setting(&self, interface_number: u8, alt_setting: u8) -> Result;
+    fn kernel_driver_active(&self, interface_number: u8) -> Result<bool>;
+    fn detach_kernel_driver(&self, interface_number: u8) -> Result;
+    fn attach_kernel_driver(&self, interface_number: u8) -> Result;
+    /// `endpoint` is the address including the direction bit.
+    fn clear_halt(&self, endpoint: u8) -> Result;
     fn control_transfer_in(&self, control_transfer_request: ControlTransferRequest, capacity: usize) -> AsyncResult<Vec<u8>>;
     fn control_transfer_out(&self, control_transfer_request: ControlTransferRequest, data: &[u8], ) -> AsyncResult<usize>;
     fn bulk_transfer_in(&self, endpoint: u8, capacity: usize, timeout: Duration) ->AsyncResult<Vec<u8>>;
     fn bulk_transfer_out(&self, endpoint: u8, data: &[u8], timeout: Duration)->AsyncResult<usize>;
+    /// Read into `buf`, handing it back along with the received length or the error.
+    fn bulk_transfer_in_buf(&self, endpoint: u8, buf: Vec<u8>, timeout: Duration) -> AsyncBufResult;
+    /// Send the first `len` bytes of `data` without copying them, handing it
+    /// back along with the sent length or the error.
+    fn bulk_transfer_out_buf(&self, endpoint: u8, data: Vec<u8>, len: usize, timeout: Duration) -> AsyncBufResult;
+    /// Returns the number of streams allocated on each of `endpoints`.
+    fn alloc_streams(&self, num_streams: u32, endpoints: &[u8]) -> Result<u32>;
+    fn free_streams(&self, endpoints: &[u8]) -> Result;
+    fn bulk_stream_transfer_in(&self, endpoint: u8, stream_id: u32, capacity: usize, timeout: Duration) ->AsyncResult<Vec<u8>>;
+    fn bulk_stream_transfer_out(&self, endpoint: u8, stream_id: u32, data: &[u8], timeout: Duration)->AsyncResult<usize>;
     fn interrupt_transfer_in(&self, endpoint: u8, capacity: usize, timeout: Duration) ->AsyncResult<Vec<u8>>;
     fn interrupt_transfer_out(&self, endpoint: u8, data: &[u8], timeout: Duration)->AsyncResult<usize>;
     fn iso_transfer_in(&self, endpoint: u8, num_iso_packages: usize, package_capacity: usize, timeout: Duration) ->AsyncResult<Vec<Vec<u8>>>;
-    fn iso_transfer_out(&self, endpoint: u8, packs: Vec<Vec<u8>>, timeout: Duration)->AsyncResult<Vec<usize>>;
-    fn bulk_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig)->Result<EndpointPipInImpl>;
+    fn iso_transfer_out(&self, endpoint: u8, packs: Vec<Vec<u8>>, timeout: Duration)->AsyncResult<Vec<IsoPacketResult>>;
+    fn bulk_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig)->Result<Box<dyn EndpointPipInInner>>;
+    fn interrupt_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig)->Result<Box<dyn EndpointPipInInner>>;
+    fn iso_transfer_pip_in(&self, endpoint: u8, num_iso_packages: usize, pip_config: PipConfig)->Result<Box<dyn EndpointPipInInner<Vec<IsoPacket>>>>;
+    fn bulk_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig)->Result<Box<dyn EndpointPipOutInner>>;
+    fn iso_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig)->Result<Box<dyn EndpointPipOutInner<Vec<Vec<u8>>>>>;
 }
 
-pub(crate) trait ManagerCtx {
-    fn new() -> Self;
-    fn device_list(&self) -> Result<Vec<UsbDevice>>;
-    fn open_device_with_vid_pid(&self, vid: u16, pid: u16) -> Result<UsbDevice>;
+/// Backend of a [`UsbContext`](crate::context::UsbContext). Devices and
+/// hotplug registrations keep a reference to it.
+pub(crate) trait ManagerCtx: Send + Sync {
+    fn new(builder: &UsbContextBuilder) -> Result<Self> where Self: Sized;
+    fn device_list(self: Arc<Self>) -> Result<Vec<UsbDevice>>;
+    fn open_device_with_vid_pid(self: Arc<Self>, vid: u16, pid: u16) -> Result<UsbDevice>;
+    #[cfg(unix)]
+    fn open_device_with_fd(self: Arc<Self>, fd: RawFd)->Result<UsbDevice>;
+    fn hotplug(self: Arc<Self>, filter: HotplugFilter) -> Result<HotplugStream>;
+    fn handle_events(&self, timeout: Duration) -> Result;
+    /// Told by the backend whenever its descriptors change.
+    #[cfg(unix)]
+    fn pollfd_watch(&self) -> Arc<PollfdWatch>;
+    #[cfg(unix)]
+    fn pollfds(&self) -> Result<Vec<PollFd>>;
+    /// Time until the next transfer timeout to handle, if any.
     #[cfg(unix)]
-    fn open_device_with_fd(&self, fd: RawFd)->Result<UsbDevice>;
-    fn close(&self);
+    fn next_timeout(&self) -> Result<Option<Duration>>;
+    /// The mock backend behind this context, to plug virtual devices into.
+    #[cfg(mock)]
+    fn as_mock(self: Arc<Self>) -> Option<Arc<mock::manager::ManagerCtxImpl>> {
+        None
+    }
 }
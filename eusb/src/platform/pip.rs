@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use log::warn;
+use crate::buffer::{BufferPool, PooledBuffer};
+use crate::define::{OverflowPolicy, PipConfig, StallPolicy};
+use crate::error::*;
+
+/// Buffers received by an IN pipe, waiting for the consumer.
+///
+/// Shared by the pip transfers of a backend. `T` is what a backend needs to
+/// resubmit a transfer paused by [`OverflowPolicy::Block`], `I` is what one
+/// completed transfer yields.
+pub(crate) struct PipQueue<T, I = PooledBuffer> {
+    inner: Mutex<PipQueueInner<T, I>>,
+    position: AtomicU64,
+    pool: BufferPool,
+}
+
+/// Frees the transfers of a dropped pipe, see [`PipQueue::when_idle`].
+pub(crate) type OnIdle = Box<dyn FnOnce() + Send>;
+
+/// Result of [`PipQueue::poll_next`] and the parked transfers to resubmit.
+pub(crate) type PollNext<T, I = PooledBuffer> = (Poll<Option<Result<I>>>, Vec<T>);
+
+pub(crate) enum Pushed<T> {
+    /// Submit the transfer again.
+    Resubmit(T),
+    /// The queue keeps the transfer until the consumer makes room.
+    Parked,
+    /// The pipe is closed or failed, the transfer must not be resubmitted.
+    Stopped,
+}
+
+struct PipQueueInner<T, I> {
+    buf: VecDeque<I>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: u64,
+    parked: Vec<T>,
+    /// Transfers in flight or parked.
+    active: usize,
+    /// Runs once `active` drops to 0.
+    on_idle: Option<OnIdle>,
+    closed: bool,
+    /// First error, handed to the consumer once after the buffered data.
+    error: Option<Error>,
+    error_reported: bool,
+    stall: StallPolicy,
+    /// Stalls since the last completed transfer.
+    stalls: u32,
+    waker: Option<Waker>,
+}
+
+impl<T, I> PipQueue<T, I> {
+    pub fn new(config: &PipConfig) -> Self {
+        Self {
+            inner: Mutex::new(PipQueueInner {
+                buf: VecDeque::with_capacity(config.cache_size),
+                capacity: config.cache_size.max(1),
+                policy: config.overflow,
+                dropped: 0,
+                parked: vec![],
+                active: config.request_num,
+                on_idle: None,
+                closed: false,
+                error: None,
+                error_reported: false,
+                stall: config.stall,
+                stalls: 0,
+                waker: None,
+            }),
+            position: AtomicU64::new(0),
+            pool: config.buffer_pool.clone().unwrap_or_else(|| {
+                BufferPool::new(config.package_size, config.cache_size + config.request_num)
+            }),
+        }
+    }
+
+    /// Where the backend takes the buffers of its transfers from.
+    pub fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    /// Reserve `count` consecutive positions in the stream, returns the first.
+    ///
+    /// Called by the completion callbacks, which run in the order the
+    /// transfers complete.
+    pub fn next_position(&self, count: u64) -> u64 {
+        self.position.fetch_add(count, Ordering::Relaxed)
+    }
+
+    /// Hand over the data of a completed transfer.
+    pub fn push(&self, endpoint: u8, data: I, transfer: T) -> Pushed<T> {
+        let mut g = self.inner.lock().unwrap();
+        if g.closed || g.error.is_some() {
+            Self::stopped(g);
+            return Pushed::Stopped;
+        }
+        g.stalls = 0;
+        let mut next = Pushed::Resubmit(transfer);
+        if g.buf.len() < g.capacity {
+            g.buf.push_back(data);
+        } else {
+            match g.policy {
+                OverflowPolicy::DropNewest => {
+                    warn!("ep[{}] overflow", endpoint);
+                    g.dropped += 1;
+                }
+                OverflowPolicy::DropOldest => {
+                    warn!("ep[{}] overflow", endpoint);
+                    g.dropped += 1;
+                    g.buf.pop_front();
+                    g.buf.push_back(data);
+                }
+                OverflowPolicy::Block => {
+                    g.buf.push_back(data);
+                    if let Pushed::Resubmit(transfer) = std::mem::replace(&mut next, Pushed::Parked) {
+                        g.parked.push(transfer);
+                    }
+                }
+                OverflowPolicy::Error => {
+                    warn!("ep[{}] overflow", endpoint);
+                    g.dropped += 1;
+                    g.error = Some(Error::Overflow);
+                    next = Pushed::Stopped;
+                }
+            }
+        }
+        if let Some(w) = g.waker.take() {
+            w.wake();
+        }
+        if matches!(next, Pushed::Stopped) {
+            Self::stopped(g);
+        }
+        next
+    }
+
+    /// A transfer found the endpoint stalled. Clears the halt with
+    /// `clear_halt` as the [`StallPolicy`] says, returns whether to resubmit
+    /// the transfer. Otherwise the transfer is stopped and the pipe fails with
+    /// `stall`, also when clearing the halt failed.
+    pub fn stalled(&self, stall: Error, clear_halt: impl FnOnce() -> Result) -> bool {
+        let retries = {
+            let mut g = self.inner.lock().unwrap();
+            g.stalls = g.stalls.saturating_add(1);
+            match g.stall {
+                StallPolicy::Fail => None,
+                StallPolicy::ClearHaltAndRetry(retries) => Some((g.stalls, retries)),
+            }
+        };
+        match retries {
+            Some((stalls, retries)) => {
+                if let Err(e) = clear_halt() {
+                    warn!("clear halt: {}", e);
+                    self.stop_with(stall);
+                    return false;
+                }
+                if stalls > retries {
+                    self.stop_with(stall);
+                    return false;
+                }
+                true
+            }
+            None => {
+                self.stop_with(stall);
+                false
+            }
+        }
+    }
+
+    /// A transfer stopped for good.
+    pub fn stop(&self) {
+        let mut g = self.inner.lock().unwrap();
+        if let Some(w) = g.waker.take() {
+            w.wake();
+        }
+        Self::stopped(g);
+    }
+
+    /// A transfer failed, which ends the pipe.
+    pub fn stop_with(&self, error: Error) {
+        let mut g = self.inner.lock().unwrap();
+        if !g.closed {
+            g.error.get_or_insert(error);
+        }
+        if let Some(w) = g.waker.take() {
+            w.wake();
+        }
+        Self::stopped(g);
+    }
+
+    /// Count a transfer out and run the [`when_idle`](Self::when_idle) hook
+    /// if it was the last one. The caller must not touch its transfer after.
+    fn stopped(mut g: MutexGuard<'_, PipQueueInner<T, I>>) {
+        g.active -= 1;
+        let on_idle = if g.active == 0 { g.on_idle.take() } else { None };
+        drop(g);
+        if let Some(f) = on_idle {
+            f();
+        }
+    }
+
+    /// Run `f` once no transfer is in flight or parked, so the backend can
+    /// free them. Runs right away if none is, otherwise in the callback of the
+    /// last transfer to stop.
+    #[allow(unused)]
+    pub fn when_idle(&self, f: OnIdle) {
+        let mut g = self.inner.lock().unwrap();
+        if g.active > 0 {
+            g.on_idle = Some(f);
+            return;
+        }
+        drop(g);
+        f();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().unwrap().closed
+    }
+
+    /// The consumer is gone. Returns the parked transfers, which are stopped.
+    pub fn close(&self) -> Vec<T> {
+        let mut g = self.inner.lock().unwrap();
+        g.closed = true;
+        g.buf.clear();
+        let parked = std::mem::take(&mut g.parked);
+        g.active -= parked.len();
+        parked
+    }
+
+    /// Next buffer, along with the parked transfers to resubmit now that there is room.
+    pub fn poll_next(&self, cx: &mut Context<'_>) -> PollNext<T, I> {
+        let mut g = self.inner.lock().unwrap();
+        match g.buf.pop_front() {
+            Some(data) => {
+                let resume = if g.buf.len() < g.capacity && g.error.is_none() {
+                    std::mem::take(&mut g.parked)
+                } else {
+                    vec![]
+                };
+                (Poll::Ready(Some(Ok(data))), resume)
+            }
+            None if g.error.is_some() && !g.error_reported => {
+                g.error_reported = true;
+                (Poll::Ready(g.error.clone().map(Err)), vec![])
+            }
+            None if g.active == 0 || g.error.is_some() => (Poll::Ready(None), vec![]),
+            None => {
+                g.waker = Some(cx.waker().clone());
+                (Poll::Pending, vec![])
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.inner.lock().unwrap().dropped
+    }
+
+    pub fn error(&self) -> Option<Error> {
+        self.inner.lock().unwrap().error.clone()
+    }
+}
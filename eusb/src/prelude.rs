@@ -1,10 +1,18 @@
+pub use crate::buffer::{BufferPool, PooledBuffer};
+pub use crate::context::{EventThreadPriority, UsbContext, UsbContextBuilder};
 pub use crate::device::UsbDevice;
-pub use crate::endpoint::EndpointPipIn;
+pub use crate::endpoint::{EndpointIsoPipIn, EndpointIsoPipOut, EndpointPipIn, EndpointPipOut};
+pub use crate::filter::DeviceFilter;
+pub use crate::hotplug::{HotplugEvent, HotplugFilter, HotplugStream};
+pub use crate::interface::{ClaimedInterface, Endpoint};
+pub use crate::streams::BulkStreams;
+pub use crate::standard::{DescriptorType, StandardFeature};
 pub use crate::define::*;
 
 #[cfg(test)]
 mod tests {
     use log::*;
+    #[cfg(libusb)]
     use tokio::time::Instant;
     use super::*;
     use crate::utils::test::init;
@@ -28,6 +36,7 @@ mod tests {
     }
 
 
+    #[cfg(libusb)]
     #[tokio::test]
     async fn test_hackrf() {
         init();
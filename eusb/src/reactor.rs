@@ -0,0 +1,278 @@
+//! Handle libusb events on an async runtime instead of an event thread.
+//!
+//! Build the context [`without_event_thread`](crate::context::UsbContextBuilder::without_event_thread)
+//! and run the future of [`UsbContext::drive_events`] on the runtime. It
+//! watches the file descriptors of libusb through a [`Reactor`] and handles
+//! events whenever one of them is ready or a transfer timeout expires.
+//!
+//! Dropping a pipe does not block, its transfers are freed once the driver
+//! handled their cancellation. The context stays alive until then.
+//!
+//! ```ignore
+//! let ctx = UsbContext::builder().without_event_thread().build()?;
+//! tokio::spawn(ctx.drive_events(TokioReactor));
+//! let device = ctx.open_with_vid_pid(0x1D50, 0x6089)?;
+//! ```
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::task::AtomicWaker;
+use crate::context::UsbContext;
+use crate::error::*;
+use crate::platform::ManagerCtx;
+
+/// A file descriptor libusb waits on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PollFd {
+    pub fd: RawFd,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// A file descriptor registered with a [`Reactor`], deregistered when dropped.
+pub trait AsyncFdRegistration: Send {
+    /// Ready once the descriptor became readable or writable, as registered,
+    /// since the last time it was ready.
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// The part of an async runtime [`UsbContext::drive_events`] needs, like
+/// tokio's `AsyncFd` and `sleep`.
+pub trait Reactor: Send + Unpin + 'static {
+    fn register(&self, fd: PollFd) -> io::Result<Box<dyn AsyncFdRegistration>>;
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output=()> + Send>>;
+}
+
+type Registrations = HashMap<RawFd, (PollFd, Box<dyn AsyncFdRegistration>)>;
+
+/// Follows the file descriptors of a context for its [`EventDriver`].
+#[derive(Default)]
+pub(crate) struct PollfdWatch {
+    changed: AtomicBool,
+    waker: AtomicWaker,
+    /// Registrations of the driver. One is dropped as soon as libusb removes
+    /// its descriptor, before the number can be reused.
+    registered: Mutex<Registrations>,
+}
+
+#[allow(unused)]
+impl PollfdWatch {
+    pub fn added(&self) {
+        self.notify();
+    }
+
+    pub fn removed(&self, fd: RawFd) {
+        let registration = self.registered.lock().unwrap().remove(&fd);
+        drop(registration);
+        self.notify();
+    }
+
+    /// Have the driver check the descriptors of the context again and handle
+    /// its events.
+    pub fn notify(&self) {
+        self.changed.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// Handles the events of a context on an async runtime, see the
+/// [module documentation](self).
+///
+/// Completes once the context is shut down, which it does not prevent, or
+/// fails with the first error.
+#[must_use = "futures do nothing unless polled"]
+pub struct EventDriver<R> {
+    manager: Weak<dyn ManagerCtx>,
+    watch: Arc<PollfdWatch>,
+    reactor: R,
+    sleep: Option<Pin<Box<dyn Future<Output=()> + Send>>>,
+    /// Handle events on the next poll.
+    pending: bool,
+}
+
+impl UsbContext {
+    /// A future handling the events of this context on an async runtime.
+    /// Only one should run at a time.
+    pub fn drive_events<R: Reactor>(&self, reactor: R) -> EventDriver<R> {
+        let watch = self.platform.pollfd_watch();
+        watch.notify();
+        EventDriver {
+            manager: Arc::downgrade(&self.platform),
+            watch,
+            reactor,
+            sleep: None,
+            pending: true,
+        }
+    }
+}
+
+impl<R: Reactor> EventDriver<R> {
+    fn sync(&self, manager: &dyn ManagerCtx) -> Result {
+        let fds = manager.pollfds()?;
+        let mut g = self.watch.registered.lock().unwrap();
+        g.retain(|fd, (o, _)| fds.iter().any(|n| n.fd == *fd && n == o));
+        for fd in fds {
+            if let Entry::Vacant(e) = g.entry(fd.fd) {
+                e.insert((fd, self.reactor.register(fd)?));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Reactor> Future for EventDriver<R> {
+    type Output = Result;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.watch.waker.register(cx.waker());
+        loop {
+            let manager = match this.manager.upgrade() {
+                Some(manager) => manager,
+                None => return Poll::Ready(Ok(())),
+            };
+            if this.watch.changed.swap(false, Ordering::AcqRel) {
+                this.sync(manager.as_ref())?;
+                this.pending = true;
+            }
+
+            let mut ready = std::mem::take(&mut this.pending);
+            for (_, registration) in this.watch.registered.lock().unwrap().values_mut() {
+                if let Poll::Ready(r) = registration.poll_ready(cx) {
+                    r?;
+                    ready = true;
+                }
+            }
+            if let Some(sleep) = this.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_ready() {
+                    this.sleep = None;
+                    ready = true;
+                }
+            }
+            if !ready {
+                return Poll::Pending;
+            }
+
+            manager.handle_events(Duration::ZERO)?;
+            this.sleep = manager.next_timeout()?.map(|o| this.reactor.sleep(o));
+        }
+    }
+}
+
+/// [`Reactor`] of the current tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioReactor;
+
+#[cfg(feature = "tokio")]
+struct TokioFd {
+    fd: tokio::io::unix::AsyncFd<RawFd>,
+    readable: bool,
+    writable: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncFdRegistration for TokioFd {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.readable {
+            if let Poll::Ready(guard) = self.fd.poll_read_ready(cx) {
+                guard?.clear_ready();
+                return Poll::Ready(Ok(()));
+            }
+        }
+        if self.writable {
+            if let Poll::Ready(guard) = self.fd.poll_write_ready(cx) {
+                guard?.clear_ready();
+                return Poll::Ready(Ok(()));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Reactor for TokioReactor {
+    fn register(&self, fd: PollFd) -> io::Result<Box<dyn AsyncFdRegistration>> {
+        use tokio::io::Interest;
+
+        let interest = match (fd.readable, fd.writable) {
+            (true, true) => Interest::READABLE | Interest::WRITABLE,
+            (false, true) => Interest::WRITABLE,
+            _ => Interest::READABLE,
+        };
+        // SAFETY: libusb keeps the descriptor open until it is removed, which
+        // drops the registration first.
+        let async_fd = unsafe { tokio::io::unix::AsyncFd::register_with_interest(fd.fd, interest)? };
+        Ok(Box::new(TokioFd {
+            fd: async_fd,
+            readable: fd.readable,
+            writable: fd.writable,
+        }))
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output=()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::define::*;
+    use crate::mock::{MockDevice, MockResponse};
+    use super::*;
+
+    /// The mock has no descriptors, only timers are needed.
+    struct TimerOnly;
+
+    impl Reactor for TimerOnly {
+        fn register(&self, _fd: PollFd) -> io::Result<Box<dyn AsyncFdRegistration>> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output=()> + Send>> {
+            Box::pin(tokio::time::sleep(duration))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_driver() {
+        let ctx = UsbContext::builder().with_mock().without_event_thread().build().unwrap();
+        let driver = tokio::spawn(ctx.drive_events(TimerOnly));
+        let mock = MockDevice::new(0xA00A, 4).plug_into(&ctx);
+        let device = ctx.open_with_vid_pid(0xA00A, 4).unwrap();
+
+        mock.push(0x80, MockResponse::Data(vec![1]));
+        let data = device.control_transfer_in(ControlTransferRequest::default(), 1).await.unwrap();
+        assert_eq!(data, vec![1]);
+        assert!(!driver.is_finished());
+
+        drop(device);
+        drop(ctx);
+        driver.await.unwrap().unwrap();
+    }
+    #[tokio::test]
+    async fn test_completion_needs_driver() {
+        let ctx = UsbContext::builder().with_mock().without_event_thread().build().unwrap();
+        let mock = MockDevice::new(0xA00A, 5).plug_into(&ctx);
+        let device = ctx.open_with_vid_pid(0xA00A, 5).unwrap();
+
+        mock.push(0x80, MockResponse::Data(vec![2]));
+        let mut transfer = Box::pin(device.control_transfer_in(ControlTransferRequest::default(), 1));
+        let timeout = tokio::time::timeout(Duration::from_millis(20), transfer.as_mut()).await;
+        assert!(timeout.is_err());
+
+        let driver = tokio::spawn(ctx.drive_events(TimerOnly));
+        assert_eq!(transfer.await.unwrap(), vec![2]);
+
+        drop(device);
+        drop(ctx);
+        driver.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,983 @@
+//! Record the transfers of a device and replay them without hardware.
+//!
+//! Enabled by the `record` feature. [`UsbDevice::record`](crate::UsbDevice::record)
+//! wraps a device so every control, bulk, interrupt and isochronous transfer
+//! issued through it is appended to a file, one JSON object per line after a
+//! header describing the device. [`UsbDevice::replay`](crate::UsbDevice::replay)
+//! loads such a file and answers the same calls from it.
+//!
+//! Replay serves the recorded transfers of each endpoint in order. Control
+//! transfers must match the recorded setup packet, OUT payloads are not compared,
+//! and recorded timing is informational only.
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::buffer::PooledBuffer;
+use crate::define::*;
+use crate::error::*;
+use crate::platform::{AsyncBufResult, AsyncResult, DeviceCtx, EndpointPipInInner, EndpointPipOutInner, TransferDone};
+
+/// Identity of the recorded device, stored on the first line of a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    pub descriptor: DeviceDescriptor,
+    pub device_class: DeviceClass,
+    pub device_subclass: DeviceClass,
+    pub device_protocol: DeviceClass,
+    pub configs: Vec<ConfigDescriptor>,
+    pub active_config: Option<u8>,
+    pub strings: HashMap<u8, String>,
+    pub bus_number: u8,
+    pub device_address: u8,
+    #[serde(default)]
+    pub port_numbers: Vec<u8>,
+}
+
+/// One completed transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRecord {
+    /// Microseconds from the start of the recording to the submission.
+    pub start_us: u64,
+    pub duration_us: u64,
+    pub transfer_type: EndpointTransferType,
+    pub direction: Direction,
+    /// Endpoint address including the direction bit, `0x80`/`0x00` for control.
+    pub endpoint: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub setup: Option<ControlSetup>,
+    /// Stream of a USB 3 bulk stream transfer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<u32>,
+    /// Requested length of an IN transfer.
+    pub length: usize,
+    /// Sent or received payload, one entry per packet for isochronous transfers.
+    pub data: Vec<Vec<u8>>,
+    /// Bytes accepted by the device for OUT transfers, one entry per packet.
+    pub actual_lengths: Vec<usize>,
+    pub status: std::result::Result<(), Error>,
+    /// Status of each packet received by an isochronous pipe.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packet_status: Vec<std::result::Result<(), Error>>,
+}
+
+/// Content of a recording file.
+pub struct Session {
+    pub device: DeviceRecord,
+    pub transfers: Vec<TransferRecord>,
+}
+
+impl Session {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).map_err(io_err)?;
+        let mut lines = BufReader::new(file).lines();
+        let header = lines.next()
+            .ok_or_else(|| Error::Other("empty recording".to_string()))?
+            .map_err(io_err)?;
+        let device = serde_json::from_str(&header).map_err(parse_err)?;
+        let mut transfers = vec![];
+        for line in lines {
+            let line = line.map_err(io_err)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            transfers.push(serde_json::from_str(&line).map_err(parse_err)?);
+        }
+        Ok(Self { device, transfers })
+    }
+}
+
+fn io_err(e: std::io::Error) -> Error {
+    Error::Io(e.to_string())
+}
+
+fn parse_err(e: serde_json::Error) -> Error {
+    Error::Other(format!("invalid recording: {}", e))
+}
+
+/// Lines go to a writer thread, transfers never wait on the file.
+struct RecordLog {
+    start: Instant,
+    tx: Option<Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+fn write_lines(file: File, rx: Receiver<Vec<u8>>) {
+    let mut w = BufWriter::new(file);
+    while let Ok(line) = rx.recv() {
+        let r = std::iter::once(line).chain(rx.try_iter())
+            .try_for_each(|o| w.write_all(&o))
+            .and_then(|_| w.flush());
+        if let Err(e) = r {
+            warn!("write recording fail: {}", e);
+        }
+    }
+}
+
+impl RecordLog {
+    fn new(file: File) -> Result<Self> {
+        let (tx, rx) = channel();
+        let writer = std::thread::Builder::new()
+            .name("USB record".into())
+            .spawn(move || write_lines(file, rx))
+            .map_err(io_err)?;
+        Ok(Self {
+            start: Instant::now(),
+            tx: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    fn begin(&self, transfer_type: EndpointTransferType, endpoint: u8) -> TransferRecord {
+        TransferRecord {
+            start_us: self.start.elapsed().as_micros() as _,
+            duration_us: 0,
+            transfer_type,
+            direction: if endpoint & 0x80 == 0x80 { Direction::In } else { Direction::Out },
+            endpoint,
+            setup: None,
+            stream_id: None,
+            length: 0,
+            data: vec![],
+            actual_lengths: vec![],
+            status: Ok(()),
+            packet_status: vec![],
+        }
+    }
+
+    fn write<T: Serialize>(&self, line: &T) {
+        let mut buf = match serde_json::to_vec(line) {
+            Ok(buf) => buf,
+            Err(e) => {
+                warn!("write recording fail: {}", e);
+                return;
+            }
+        };
+        buf.push(b'\n');
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(buf);
+        }
+    }
+
+    fn finish(&self, mut record: TransferRecord) {
+        let start = Duration::from_micros(record.start_us);
+        record.duration_us = self.start.elapsed().saturating_sub(start).as_micros() as _;
+        self.write(&record);
+    }
+
+    fn track<T: 'static>(
+        self: &Arc<Self>,
+        mut record: TransferRecord,
+        f: AsyncResult<T>,
+        fill: impl FnOnce(&mut TransferRecord, &T) + 'static,
+    ) -> AsyncResult<T> {
+        let log = self.clone();
+        Box::pin(async move {
+            let r = f.await;
+            match &r {
+                Ok(v) => fill(&mut record, v),
+                Err(e) => record.status = Err(e.clone()),
+            }
+            log.finish(record);
+            r
+        })
+    }
+
+    fn track_buf(
+        self: &Arc<Self>,
+        mut record: TransferRecord,
+        f: AsyncBufResult,
+        fill: impl FnOnce(&mut TransferRecord, &[u8], usize) + 'static,
+    ) -> AsyncBufResult {
+        let log = self.clone();
+        Box::pin(async move {
+            let (buf, r) = f.await;
+            match &r {
+                Ok(n) => fill(&mut record, &buf, *n),
+                Err(e) => record.status = Err(e.clone()),
+            }
+            log.finish(record);
+            (buf, r)
+        })
+    }
+}
+
+impl Drop for RecordLog {
+    /// The recording is complete once the device is dropped.
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+pub(crate) struct Recorder {
+    inner: Box<dyn DeviceCtx>,
+    log: Arc<RecordLog>,
+}
+
+impl Recorder {
+    pub(crate) fn new(inner: Box<dyn DeviceCtx>, path: &Path) -> Result<Self> {
+        let descriptor = inner.device_descriptor()?;
+        let mut strings = HashMap::new();
+        for index in [descriptor.iManufacturer, descriptor.iProduct, descriptor.iSerialNumber] {
+            if index == 0 {
+                continue;
+            }
+            if let Ok(s) = inner.get_string_ascii(index) {
+                strings.insert(index, s);
+            }
+        }
+        let device = DeviceRecord {
+            device_class: inner.device_class()?,
+            device_subclass: inner.device_subclass()?,
+            device_protocol: inner.device_protocol()?,
+            configs: inner.config_list().unwrap_or_default(),
+            active_config: inner.get_active_configuration().ok().map(|o| o.value),
+            strings,
+            bus_number: inner.bus_number(),
+            device_address: inner.device_address(),
+            port_numbers: inner.port_numbers().unwrap_or_default(),
+            descriptor,
+        };
+        let file = File::create(path).map_err(io_err)?;
+        let log = Arc::new(RecordLog::new(file)?);
+        log.write(&device);
+        Ok(Self { inner, log })
+    }
+}
+
+impl DeviceCtx for Recorder {
+    fn device_descriptor(&self) -> Result<DeviceDescriptor> {
+        self.inner.device_descriptor()
+    }
+
+    fn get_string_ascii(&self, index: u8) -> Result<String> {
+        self.inner.get_string_ascii(index)
+    }
+
+    fn device_class(&self) -> Result<DeviceClass> {
+        self.inner.device_class()
+    }
+
+    fn device_subclass(&self) -> Result<DeviceClass> {
+        self.inner.device_subclass()
+    }
+
+    fn device_protocol(&self) -> Result<DeviceClass> {
+        self.inner.device_protocol()
+    }
+
+    fn config_list(&self) -> Result<Vec<ConfigDescriptor>> {
+        self.inner.config_list()
+    }
+
+    fn set_config_by_value(&self, config_value: u8) -> Result {
+        self.inner.set_config_by_value(config_value)
+    }
+
+    fn serial_number(&self) -> Result<String> {
+        self.inner.serial_number()
+    }
+
+    fn bus_number(&self) -> u8 {
+        self.inner.bus_number()
+    }
+
+    fn device_address(&self) -> u8 {
+        self.inner.device_address()
+    }
+
+    fn port_numbers(&self) -> Result<Vec<u8>> {
+        self.inner.port_numbers()
+    }
+
+    fn get_active_configuration(&self) -> Result<ConfigDescriptor> {
+        self.inner.get_active_configuration()
+    }
+
+    fn claim_interface(&self, interface_number: u8) -> Result {
+        self.inner.claim_interface(interface_number)
+    }
+
+    fn release_interface(&self, interface_number: u8) -> Result {
+        self.inner.release_interface(interface_number)
+    }
+
+    fn set_interface_alt_setting(&self, interface_number: u8, alt_setting: u8) -> Result {
+        self.inner.set_interface_alt_setting(interface_number, alt_setting)
+    }
+
+    fn kernel_driver_active(&self, interface_number: u8) -> Result<bool> {
+        self.inner.kernel_driver_active(interface_number)
+    }
+
+    fn detach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.inner.detach_kernel_driver(interface_number)
+    }
+
+    fn attach_kernel_driver(&self, interface_number: u8) -> Result {
+        self.inner.attach_kernel_driver(interface_number)
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result {
+        self.inner.clear_halt(endpoint)
+    }
+
+    fn control_transfer_in(&self, control_transfer_request: ControlTransferRequest, capacity: usize) -> AsyncResult<Vec<u8>> {
+        let mut record = self.log.begin(EndpointTransferType::Control, 0x80);
+        record.setup = Some(control_transfer_request.setup(Direction::In, capacity as _));
+        record.length = capacity;
+        let f = self.inner.control_transfer_in(control_transfer_request, capacity);
+        self.log.track(record, f, |record, data| record.data = vec![data.clone()])
+    }
+
+    fn control_transfer_out(&self, control_transfer_request: ControlTransferRequest, data: &[u8]) -> AsyncResult<usize> {
+        let mut record = self.log.begin(EndpointTransferType::Control, 0x00);
+        record.setup = Some(control_transfer_request.setup(Direction::Out, data.len() as _));
+        record.data = vec![data.to_vec()];
+        let f = self.inner.control_transfer_out(control_transfer_request, data);
+        self.log.track(record, f, |record, n| record.actual_lengths = vec![*n])
+    }
+
+    fn bulk_transfer_in(&self, endpoint: u8, capacity: usize, timeout: Duration) -> AsyncResult<Vec<u8>> {
+        let mut record = self.log.begin(EndpointTransferType::Bulk, endpoint | 0x80);
+        record.length = capacity;
+        let f = self.inner.bulk_transfer_in(endpoint, capacity, timeout);
+        self.log.track(record, f, |record, data| record.data = vec![data.clone()])
+    }
+
+    fn bulk_transfer_out(&self, endpoint: u8, data: &[u8], timeout: Duration) -> AsyncResult<usize> {
+        let mut record = self.log.begin(EndpointTransferType::Bulk, endpoint & 0x7F);
+        record.data = vec![data.to_vec()];
+        let f = self.inner.bulk_transfer_out(endpoint, data, timeout);
+        self.log.track(record, f, |record, n| record.actual_lengths = vec![*n])
+    }
+
+    fn bulk_transfer_in_buf(&self, endpoint: u8, buf: Vec<u8>, timeout: Duration) -> AsyncBufResult {
+        let mut record = self.log.begin(EndpointTransferType::Bulk, endpoint | 0x80);
+        record.length = buf.len();
+        let f = self.inner.bulk_transfer_in_buf(endpoint, buf, timeout);
+        self.log.track_buf(record, f, |record, buf, n| record.data = vec![buf[..n].to_vec()])
+    }
+
+    fn bulk_transfer_out_buf(&self, endpoint: u8, data: Vec<u8>, len: usize, timeout: Duration) -> AsyncBufResult {
+        let mut record = self.log.begin(EndpointTransferType::Bulk, endpoint & 0x7F);
+        record.data = vec![data[..len].to_vec()];
+        let f = self.inner.bulk_transfer_out_buf(endpoint, data, len, timeout);
+        self.log.track_buf(record, f, |record, _, n| record.actual_lengths = vec![n])
+    }
+
+    fn alloc_streams(&self, num_streams: u32, endpoints: &[u8]) -> Result<u32> {
+        self.inner.alloc_streams(num_streams, endpoints)
+    }
+
+    fn free_streams(&self, endpoints: &[u8]) -> Result {
+        self.inner.free_streams(endpoints)
+    }
+
+    fn bulk_stream_transfer_in(&self, endpoint: u8, stream_id: u32, capacity: usize, timeout: Duration) -> AsyncResult<Vec<u8>> {
+        let mut record = self.log.begin(EndpointTransferType::Bulk, endpoint | 0x80);
+        record.stream_id = Some(stream_id);
+        record.length = capacity;
+        let f = self.inner.bulk_stream_transfer_in(endpoint, stream_id, capacity, timeout);
+        self.log.track(record, f, |record, data| record.data = vec![data.clone()])
+    }
+
+    fn bulk_stream_transfer_out(&self, endpoint: u8, stream_id: u32, data: &[u8], timeout: Duration) -> AsyncResult<usize> {
+        let mut record = self.log.begin(EndpointTransferType::Bulk, endpoint & 0x7F);
+        record.stream_id = Some(stream_id);
+        record.data = vec![data.to_vec()];
+        let f = self.inner.bulk_stream_transfer_out(endpoint, stream_id, data, timeout);
+        self.log.track(record, f, |record, n| record.actual_lengths = vec![*n])
+    }
+
+    fn interrupt_transfer_in(&self, endpoint: u8, capacity: usize, timeout: Duration) -> AsyncResult<Vec<u8>> {
+        let mut record = self.log.begin(EndpointTransferType::Interrupt, endpoint | 0x80);
+        record.length = capacity;
+        let f = self.inner.interrupt_transfer_in(endpoint, capacity, timeout);
+        self.log.track(record, f, |record, data| record.data = vec![data.clone()])
+    }
+
+    fn interrupt_transfer_out(&self, endpoint: u8, data: &[u8], timeout: Duration) -> AsyncResult<usize> {
+        let mut record = self.log.begin(EndpointTransferType::Interrupt, endpoint & 0x7F);
+        record.data = vec![data.to_vec()];
+        let f = self.inner.interrupt_transfer_out(endpoint, data, timeout);
+        self.log.track(record, f, |record, n| record.actual_lengths = vec![*n])
+    }
+
+    fn iso_transfer_in(&self, endpoint: u8, num_iso_packages: usize, package_capacity: usize, timeout: Duration) -> AsyncResult<Vec<Vec<u8>>> {
+        let mut record = self.log.begin(EndpointTransferType::Isochronous, endpoint | 0x80);
+        record.length = num_iso_packages * package_capacity;
+        let f = self.inner.iso_transfer_in(endpoint, num_iso_packages, package_capacity, timeout);
+        self.log.track(record, f, |record, packs| record.data = packs.clone())
+    }
+
+    fn iso_transfer_out(&self, endpoint: u8, packs: Vec<Vec<u8>>, timeout: Duration) -> AsyncResult<Vec<IsoPacketResult>> {
+        let mut record = self.log.begin(EndpointTransferType::Isochronous, endpoint & 0x7F);
+        record.data = packs.clone();
+        let f = self.inner.iso_transfer_out(endpoint, packs, timeout);
+        self.log.track(record, f, |record, packets| {
+            record.actual_lengths = packets.iter().map(|o| o.actual_length).collect();
+            record.packet_status = packets.iter().map(|o| o.status.clone()).collect();
+        })
+    }
+
+    fn bulk_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
+        let length = pip_config.package_size;
+        let inner = self.inner.bulk_transfer_pip_in(endpoint, pip_config)?;
+        Ok(Box::new(RecordPipIn {
+            inner,
+            log: self.log.clone(),
+            transfer_type: EndpointTransferType::Bulk,
+            endpoint: endpoint | 0x80,
+            length,
+            record: None,
+        }))
+    }
+
+    fn interrupt_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
+        let length = pip_config.package_size;
+        let inner = self.inner.interrupt_transfer_pip_in(endpoint, pip_config)?;
+        Ok(Box::new(RecordPipIn {
+            inner,
+            log: self.log.clone(),
+            transfer_type: EndpointTransferType::Interrupt,
+            endpoint: endpoint | 0x80,
+            length,
+            record: None,
+        }))
+    }
+
+    fn iso_transfer_pip_in(&self, endpoint: u8, num_iso_packages: usize, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner<Vec<IsoPacket>>>> {
+        let length = num_iso_packages * pip_config.package_size;
+        let inner = self.inner.iso_transfer_pip_in(endpoint, num_iso_packages, pip_config)?;
+        Ok(Box::new(RecordPipIn {
+            inner,
+            log: self.log.clone(),
+            transfer_type: EndpointTransferType::Isochronous,
+            endpoint: endpoint | 0x80,
+            length,
+            record: None,
+        }))
+    }
+
+    fn bulk_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner>> {
+        let inner = self.inner.bulk_transfer_pip_out(endpoint, pip_config)?;
+        Ok(Box::new(RecordPipOut {
+            inner,
+            log: self.log.clone(),
+            transfer_type: EndpointTransferType::Bulk,
+            endpoint: endpoint & 0x7F,
+        }))
+    }
+
+    fn iso_transfer_pip_out(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner<Vec<Vec<u8>>>>> {
+        let inner = self.inner.iso_transfer_pip_out(endpoint, pip_config)?;
+        Ok(Box::new(RecordPipOut {
+            inner,
+            log: self.log.clone(),
+            transfer_type: EndpointTransferType::Isochronous,
+            endpoint: endpoint & 0x7F,
+        }))
+    }
+}
+
+/// How an item of an IN pipe is stored in a [`TransferRecord`].
+trait PipRecord: Sized + Send + 'static {
+    fn store(&self, record: &mut TransferRecord);
+    /// `position` counts the isochronous packets served so far.
+    fn load(record: TransferRecord, position: &mut u64) -> Self;
+}
+
+impl PipRecord for PooledBuffer {
+    fn store(&self, record: &mut TransferRecord) {
+        record.data = vec![self.to_vec()];
+    }
+
+    fn load(record: TransferRecord, _position: &mut u64) -> Self {
+        record.data.into_iter().next().unwrap_or_default().into()
+    }
+}
+
+impl PipRecord for Vec<IsoPacket> {
+    fn store(&self, record: &mut TransferRecord) {
+        record.data = self.iter().map(|o| o.data.clone()).collect();
+        record.packet_status = self.iter().map(|o| o.status.clone()).collect();
+    }
+
+    fn load(record: TransferRecord, position: &mut u64) -> Self {
+        let mut status = record.packet_status.into_iter();
+        record.data.into_iter()
+            .map(|data| {
+                let packet = IsoPacket {
+                    data,
+                    status: status.next().unwrap_or(Ok(())),
+                    position: *position,
+                };
+                *position += 1;
+                packet
+            })
+            .collect()
+    }
+}
+
+struct RecordPipIn<I = PooledBuffer> {
+    inner: Box<dyn EndpointPipInInner<I>>,
+    log: Arc<RecordLog>,
+    transfer_type: EndpointTransferType,
+    endpoint: u8,
+    length: usize,
+    /// Started when the consumer begins waiting for the next buffer.
+    record: Option<TransferRecord>,
+}
+
+impl<I: PipRecord> EndpointPipInInner<I> for RecordPipIn<I> {
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<I>>> {
+        let mut record = self.record.take().unwrap_or_else(|| {
+            let mut record = self.log.begin(self.transfer_type, self.endpoint);
+            record.length = self.length;
+            record
+        });
+        let r = self.inner.poll_next(cx);
+        match &r {
+            Poll::Pending => self.record = Some(record),
+            Poll::Ready(Some(item)) => {
+                match item {
+                    Ok(data) => data.store(&mut record),
+                    Err(e) => record.status = Err(e.clone()),
+                }
+                self.log.finish(record);
+            }
+            Poll::Ready(None) => {}
+        }
+        r
+    }
+
+    fn dropped(&self) -> u64 {
+        self.inner.dropped()
+    }
+
+    fn error(&self) -> Option<Error> {
+        self.inner.error()
+    }
+}
+
+/// How an item of an OUT pipe is stored in a [`TransferRecord`].
+trait PipOutRecord: Send + 'static {
+    fn store(&self, record: &mut TransferRecord);
+    /// Bytes to report when the recording has no length.
+    fn byte_len(&self) -> usize;
+}
+
+impl PipOutRecord for Vec<u8> {
+    fn store(&self, record: &mut TransferRecord) {
+        record.data = vec![self.clone()];
+    }
+
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl PipOutRecord for Vec<Vec<u8>> {
+    fn store(&self, record: &mut TransferRecord) {
+        record.data = self.clone();
+    }
+
+    fn byte_len(&self) -> usize {
+        self.iter().map(|o| o.len()).sum()
+    }
+}
+
+struct RecordPipOut<I = Vec<u8>> {
+    inner: Box<dyn EndpointPipOutInner<I>>,
+    log: Arc<RecordLog>,
+    transfer_type: EndpointTransferType,
+    endpoint: u8,
+}
+
+impl<I: PipOutRecord> EndpointPipOutInner<I> for RecordPipOut<I> {
+    fn submit(&mut self, data: I, done: TransferDone) -> Result {
+        let mut record = self.log.begin(self.transfer_type, self.endpoint);
+        data.store(&mut record);
+        let log = self.log.clone();
+        self.inner.submit(data, Box::new(move |r| {
+            match &r {
+                Ok(n) => record.actual_lengths = vec![*n],
+                Err(e) => record.status = Err(e.clone()),
+            }
+            log.finish(record);
+            done(r);
+        }))
+    }
+}
+
+type ReplayQueues = Arc<Mutex<HashMap<u8, VecDeque<TransferRecord>>>>;
+
+pub(crate) struct Replay {
+    device: DeviceRecord,
+    active_config: Mutex<Option<u8>>,
+    queues: ReplayQueues,
+}
+
+impl From<Session> for Replay {
+    fn from(value: Session) -> Self {
+        let mut queues: HashMap<u8, VecDeque<TransferRecord>> = HashMap::new();
+        for record in value.transfers {
+            queues.entry(record.endpoint).or_default().push_back(record);
+        }
+        Self {
+            active_config: Mutex::new(value.device.active_config),
+            device: value.device,
+            queues: Arc::new(Mutex::new(queues)),
+        }
+    }
+}
+
+fn replay_next(queues: &ReplayQueues, endpoint: u8, setup: Option<ControlSetup>) -> Result<TransferRecord> {
+    let record = {
+        let mut g = queues.lock().unwrap();
+        let queue = g.get_mut(&endpoint)
+            .filter(|o| !o.is_empty())
+            .ok_or_else(|| Error::Other(format!("replay: no recorded transfer left on endpoint 0x{:02X}", endpoint)))?;
+        let expected = &queue[0].setup;
+        if setup.is_some() && *expected != setup {
+            return Err(Error::Other(format!("replay: expected setup {:?}, got {:?}", expected, setup)));
+        }
+        queue.pop_front().unwrap()
+    };
+    record.status.clone()?;
+    Ok(record)
+}
+
+impl Replay {
+    fn next(&self, endpoint: u8, setup: Option<ControlSetup>) -> Result<TransferRecord> {
+        replay_next(&self.queues, endpoint, setup)
+    }
+
+    fn data_in(&self, endpoint: u8, setup: Option<ControlSetup>) -> AsyncResult<Vec<u8>> {
+        let r = self.next(endpoint, setup)
+            .map(|o| o.data.into_iter().next().unwrap_or_default());
+        Box::pin(async move { r })
+    }
+
+    fn data_out(&self, endpoint: u8, setup: Option<ControlSetup>) -> AsyncResult<usize> {
+        let r = self.next(endpoint, setup)
+            .map(|o| o.actual_lengths.first().copied().unwrap_or_default());
+        Box::pin(async move { r })
+    }
+}
+
+impl DeviceCtx for Replay {
+    fn device_descriptor(&self) -> Result<DeviceDescriptor> {
+        Ok(self.device.descriptor.clone())
+    }
+
+    fn get_string_ascii(&self, index: u8) -> Result<String> {
+        self.device.strings.get(&index).cloned().ok_or(Error::NotFound)
+    }
+
+    fn device_class(&self) -> Result<DeviceClass> {
+        Ok(self.device.device_class)
+    }
+
+    fn device_subclass(&self) -> Result<DeviceClass> {
+        Ok(self.device.device_subclass)
+    }
+
+    fn device_protocol(&self) -> Result<DeviceClass> {
+        Ok(self.device.device_protocol)
+    }
+
+    fn config_list(&self) -> Result<Vec<ConfigDescriptor>> {
+        Ok(self.device.configs.clone())
+    }
+
+    fn set_config_by_value(&self, config_value: u8) -> Result {
+        if !self.device.configs.iter().any(|o| o.value == config_value) {
+            return Err(Error::NotFound);
+        }
+        *self.active_config.lock().unwrap() = Some(config_value);
+        Ok(())
+    }
+
+    fn serial_number(&self) -> Result<String> {
+        self.get_string_ascii(self.device.descriptor.iSerialNumber)
+    }
+
+    fn bus_number(&self) -> u8 {
+        self.device.bus_number
+    }
+
+    fn device_address(&self) -> u8 {
+        self.device.device_address
+    }
+
+    fn port_numbers(&self) -> Result<Vec<u8>> {
+        Ok(self.device.port_numbers.clone())
+    }
+
+    fn get_active_configuration(&self) -> Result<ConfigDescriptor> {
+        let value = (*self.active_config.lock().unwrap()).ok_or(Error::NotFound)?;
+        self.device.configs.iter()
+            .find(|o| o.value == value)
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn claim_interface(&self, _interface_number: u8) -> Result {
+        Ok(())
+    }
+
+    fn release_interface(&self, _interface_number: u8) -> Result {
+        Ok(())
+    }
+
+    fn set_interface_alt_setting(&self, _interface_number: u8, _alt_setting: u8) -> Result {
+        Ok(())
+    }
+
+    fn kernel_driver_active(&self, _interface_number: u8) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn detach_kernel_driver(&self, _interface_number: u8) -> Result {
+        Err(Error::NotFound)
+    }
+
+    fn attach_kernel_driver(&self, _interface_number: u8) -> Result {
+        Ok(())
+    }
+
+    fn clear_halt(&self, _endpoint: u8) -> Result {
+        Ok(())
+    }
+
+    fn control_transfer_in(&self, control_transfer_request: ControlTransferRequest, capacity: usize) -> AsyncResult<Vec<u8>> {
+        self.data_in(0x80, Some(control_transfer_request.setup(Direction::In, capacity as _)))
+    }
+
+    fn control_transfer_out(&self, control_transfer_request: ControlTransferRequest, data: &[u8]) -> AsyncResult<usize> {
+        self.data_out(0x00, Some(control_transfer_request.setup(Direction::Out, data.len() as _)))
+    }
+
+    fn bulk_transfer_in(&self, endpoint: u8, _capacity: usize, _timeout: Duration) -> AsyncResult<Vec<u8>> {
+        self.data_in(endpoint | 0x80, None)
+    }
+
+    fn bulk_transfer_out(&self, endpoint: u8, _data: &[u8], _timeout: Duration) -> AsyncResult<usize> {
+        self.data_out(endpoint & 0x7F, None)
+    }
+
+    fn bulk_transfer_in_buf(&self, endpoint: u8, mut buf: Vec<u8>, _timeout: Duration) -> AsyncBufResult {
+        let r = self.next(endpoint | 0x80, None).map(|o| {
+            let data = o.data.into_iter().next().unwrap_or_default();
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            n
+        });
+        Box::pin(async move { (buf, r) })
+    }
+
+    fn bulk_transfer_out_buf(&self, endpoint: u8, data: Vec<u8>, _len: usize, _timeout: Duration) -> AsyncBufResult {
+        let r = self.next(endpoint & 0x7F, None)
+            .map(|o| o.actual_lengths.first().copied().unwrap_or_default());
+        Box::pin(async move { (data, r) })
+    }
+
+    fn alloc_streams(&self, num_streams: u32, _endpoints: &[u8]) -> Result<u32> {
+        Ok(num_streams)
+    }
+
+    fn free_streams(&self, _endpoints: &[u8]) -> Result {
+        Ok(())
+    }
+
+    fn bulk_stream_transfer_in(&self, endpoint: u8, _stream_id: u32, _capacity: usize, _timeout: Duration) -> AsyncResult<Vec<u8>> {
+        self.data_in(endpoint | 0x80, None)
+    }
+
+    fn bulk_stream_transfer_out(&self, endpoint: u8, _stream_id: u32, _data: &[u8], _timeout: Duration) -> AsyncResult<usize> {
+        self.data_out(endpoint & 0x7F, None)
+    }
+
+    fn interrupt_transfer_in(&self, endpoint: u8, _capacity: usize, _timeout: Duration) -> AsyncResult<Vec<u8>> {
+        self.data_in(endpoint | 0x80, None)
+    }
+
+    fn interrupt_transfer_out(&self, endpoint: u8, _data: &[u8], _timeout: Duration) -> AsyncResult<usize> {
+        self.data_out(endpoint & 0x7F, None)
+    }
+
+    fn iso_transfer_in(&self, endpoint: u8, _num_iso_packages: usize, _package_capacity: usize, _timeout: Duration) -> AsyncResult<Vec<Vec<u8>>> {
+        let r = self.next(endpoint | 0x80, None).map(|o| o.data);
+        Box::pin(async move { r })
+    }
+
+    fn iso_transfer_out(&self, endpoint: u8, _packs: Vec<Vec<u8>>, _timeout: Duration) -> AsyncResult<Vec<IsoPacketResult>> {
+        let r = self.next(endpoint & 0x7F, None).map(|o| {
+            let mut status = o.packet_status.into_iter();
+            o.actual_lengths.into_iter()
+                .map(|actual_length| IsoPacketResult {
+                    actual_length,
+                    status: status.next().unwrap_or(Ok(())),
+                })
+                .collect()
+        });
+        Box::pin(async move { r })
+    }
+
+    fn bulk_transfer_pip_in(&self, endpoint: u8, _pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
+        Ok(Box::new(ReplayPipIn::new(&self.queues, endpoint)))
+    }
+
+    fn interrupt_transfer_pip_in(&self, endpoint: u8, pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner>> {
+        self.bulk_transfer_pip_in(endpoint, pip_config)
+    }
+
+    fn iso_transfer_pip_in(&self, endpoint: u8, _num_iso_packages: usize, _pip_config: PipConfig) -> Result<Box<dyn EndpointPipInInner<Vec<IsoPacket>>>> {
+        Ok(Box::new(ReplayPipIn::new(&self.queues, endpoint)))
+    }
+
+    fn bulk_transfer_pip_out(&self, endpoint: u8, _pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner>> {
+        Ok(Box::new(ReplayPipOut {
+            queues: self.queues.clone(),
+            endpoint: endpoint & 0x7F,
+        }))
+    }
+
+    fn iso_transfer_pip_out(&self, endpoint: u8, _pip_config: PipConfig) -> Result<Box<dyn EndpointPipOutInner<Vec<Vec<u8>>>>> {
+        Ok(Box::new(ReplayPipOut {
+            queues: self.queues.clone(),
+            endpoint: endpoint & 0x7F,
+        }))
+    }
+}
+
+struct ReplayPipIn<I> {
+    queues: ReplayQueues,
+    endpoint: u8,
+    error: Option<Error>,
+    position: u64,
+    _item: PhantomData<fn() -> I>,
+}
+
+impl<I> ReplayPipIn<I> {
+    fn new(queues: &ReplayQueues, endpoint: u8) -> Self {
+        Self {
+            queues: queues.clone(),
+            endpoint: endpoint | 0x80,
+            error: None,
+            position: 0,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<I: PipRecord> EndpointPipInInner<I> for ReplayPipIn<I> {
+    /// Serves the recorded buffers and ends after a recorded error or when
+    /// the recording runs out.
+    fn poll_next(&mut self, _cx: &mut Context<'_>) -> Poll<Option<Result<I>>> {
+        if self.error.is_some() {
+            return Poll::Ready(None);
+        }
+        let record = {
+            let mut g = self.queues.lock().unwrap();
+            g.get_mut(&self.endpoint).and_then(|o| o.pop_front())
+        };
+        Poll::Ready(record.map(|record| match record.status {
+            Ok(()) => Ok(I::load(record, &mut self.position)),
+            Err(e) => {
+                self.error = Some(e.clone());
+                Err(e)
+            }
+        }))
+    }
+
+    fn dropped(&self) -> u64 {
+        0
+    }
+
+    fn error(&self) -> Option<Error> {
+        self.error.clone()
+    }
+}
+
+struct ReplayPipOut {
+    queues: ReplayQueues,
+    endpoint: u8,
+}
+
+impl<I: PipOutRecord> EndpointPipOutInner<I> for ReplayPipOut {
+    fn submit(&mut self, data: I, done: TransferDone) -> Result {
+        let r = replay_next(&self.queues, self.endpoint, None)
+            .map(|o| o.actual_lengths.first().copied().unwrap_or(data.byte_len()));
+        done(r);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::mock::MockResponse;
+    use crate::mock::test::*;
+    use crate::prelude::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("eusb-record-{}.jsonl", std::process::id()));
+        let (mock, device) = open(mock_device().with_product("recorded"));
+        mock.push(0x80, MockResponse::Data(b"v1".to_vec()));
+        mock.push(0x02, MockResponse::Ack);
+        mock.push(0x81, MockResponse::Timeout);
+        mock.push(0x81, MockResponse::Data(vec![1; 8]));
+        mock.push(0x81, MockResponse::Data(vec![2; 8]));
+
+        let request = || ControlTransferRequest {
+            transfer_type: UsbControlTransferType::Vendor,
+            request: 15,
+            ..Default::default()
+        };
+        let timeout = Duration::from_secs(1);
+
+        {
+            let device = device.record(&path).unwrap();
+            assert_eq!(device.control_transfer_in(request(), 16).await.unwrap(), b"v1");
+            assert_eq!(device.bulk_transfer_out(2, &[9; 4], timeout).await.unwrap(), 4);
+            assert!(matches!(device.bulk_transfer_in(1, 8, timeout).await.unwrap_err().kind(), Error::Timeout));
+            let mut pip = device.bulk_transfer_pip_in(1, PipConfig { package_size: 8, request_num: 1, ..Default::default() }).unwrap();
+            assert_eq!(pip.next().await.unwrap().unwrap(), vec![1; 8]);
+            assert_eq!(pip.next().await.unwrap().unwrap(), vec![2; 8]);
+        }
+
+        let session = Session::load(&path).unwrap();
+        assert_eq!(session.device.descriptor.idVendor, 0x1D50);
+        assert_eq!(session.transfers.len(), 5);
+        assert_eq!(session.transfers[1].data, vec![vec![9; 4]]);
+
+        let device = UsbDevice::replay(&path).unwrap();
+        assert_eq!(device.product().unwrap(), "recorded");
+        let other = ControlTransferRequest { request: 16, ..request() };
+        assert!(device.control_transfer_in(other, 16).await.is_err());
+        assert_eq!(device.control_transfer_in(request(), 16).await.unwrap(), b"v1");
+        assert_eq!(device.bulk_transfer_out(2, &[9; 4], timeout).await.unwrap(), 4);
+        assert!(matches!(device.bulk_transfer_in(1, 8, timeout).await.unwrap_err().kind(), Error::Timeout));
+        let mut pip = device.bulk_transfer_pip_in(1, PipConfig::default()).unwrap();
+        assert_eq!(pip.next().await.unwrap().unwrap(), vec![1; 8]);
+        assert_eq!(pip.next().await.unwrap().unwrap(), vec![2; 8]);
+        assert!(pip.next().await.is_none());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
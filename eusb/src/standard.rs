@@ -0,0 +1,184 @@
+use std::time::Duration;
+use crate::define::*;
+use crate::device::UsbDevice;
+use crate::error::*;
+
+// Standard request codes, USB 2.0 table 9-4.
+const GET_STATUS: u8 = 0x00;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const GET_DESCRIPTOR: u8 = 0x06;
+const GET_CONFIGURATION: u8 = 0x08;
+const GET_INTERFACE: u8 = 0x0A;
+const SYNCH_FRAME: u8 = 0x0C;
+
+/// Feature selector of [`UsbDevice::clear_feature`] and [`UsbDevice::set_feature`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StandardFeature {
+    /// Halt of the endpoint with this address, including the direction bit.
+    EndpointHalt(u8),
+    DeviceRemoteWakeup,
+    /// Test mode with this selector, it can only be set.
+    TestMode(u8),
+}
+
+impl StandardFeature {
+    /// Recipient, wValue and wIndex of the request.
+    fn target(self) -> (UsbControlRecipient, u16, u16) {
+        match self {
+            StandardFeature::EndpointHalt(endpoint) => (UsbControlRecipient::Endpoint(endpoint), 0, 0),
+            StandardFeature::DeviceRemoteWakeup => (UsbControlRecipient::Device, 1, 0),
+            StandardFeature::TestMode(selector) => (UsbControlRecipient::Device, 2, (selector as u16) << 8),
+        }
+    }
+}
+
+/// Descriptor type of [`UsbDevice::get_descriptor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorType {
+    Device,
+    Configuration,
+    String,
+    Interface,
+    Endpoint,
+    DeviceQualifier,
+    OtherSpeedConfiguration,
+    Bos,
+    /// Class or vendor specific type.
+    Other(u8),
+}
+
+impl DescriptorType {
+    /// bDescriptorType
+    pub fn code(self) -> u8 {
+        match self {
+            DescriptorType::Device => 0x01,
+            DescriptorType::Configuration => 0x02,
+            DescriptorType::String => 0x03,
+            DescriptorType::Interface => 0x04,
+            DescriptorType::Endpoint => 0x05,
+            DescriptorType::DeviceQualifier => 0x06,
+            DescriptorType::OtherSpeedConfiguration => 0x07,
+            DescriptorType::Bos => 0x0F,
+            DescriptorType::Other(code) => code,
+        }
+    }
+}
+
+fn standard_request(recipient: UsbControlRecipient, request: u8, value: u16, index: u16, timeout: Duration) -> ControlTransferRequest {
+    ControlTransferRequest {
+        recipient,
+        transfer_type: UsbControlTransferType::Standard,
+        request,
+        value,
+        index,
+        timeout,
+    }
+}
+
+/// Check that the device answered with all `N` bytes.
+fn exact<const N: usize>(data: Vec<u8>) -> Result<[u8; N]> {
+    let len = data.len();
+    data.try_into().map_err(|_| Error::Io(format!("expected {} bytes, got {}", N, len)))
+}
+
+/// Standard requests of USB 2.0 chapter 9.
+impl UsbDevice {
+    /// GET_STATUS of the device, an interface or an endpoint.
+    pub async fn get_status(&self, recipient: UsbControlRecipient, timeout: Duration) -> Result<u16> {
+        let data = self.control_transfer_in(standard_request(recipient, GET_STATUS, 0, 0, timeout), 2).await?;
+        Ok(u16::from_le_bytes(exact(data)?))
+    }
+
+    /// CLEAR_FEATURE. Clearing [`StandardFeature::EndpointHalt`] this way does
+    /// not reset the data toggle kept by the host.
+    pub async fn clear_feature(&self, feature: StandardFeature, timeout: Duration) -> Result {
+        if let StandardFeature::TestMode(_) = feature {
+            return Err(Error::InvalidParam);
+        }
+        let (recipient, value, index) = feature.target();
+        self.control_transfer_out(standard_request(recipient, CLEAR_FEATURE, value, index, timeout), &[]).await?;
+        Ok(())
+    }
+
+    pub async fn set_feature(&self, feature: StandardFeature, timeout: Duration) -> Result {
+        let (recipient, value, index) = feature.target();
+        self.control_transfer_out(standard_request(recipient, SET_FEATURE, value, index, timeout), &[]).await?;
+        Ok(())
+    }
+
+    /// GET_DESCRIPTOR, up to `capacity` bytes. `language` is the LANGID of a
+    /// string descriptor, 0 for the others.
+    pub async fn get_descriptor(
+        &self, descriptor_type: DescriptorType, index: u8, language: u16, capacity: usize, timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let value = (descriptor_type.code() as u16) << 8 | index as u16;
+        self.control_transfer_in(standard_request(UsbControlRecipient::Device, GET_DESCRIPTOR, value, language, timeout), capacity).await
+    }
+
+    /// GET_CONFIGURATION, 0 if the device is not configured.
+    pub async fn get_configuration(&self, timeout: Duration) -> Result<u8> {
+        let data = self.control_transfer_in(standard_request(UsbControlRecipient::Device, GET_CONFIGURATION, 0, 0, timeout), 1).await?;
+        Ok(exact::<1>(data)?[0])
+    }
+
+    /// GET_INTERFACE, the alternate setting the device has selected.
+    pub async fn get_interface(&self, interface_number: u8, timeout: Duration) -> Result<u8> {
+        let request = standard_request(UsbControlRecipient::Interface(interface_number), GET_INTERFACE, 0, 0, timeout);
+        let data = self.control_transfer_in(request, 1).await?;
+        Ok(exact::<1>(data)?[0])
+    }
+
+    /// SYNCH_FRAME of an isochronous endpoint, given by address.
+    pub async fn synch_frame(&self, endpoint: u8, timeout: Duration) -> Result<u16> {
+        let request = standard_request(UsbControlRecipient::Endpoint(endpoint), SYNCH_FRAME, 0, 0, timeout);
+        let data = self.control_transfer_in(request, 2).await?;
+        Ok(u16::from_le_bytes(exact(data)?))
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::mock::MockResponse;
+    use crate::mock::test::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_standard_requests() {
+        let (mock, device) = open(mock_device());
+        let timeout = Duration::from_secs(1);
+
+        mock.push(0x80, MockResponse::Data(vec![0x01, 0x00]));
+        assert_eq!(device.get_status(UsbControlRecipient::Endpoint(0x81), timeout).await.unwrap(), 1);
+
+        mock.push(0x00, MockResponse::Ack);
+        device.clear_feature(StandardFeature::EndpointHalt(0x81), timeout).await.unwrap();
+        assert!(matches!(device.clear_feature(StandardFeature::TestMode(1), timeout).await, Err(Error::InvalidParam)));
+
+        mock.push(0x00, MockResponse::Ack);
+        device.set_feature(StandardFeature::TestMode(4), timeout).await.unwrap();
+
+        mock.push(0x80, MockResponse::Data(vec![4, 3, 0x09, 0x04]));
+        let data = device.get_descriptor(DescriptorType::String, 0, 0, 255, timeout).await.unwrap();
+        assert_eq!(data, vec![4, 3, 0x09, 0x04]);
+
+        mock.push(0x80, MockResponse::Data(vec![1]));
+        assert_eq!(device.get_configuration(timeout).await.unwrap(), 1);
+
+        mock.push(0x80, MockResponse::Data(vec![2]));
+        assert_eq!(device.get_interface(1, timeout).await.unwrap(), 2);
+
+        mock.push(0x80, MockResponse::Data(vec![0x34]));
+        assert!(matches!(device.synch_frame(0x83, timeout).await, Err(Error::Io(_))));
+
+        assert_eq!(mock.take_control_setups(), vec![
+            ControlSetup { request_type: 0x82, request: 0x00, value: 0, index: 0x81, length: 2 },
+            ControlSetup { request_type: 0x02, request: 0x01, value: 0, index: 0x81, length: 0 },
+            ControlSetup { request_type: 0x00, request: 0x03, value: 2, index: 0x0400, length: 0 },
+            ControlSetup { request_type: 0x80, request: 0x06, value: 0x0300, index: 0, length: 255 },
+            ControlSetup { request_type: 0x80, request: 0x08, value: 0, index: 0, length: 1 },
+            ControlSetup { request_type: 0x81, request: 0x0A, value: 0, index: 1, length: 1 },
+            ControlSetup { request_type: 0x82, request: 0x0C, value: 0, index: 0x83, length: 2 },
+        ]);
+    }
+}
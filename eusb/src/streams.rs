@@ -0,0 +1,97 @@
+use std::time::Duration;
+use log::warn;
+use crate::device::UsbDevice;
+use crate::error::*;
+
+/// USB 3 bulk streams allocated by [`UsbDevice::alloc_streams`], freed when dropped.
+///
+/// Endpoints are addresses including the direction bit, e.g. `0x81` for IN 1.
+/// Stream IDs run from 1 to [`num_streams`](Self::num_streams).
+pub struct BulkStreams<'a> {
+    device: &'a UsbDevice,
+    endpoints: Vec<u8>,
+    num_streams: u32,
+}
+
+impl<'a> BulkStreams<'a> {
+    pub(crate) fn new(device: &'a UsbDevice, endpoints: Vec<u8>, num_streams: u32) -> Self {
+        Self { device, endpoints, num_streams }
+    }
+
+    pub fn device(&self) -> &'a UsbDevice {
+        self.device
+    }
+
+    /// Streams allocated on each endpoint, which may be fewer than requested.
+    pub fn num_streams(&self) -> u32 {
+        self.num_streams
+    }
+
+    pub fn endpoints(&self) -> &[u8] {
+        &self.endpoints
+    }
+
+    fn check(&self, endpoint: u8, stream_id: u32) -> Result {
+        if !self.endpoints.contains(&endpoint) || stream_id == 0 || stream_id > self.num_streams {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    pub async fn transfer_in(&self, endpoint: u8, stream_id: u32, capacity: usize, timeout: Duration) -> Result<Vec<u8>> {
+        if endpoint & 0x80 == 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.check(endpoint, stream_id)?;
+        self.device.ctx().bulk_stream_transfer_in(endpoint, stream_id, capacity, timeout).await
+    }
+
+    pub async fn transfer_out(&self, endpoint: u8, stream_id: u32, data: &[u8], timeout: Duration) -> Result<usize> {
+        if endpoint & 0x80 != 0 {
+            return Err(Error::InvalidParam);
+        }
+        self.check(endpoint, stream_id)?;
+        self.device.ctx().bulk_stream_transfer_out(endpoint, stream_id, data, timeout).await
+    }
+}
+
+impl Drop for BulkStreams<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.device.ctx().free_streams(&self.endpoints) {
+            warn!("free streams {:?} fail: {}", self.endpoints, e);
+        }
+    }
+}
+
+#[cfg(all(test, mock))]
+mod tests {
+    use crate::mock::MockResponse;
+    use crate::mock::test::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bulk_streams() {
+        let (mock, device) = open(mock_device());
+        let timeout = Duration::from_secs(1);
+
+        {
+            let streams = device.alloc_streams(4, &[0x81, 0x02]).unwrap();
+            assert_eq!(streams.num_streams(), 4);
+            assert_eq!(mock.streams(0x81), Some(4));
+            assert!(matches!(device.alloc_streams(2, &[0x02]), Err(Error::Busy)));
+
+            mock.push(0x81, MockResponse::Data(vec![1, 2]));
+            assert_eq!(streams.transfer_in(0x81, 3, 64, timeout).await.unwrap(), vec![1, 2]);
+            mock.push(0x02, MockResponse::Ack);
+            assert_eq!(streams.transfer_out(0x02, 4, &[3], timeout).await.unwrap(), 1);
+            assert_eq!(mock.take_written(0x02), vec![vec![3]]);
+
+            assert!(matches!(streams.transfer_in(0x81, 0, 64, timeout).await, Err(Error::InvalidParam)));
+            assert!(matches!(streams.transfer_in(0x81, 5, 64, timeout).await, Err(Error::InvalidParam)));
+            assert!(matches!(streams.transfer_in(0x02, 1, 64, timeout).await, Err(Error::InvalidParam)));
+            assert!(matches!(streams.transfer_out(0x03, 1, &[3], timeout).await, Err(Error::InvalidParam)));
+        }
+        assert_eq!(mock.streams(0x81), None);
+        assert_eq!(mock.streams(0x02), None);
+    }
+}
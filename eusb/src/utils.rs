@@ -1,3 +1,27 @@
+/// Match `text` against a pattern where `*` is any sequence and `?` any character.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|o| *o == '*')
+}
+
 #[cfg(test)]
 pub(crate) mod test{
     use env_logger::*;
@@ -5,7 +29,7 @@ pub(crate) mod test{
 
 
     pub(crate) fn init(){
-        Builder::new().filter_level(LevelFilter::Debug).init();
+        let _ = Builder::new().filter_level(LevelFilter::Debug).is_test(true).try_init();
     }
 }
 pub(crate) fn bcd_to_version(bcd: u16) -> Vec<u16> {
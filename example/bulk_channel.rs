@@ -41,7 +41,7 @@ async fn main(){
 
         for _ in 0..50{
            if let Some(data) = ep.next().await{
-               all+= data.len();
+               all+= data.unwrap().len();
            }
         }
 
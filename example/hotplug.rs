@@ -0,0 +1,20 @@
+use futures::StreamExt;
+use log::*;
+use eusb::prelude::*;
+
+#[tokio::main]
+async fn main() {
+    let _ = env_logger::builder().filter_level(LevelFilter::Info).is_test(true).try_init();
+
+    let mut events = UsbDevice::hotplug(HotplugFilter {
+        enumerate: true,
+        ..Default::default()
+    }).unwrap();
+
+    while let Some(event) = events.next().await {
+        match event {
+            HotplugEvent::Arrived(device) => info!("arrived: {}", device),
+            HotplugEvent::Left(id) => info!("left: bus {}, address {}", id.bus_number, id.device_address),
+        }
+    }
+}
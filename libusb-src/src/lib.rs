@@ -2,7 +2,7 @@
 
 mod constants;
 pub use self::constants::*;
-use libc::{c_char, c_int, c_short, c_uchar, c_uint, c_void, ssize_t, timeval};
+use libc::{c_char, c_int, c_short, c_uchar, c_uint, c_void, size_t, ssize_t, timeval};
 
 #[repr(C)]
 pub struct libusb_context {
@@ -302,6 +302,12 @@ extern "system" {
         endpoints: *mut c_uchar,
         num_endpoints: c_int,
     ) -> c_int;
+    pub fn libusb_dev_mem_alloc(dev_handle: *mut libusb_device_handle, length: size_t) -> *mut c_uchar;
+    pub fn libusb_dev_mem_free(
+        dev_handle: *mut libusb_device_handle,
+        buffer: *mut c_uchar,
+        length: size_t,
+    ) -> c_int;
     pub fn libusb_get_string_descriptor_ascii(
         dev_handle: *mut libusb_device_handle,
         desc_index: u8,
@@ -442,6 +448,7 @@ extern "system" {
     pub fn libusb_pollfds_handle_timeouts(context: *mut libusb_context) -> c_int;
     pub fn libusb_get_next_timeout(context: *mut libusb_context, tv: *mut timeval) -> c_int;
     pub fn libusb_get_pollfds(context: *mut libusb_context) -> *const *mut libusb_pollfd;
+    pub fn libusb_free_pollfds(pollfds: *const *mut libusb_pollfd);
     pub fn libusb_set_pollfd_notifiers(
         context: *mut libusb_context,
         added_cb: Option<libusb_pollfd_added_cb>,